@@ -1,37 +1,154 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod tls;
+
 use gethostname::gethostname;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tauri::{Emitter, Manager};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot, Notify, Semaphore};
 use tokio::time::interval;
 use tauri::AppHandle;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use std::path::PathBuf;
 use uuid::Uuid;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use hmac::{Hmac, Mac};
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::ChaCha20Poly1305;
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
-const DISCOVERY_PORT: u16 = 5000;
-const FILE_TRANSFER_PORT: u16 = 5001;
-const PEER_TIMEOUT_SECS: u64 = 2;
+/// Defaults for `UserSettings::discovery_port` and `UserSettings::transfer_port`
+const DEFAULT_DISCOVERY_PORT: u16 = 5000;
+const DEFAULT_TRANSFER_PORT: u16 = 5001;
+/// Ports after `UserSettings::transfer_port` tried when it's taken
+const TRANSFER_PORT_FALLBACKS: u16 = 10;
+/// Link-local all-nodes group used for IPv6 discovery, IPv6 has no broadcast
+const DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+/// Floor for `broadcast_interval_ms`, so a typo can't flood the network
+const MIN_BROADCAST_INTERVAL_MS: u64 = 250;
+/// Discovery ticks in a row a peer may stay silent before it's dropped, so a lost datagram
+/// or two on lossy Wi-Fi doesn't make it blink out of the list
+const MAX_MISSED_BEACONS: u32 = 3;
+/// Every transfer connection starts with this magic followed by the protocol version byte
+const PROTOCOL_MAGIC: &[u8; 4] = b"KTSN";
+/// 2: the receiver answers an accepted offer with per-file resume offsets
+/// 3: the sender introduces itself with a `Hello` before the request
+/// 4: files flagged `compressed` are sent as zstd frames, see `compress_chunk`
+/// 5: files with `ranges` are sent over extra connections, see `TransferRequest::Range`
+/// 6: offers carry each file's `sha256`, the receiver follows the resume offsets with the
+///    indexes of files it already has, which aren't sent
+/// 7: the acceptance byte is followed by the indexes of the accepted files
+/// 8: `packed` batches send all their files as one zstd stream, see `pack_into`
+/// 9: files carry their Unix `mode`, `symlink_target` files carry no data
+/// 10: the receiver opens with an `AUTH_CHALLENGE_LEN` byte challenge, answered in `Hello::auth`
+/// 11: `streamed` files are sent as length-prefixed chunks ending with an empty one
+/// 12: files with `encryption` are sent as one encrypted chunk per `streamed` chunk
+/// 13: `TransferRequest::Url` has the receiver download a file itself
+const PROTOCOL_VERSION: u8 = 13;
+/// Oldest version we still talk to. What came after is either a `Capability` or only sent
+/// to peers whose `Peer::protocol_version` has it. Before 10 the receiver didn't open the
+/// connection with its challenge, which nothing older expects.
+const MIN_PROTOCOL_VERSION: u8 = 10;
+/// First version with `FileMetadata::streamed` files
+const STREAMED_VERSION: u8 = 11;
+/// First version with `FileMetadata::encryption`
+const ENCRYPTION_VERSION: u8 = 12;
+/// First version with `TransferRequest::Url`
+const URL_VERSION: u8 = 13;
+/// Answer byte for a request from an unpaired sender with a missing or wrong PIN,
+/// next to 0 (rejected) and 1 (accepted)
+const PAIRING_REJECTED: u8 = 2;
+/// Answer byte for a request whose `Hello::auth` doesn't prove the `shared_secret`
+const AUTH_REJECTED: u8 = 3;
+/// Answer byte for an offer the receiver can't save anywhere, see `check_storage`
+const STORAGE_UNAVAILABLE: u8 = 4;
+/// Random bytes the receiver sends before anything else, see `shared_secret_response`
+const AUTH_CHALLENGE_LEN: usize = 32;
+/// Wrong PINs tolerated before a new one is generated, so it can't be brute-forced
+const MAX_PIN_ATTEMPTS: u32 = 5;
+const MANUAL_PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a `TransferRequest::Url` download waits for the server to take the connection
+const URL_FETCH_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Saved name of a downloaded file when neither the sender nor the URL gives a usable one
+const FETCH_FALLBACK_NAME: &str = "download";
+/// Wait before the second connection attempt, doubled for each one after
+const CONNECT_RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+const SETTINGS_FILE: &str = "settings.json";
+const DEVICE_ID_FILE: &str = "device_id";
+const ALIASES_FILE: &str = "aliases.json";
+const PAIRED_PEERS_FILE: &str = "paired_peers.json";
+const BATCH_PROGRESS_FILE: &str = "batch_progress.json";
+const MDNS_SERVICE_TYPE: &str = "_kitsunet._tcp.local.";
 
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Peer {
+    /// Stable per-installation id, or the address for peers that don't announce one
+    id: String,
     username: String,
     address: String,
+    /// `std::env::consts::OS` of the peer, unknown for older versions and manual peers
+    #[serde(default)]
+    os: Option<String>,
+    #[serde(default)]
+    device_type: Option<DeviceType>,
+    /// Local name set with `set_peer_alias`, filled in by `get_users`
+    #[serde(default)]
+    alias: Option<String>,
     #[serde(skip)]
     last_seen: Option<Instant>,
+    /// Discovery ticks since the last presence from the peer, see `MAX_MISSED_BEACONS`
+    #[serde(skip)]
+    missed_beacons: u32,
+    /// Age of `last_seen` in milliseconds, filled in by `get_users`. `None` for manual peers
+    /// we never heard from.
+    #[serde(default)]
+    last_seen_ms_ago: Option<u64>,
+    /// Added by hand with `add_manual_peer`, exempt from the presence timeout
+    #[serde(default)]
+    manual: bool,
+    /// Where the peer receives files, as it announced it
+    #[serde(default = "default_transfer_port")]
+    transfer_port: u16,
+    /// Whether the peer takes files right now, `None` for older versions and manual peers
+    #[serde(default)]
+    status: Option<PresenceStatus>,
+    /// What the peer announced it supports. `None` if it never announced itself, like a
+    /// manual peer, in which case it's assumed to be on this version.
+    #[serde(default)]
+    capabilities: Option<Vec<Capability>>,
+    /// The `PROTOCOL_VERSION` the peer announced. `None` for manual peers and versions
+    /// that don't announce it, which are assumed to be on this version.
+    #[serde(default)]
+    protocol_version: Option<u8>,
+    /// With a `shared_secret`, whether its presence was signed with it, see
+    /// `PresenceSignature`. `None` when there was nothing to check: we have no secret, or
+    /// it was added by hand or found over mDNS.
+    #[serde(default)]
+    verified: Option<bool>,
+}
+
+fn default_transfer_port() -> u16 {
+    DEFAULT_TRANSFER_PORT
 }
 
 impl PartialEq for Peer {
     fn eq(&self, other: &Self) -> bool {
-        self.address == other.address
+        self.id == other.id
     }
 }
 
@@ -39,20 +156,344 @@ impl Eq for Peer {}
 
 impl std::hash::Hash for Peer {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.address.hash(state);
+        self.id.hash(state);
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum Message {
-    Presence(String),
+    Presence(Presence),
+}
+
+/// Optional protocol features, announced with the presence so a sender only uses what the
+/// recipient handles.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Capability {
+    /// `FileMetadata::compressed` files
+    Compression,
+    /// Nonzero resume offsets. The recipient picks those, so there's nothing to fall back
+    /// to on the sending side.
+    Resume,
+    /// Files split into `FileMetadata::ranges`
+    Ranges,
+    /// `packed` batches
+    Pack,
+    /// `TransferRequest::Calibrate`, see `estimate_transfer`
+    Calibrate,
+    /// Announced by some newer version we don't know about yet
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    /// Everything this version supports
+    const ALL: [Capability; 5] = [
+        Capability::Compression,
+        Capability::Resume,
+        Capability::Ranges,
+        Capability::Pack,
+        Capability::Calibrate,
+    ];
+}
+
+/// What a peer announces about itself on every discovery tick.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "PresenceWire")]
+struct Presence {
+    /// Missing from older versions
+    id: Option<String>,
+    username: String,
+    os: Option<String>,
+    device_type: Option<DeviceType>,
+    /// Missing from versions that always listened on `DEFAULT_TRANSFER_PORT`
+    transfer_port: Option<u16>,
+    /// Missing from versions that don't support any of them
+    capabilities: Vec<Capability>,
+    /// Missing from versions without `UserSettings::presence_status`
+    status: Option<PresenceStatus>,
+    /// Our `PROTOCOL_VERSION`, missing from versions that only talked to their own
+    protocol_version: Option<u8>,
+    /// Unix time in seconds the signature was made at, see `presence_signature`.
+    /// Both are missing without a `shared_secret`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl Presence {
+    fn local(state: &SharedState) -> Self {
+        let settings = &state.settings;
+        let timestamp = unix_secs();
+        Self {
+            id: Some(state.device_id.clone()),
+            username: settings.username.clone(),
+            os: Some(std::env::consts::OS.to_string()),
+            device_type: Some(DeviceType::local()),
+            transfer_port: Some(state.transfer_port()),
+            capabilities: Capability::ALL.to_vec(),
+            status: Some(settings.presence_status),
+            protocol_version: Some(PROTOCOL_VERSION),
+            timestamp: settings.shared_secret.is_some().then_some(timestamp),
+            signature: settings.shared_secret.as_deref()
+                .map(|secret| presence_signature(secret, &state.device_id, &settings.username, timestamp)),
+        }
+    }
+
+    /// Checks the signature against our `shared_secret`.
+    fn signature(&self, secret: &str) -> PresenceSignature {
+        let (Some(id), Some(timestamp), Some(signature)) = (&self.id, self.timestamp, &self.signature) else {
+            return PresenceSignature::Missing;
+        };
+        // Both ways, clocks on a LAN are rarely exactly in sync
+        if unix_secs().abs_diff(timestamp) > MAX_PRESENCE_AGE.as_secs() {
+            return PresenceSignature::Invalid;
+        }
+        let message = presence_signed_message(id, &self.username, timestamp);
+        if verify_shared_secret(secret, message.as_bytes(), Some(signature)) {
+            PresenceSignature::Valid
+        } else {
+            PresenceSignature::Invalid
+        }
+    }
+}
+
+/// Signed presence older than this, or this far in the future, is taken for a replay
+const MAX_PRESENCE_AGE: Duration = Duration::from_secs(60);
+
+/// What `Presence::signature` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PresenceSignature {
+    /// Made with our secret, recently
+    Valid,
+    /// Unsigned, from a peer without a secret or an older version. Shown as unverified.
+    Missing,
+    /// Made with another secret, or too long ago. Dropped.
+    Invalid,
+}
+
+fn presence_signed_message(id: &str, username: &str, timestamp: u64) -> String {
+    format!("{}\0{}\0{}", id, username, timestamp)
+}
+
+/// HMAC over the id, username and time of a presence, which the username can't be
+/// changed in without the secret. The time keeps old announcements from being replayed
+/// for long.
+fn presence_signature(secret: &str, id: &str, username: &str, timestamp: u64) -> String {
+    shared_secret_response(secret, presence_signed_message(id, username, timestamp).as_bytes())
+}
+
+fn unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Older versions announce just the username as a bare string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PresenceWire {
+    Legacy(String),
+    Full {
+        #[serde(default)]
+        id: Option<String>,
+        username: String,
+        #[serde(default)]
+        os: Option<String>,
+        #[serde(default)]
+        device_type: Option<DeviceType>,
+        #[serde(default)]
+        transfer_port: Option<u16>,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+        #[serde(default)]
+        status: Option<PresenceStatus>,
+        #[serde(default)]
+        protocol_version: Option<u8>,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        #[serde(default)]
+        signature: Option<String>,
+    },
+}
+
+impl From<PresenceWire> for Presence {
+    fn from(wire: PresenceWire) -> Self {
+        match wire {
+            PresenceWire::Legacy(username) => Self {
+                id: None,
+                username,
+                os: None,
+                device_type: None,
+                transfer_port: None,
+                capabilities: Vec::new(),
+                status: None,
+                protocol_version: None,
+                timestamp: None,
+                signature: None,
+            },
+            PresenceWire::Full { id, username, os, device_type, transfer_port, capabilities, status, protocol_version, timestamp, signature } => {
+                Self { id, username, os, device_type, transfer_port, capabilities, status, protocol_version, timestamp, signature }
+            }
+        }
+    }
+}
+
+/// Whether we take files, announced along with our presence.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+enum PresenceStatus {
+    #[default]
+    Available,
+    /// Offers are declined without asking
+    Busy,
+    /// Offers are still shown, presence goes out at `AWAY_BROADCAST_INTERVAL_FACTOR` times
+    /// the interval
+    Away,
+    /// Sent by some newer version we don't know about yet
+    #[serde(other)]
+    Unknown,
+}
+
+/// How much less often we announce ourselves while `Away`. Kept low enough that peers
+/// with the default timeout don't drop us in between.
+const AWAY_BROADCAST_INTERVAL_FACTOR: u64 = 2;
+
+/// Rough kind of device, only used to pick an icon.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum DeviceType {
+    Desktop,
+    Laptop,
+    Phone,
+    /// Sent by some newer version we don't know about yet
+    #[serde(other)]
+    Unknown,
+}
+
+impl DeviceType {
+    fn local() -> Self {
+        match std::env::consts::OS {
+            "android" | "ios" => DeviceType::Phone,
+            // A battery is the most reliable laptop hint Linux gives us
+            "linux" if std::fs::read_dir("/sys/class/power_supply").is_ok_and(|entries| {
+                entries.flatten().any(|entry| entry.file_name().to_string_lossy().starts_with("BAT"))
+            }) => DeviceType::Laptop,
+            _ => DeviceType::Desktop,
+        }
+    }
+}
+
+/// How peers find each other. Changing it takes effect on the next launch.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+enum DiscoveryMode {
+    #[default]
+    Broadcast,
+    Mdns,
+    Both,
+}
+
+impl DiscoveryMode {
+    fn uses_broadcast(self) -> bool {
+        matches!(self, DiscoveryMode::Broadcast | DiscoveryMode::Both)
+    }
+
+    fn uses_mdns(self) -> bool {
+        matches!(self, DiscoveryMode::Mdns | DiscoveryMode::Both)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 struct UserSettings {
     username: String,
+    /// Announce ourselves over broadcast and mDNS. Off is "discover only": peers keep
+    /// showing up and timing out as usual, but nothing is sent that would list us on
+    /// theirs, not even at startup.
     broadcasting_enabled: bool,
+    /// Where presence goes: `255.255.255.255` for every interface, or one broadcast or
+    /// multicast address, see `broadcast_targets`
     broadcast_address: String,
+    /// More addresses like `broadcast_address` announced to along with it, for networks
+    /// one address doesn't reach, e.g. `192.168.1.255` and `10.0.0.255`
+    broadcast_addresses: Vec<String>,
+    /// Peers announced to directly as well, for ones broadcast doesn't reach such as those
+    /// on another subnet. An IP, or `ip:port` when its discovery port isn't ours.
+    seed_peers: Vec<String>,
+    /// Where received files are saved; `None` means the system download directory
+    download_dir: Option<String>,
+    /// Save each sender's files in `Kitsunet/<sender>` under the download directory, named
+    /// after its alias or username, see `peer_folder_name`
+    organize_by_peer: bool,
+    discovery_mode: DiscoveryMode,
+    /// Announced to peers, see `PresenceStatus`
+    presence_status: PresenceStatus,
+    /// How long an offer waits for the recipient to accept or reject it
+    offer_timeout_secs: u64,
+    /// Incoming transfers handled at once, further connections wait for a free slot.
+    /// Read when the receiver starts.
+    max_incoming_transfers: usize,
+    /// Outgoing transfers running at once, further ones wait in the `TransferQueue`.
+    /// Read when the queue worker starts.
+    max_outgoing_transfers: usize,
+    /// Peer ids or addresses whose presence and offers are ignored
+    blocked_peers: HashSet<String>,
+    /// Only accept requests from peers that have entered our pairing PIN once
+    require_pairing: bool,
+    /// Passphrase set on every install of a closed group. When set, senders must answer
+    /// the connection's challenge with it before their offer is even read, and we answer
+    /// with it when sending. Empty is stored as `None`.
+    shared_secret: Option<String>,
+    /// Accept offers from `auto_accept_peers` without asking. Size and space limits still apply.
+    auto_accept_trusted: bool,
+    /// Ids of the peers whose offers `auto_accept_trusted` accepts
+    auto_accept_peers: HashSet<String>,
+    /// Like `require_pairing`, but only for senders that never announced themselves
+    pair_unknown_senders: bool,
+    /// Offers bigger than this are declined without asking
+    auto_reject_over_bytes: Option<u64>,
+    /// File extensions like `jpg` offers may contain, compared case-insensitively. Offers
+    /// with any other file are declined without asking. `None` or empty allows everything.
+    allowed_extensions: Option<HashSet<String>>,
+    /// Glob patterns like `*.csv`, matched case-insensitively against each file's path.
+    /// Offers whose files all match one are accepted from anyone without asking. Size and
+    /// space limits still apply.
+    auto_accept_patterns: Vec<String>,
+    /// Compress outgoing files unless they're already in a compressed format
+    compress_transfers: bool,
+    /// Time between presence broadcasts, at least `MIN_BROADCAST_INTERVAL_MS`
+    broadcast_interval_ms: u64,
+    /// Discovered peers are dropped after this long without a presence, and only once they
+    /// also missed `MAX_MISSED_BEACONS` broadcast intervals in a row
+    peer_timeout_secs: u64,
+    /// Connections a large file is split over, see `PARALLEL_MIN_FILE_SIZE`. 1 sends every
+    /// file over the batch connection.
+    parallel_streams: usize,
+    /// Tries at connecting to a recipient before giving up, see `CONNECT_RETRY_BASE_DELAY`
+    connect_attempts: u32,
+    /// Name of the only interface to discover and receive on, `None` for all of them.
+    /// Read when discovery and the receiver start.
+    bind_interface: Option<String>,
+    /// Idle time before TCP keepalive probes start on transfer connections, and the time
+    /// between them, see `enable_keepalive`. 0 turns keepalive off.
+    keepalive_interval_secs: u64,
+    /// UDP port presence is broadcast on. Peers only see each other if they use the same one.
+    discovery_port: u16,
+    /// TCP port we receive files on, announced to peers along with our presence
+    transfer_port: u16,
+    /// How long a sender may go quiet before the receiver gives up on the connection and
+    /// keeps the `.part` for a later resume. Pausing goes quiet too, so longer pauses fail
+    /// the transfer.
+    receive_timeout_secs: u64,
+    /// Bytes read from a file or the connection at a time, between `MIN_TRANSFER_BUFFER_KIB`
+    /// and `MAX_TRANSFER_BUFFER_KIB`. Read when a transfer starts.
+    transfer_buffer_kib: usize,
+    /// Networks like `192.168.1.0/24` to discover, announce and receive on. Empty allows
+    /// every network.
+    allowed_cidrs: Vec<String>,
+    /// Mark received files as downloaded from the internet, see `mark_as_downloaded`
+    quarantine_received: bool,
 }
 
 impl Default for UserSettings {
@@ -61,421 +502,6698 @@ impl Default for UserSettings {
             username: gethostname().into_string().unwrap_or_else(|_| "Unknown".to_string()),
             broadcasting_enabled: true,
             broadcast_address: "255.255.255.255".to_string(),
+            broadcast_addresses: Vec::new(),
+            seed_peers: Vec::new(),
+            download_dir: None,
+            organize_by_peer: false,
+            discovery_mode: DiscoveryMode::default(),
+            presence_status: PresenceStatus::default(),
+            offer_timeout_secs: 60,
+            max_incoming_transfers: 4,
+            max_outgoing_transfers: 2,
+            blocked_peers: HashSet::new(),
+            require_pairing: false,
+            shared_secret: None,
+            auto_accept_trusted: false,
+            auto_accept_peers: HashSet::new(),
+            pair_unknown_senders: false,
+            auto_reject_over_bytes: None,
+            allowed_extensions: None,
+            auto_accept_patterns: Vec::new(),
+            compress_transfers: false,
+            broadcast_interval_ms: 1000,
+            peer_timeout_secs: 5,
+            parallel_streams: 1,
+            connect_attempts: 3,
+            bind_interface: None,
+            keepalive_interval_secs: 10,
+            discovery_port: DEFAULT_DISCOVERY_PORT,
+            transfer_port: DEFAULT_TRANSFER_PORT,
+            receive_timeout_secs: 30,
+            transfer_buffer_kib: 1024,
+            allowed_cidrs: Vec::new(),
+            quarantine_received: true,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct NetworkInterfaceInfo {
-    name: String,
-    ip: String,
-    broadcast: String,
+impl UserSettings {
+    /// `broadcast_address` followed by `broadcast_addresses`
+    fn all_broadcast_addresses(&self) -> Vec<String> {
+        std::iter::once(&self.broadcast_address).chain(&self.broadcast_addresses).cloned().collect()
+    }
+}
+
+/// Checks that `address` can go into `broadcast_address` or `broadcast_addresses`: an IPv4
+/// broadcast or multicast address, or an IPv6 multicast one with an optional `%scope`.
+/// Which networks an IPv4 address ends up broadcasting to can't be told here, so anything
+/// whose host part could be all ones passes.
+fn validate_broadcast_address(address: &str) -> Result<(), String> {
+    let invalid = || format!("Not a broadcast or multicast address: {:?}", address);
+    let (ip, scope) = match address.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (address, None),
+    };
+    match ip.parse::<IpAddr>().map_err(|_| invalid())? {
+        IpAddr::V6(ip) if ip.is_multicast() && scope.is_none_or(|scope| scope.parse::<u32>().is_ok()) => Ok(()),
+        IpAddr::V4(ip) if scope.is_none() && (ip.is_multicast() || u32::from(ip).trailing_ones() >= 2) => Ok(()),
+        _ => Err(invalid()),
+    }
 }
 
-type FileOffers = Arc<Mutex<HashMap<String, oneshot::Sender<bool>>>>;
+/// Where presence for an entry of `seed_peers` goes, `port` unless it names its own.
+fn parse_seed_peer(address: &str, port: u16) -> Option<SocketAddr> {
+    address.parse().ok().or_else(|| address.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, port)))
+}
 
-#[derive(Debug, Default)]
-struct SharedState {
-    peers: HashSet<Peer>,
-    settings: UserSettings,
+/// Parses `UserSettings::allowed_cidrs`.
+fn parse_cidrs(cidrs: &[String]) -> Result<Vec<ipnet::IpNet>, String> {
+    cidrs.iter()
+        .map(|cidr| cidr.trim().parse().map_err(|_| format!("Invalid network: {:?}", cidr)))
+        .collect()
 }
 
-#[derive(Debug, Default)]
-struct AppState(Arc<Mutex<SharedState>>);
+/// Whether `ip` is in one of `allowed`, or `allowed` is empty.
+fn ip_allowed(allowed: &[ipnet::IpNet], ip: IpAddr) -> bool {
+    // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses
+    let ip = ip.to_canonical();
+    allowed.is_empty() || allowed.iter().any(|net| net.contains(&ip))
+}
 
-#[tauri::command]
-fn get_network_interfaces() -> Vec<NetworkInterfaceInfo> {
-    let mut interfaces = vec![];
-    if let Ok(ifaces) = network_interface::NetworkInterface::show() {
-        for iface in ifaces {
-            if iface.name == "lo" {
-                continue;
-            }
-            for addr in iface.addr {
-                if let Some(broadcast) = addr.broadcast() {
-                    if let std::net::IpAddr::V4(ipv4) = addr.ip() {
-                        interfaces.push(NetworkInterfaceInfo {
-                            name: iface.name.clone(),
-                            ip: ipv4.to_string(),
-                            broadcast: broadcast.to_string(),
-                        });
-                    }
-                }
-            }
-        }
-    }
-    interfaces.push(NetworkInterfaceInfo {
-                            name: "All".to_string(),
-                            ip: "255.255.255.255".to_string(),
-                            broadcast: "255.255.255.255".to_string(),
-                        });
-    interfaces
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct NetworkInterfaceInfo {
+    name: String,
+    ip: String,
+    broadcast: String,
 }
 
-#[tauri::command]
-fn get_users(state: tauri::State<AppState>) -> Vec<Peer> {
-    let state = state.0.lock().unwrap();
-    state.peers.iter().cloned().collect()
+/// One place `announce` sends our presence to.
+#[derive(Debug, Serialize, Clone)]
+struct BroadcastTarget {
+    /// Name of the interface the target is on, empty if it matches none of them
+    interface: String,
+    /// `host:port` the presence datagram is sent to
+    target: String,
+    /// Whether it's sent from the IPv6 socket
+    #[serde(skip)]
+    v6: bool,
 }
 
-#[tauri::command]
-fn get_settings(state: tauri::State<AppState>) -> UserSettings {
-    let state = state.0.lock().unwrap();
-    state.settings.clone()
+type FileOffers = Arc<Mutex<HashMap<String, oneshot::Sender<OfferDecision>>>>;
+
+/// What the user answered to a file offer.
+#[derive(Debug)]
+enum OfferDecision {
+    Accepted {
+        /// The files with these indexes, all of them if `None`
+        indexes: Option<Vec<usize>>,
+        /// What the sender encrypted the files with, see `FileEncryption`
+        password: Option<String>,
+    },
+    Rejected,
 }
+/// Sends and receives in progress, keyed by transfer id on the sending side and offer id
+/// on the receiving one.
+type ActiveTransfers = Arc<Mutex<HashMap<String, ActiveTransfer>>>;
 
-#[tauri::command]
-fn update_settings(settings: UserSettings, state: tauri::State<AppState>) {
-    let mut state = state.0.lock().unwrap();
-    state.settings = settings;
+/// One entry of `ActiveTransfers`
+struct ActiveTransfer {
+    control: Arc<TransferControl>,
+    direction: TransferDirection,
+    /// The recipient of a send, the sender's address for a receive
+    peer: String,
+    /// The last `batch-progress` of the transfer, none before the first
+    progress: Option<BatchProgress>,
 }
 
-#[tauri::command]
-async fn get_own_address() -> Result<String, String> {
-    let socket = UdpSocket::bind("0.0.0.0:0").await.map_err(|e| e.to_string())?;
-    socket.connect("8.8.8.8:80").await.map_err(|e| e.to_string())?;
-    let local_addr = socket.local_addr().map_err(|e| e.to_string())?;
-    Ok(local_addr.ip().to_string())
+/// Keeps a transfer in `ActiveTransfers` until dropped.
+struct RegisteredTransfer {
+    transfers: ActiveTransfers,
+    id: String,
+    control: Arc<TransferControl>,
 }
 
-#[tauri::command]
-async fn send_files(
-    app: AppHandle,
-    recipient: String,
-    file_paths: Vec<String>,
-) -> Result<(), String> {
-    let mut files_metadata = Vec::new();
-    for path_str in &file_paths {
-        let path = PathBuf::from(path_str);
-        let file_name = path.file_name()
-            .ok_or_else(|| "A file path is invalid".to_string())?
-            .to_str()
-            .ok_or_else(|| "A file name is not valid UTF-8".to_string())?;
-        let file_size = tokio::fs::metadata(path_str).await.map_err(|e| e.to_string())?.len();
-        files_metadata.push(FileMetadata { name: file_name.to_string(), size: file_size });
+impl RegisteredTransfer {
+    fn new(app: &AppHandle, id: &str, direction: TransferDirection, peer: &str) -> Self {
+        let transfers = app.state::<ActiveTransfers>().inner().clone();
+        let control = Arc::new(TransferControl::default());
+        transfers.lock().unwrap().insert(id.to_string(), ActiveTransfer {
+            control: control.clone(),
+            direction,
+            peer: peer.to_string(),
+            progress: None,
+        });
+        Self { transfers, id: id.to_string(), control }
     }
+}
 
-    let target_addr = format!("{}:{}", recipient, FILE_TRANSFER_PORT);
-    let mut stream = TcpStream::connect(target_addr).await.map_err(|e| e.to_string())?;
+impl Drop for RegisteredTransfer {
+    fn drop(&mut self) {
+        self.transfers.lock().unwrap().remove(&self.id);
+    }
+}
 
-    let metadata_json = serde_json::to_string(&files_metadata).map_err(|e| e.to_string())?;
-    let metadata_bytes = metadata_json.as_bytes();
+/// How a running transfer is steered from `cancel_transfer`, `pause_transfer` and
+/// `resume_transfer`. Receives can only be canceled.
+#[derive(Default)]
+struct TransferControl {
+    cancel: CancellationToken,
+    /// Set while the offer waits for the recipient's answer, see `cancel_pending_offer`
+    awaiting_answer: AtomicBool,
+    state: Mutex<TransferState>,
+    /// Woken when the state goes back to `Running`
+    resumed: Notify,
+}
 
-    // Send metadata length and metadata
-    stream.write_u64(metadata_bytes.len() as u64).await.map_err(|e| e.to_string())?;
-    stream.write_all(metadata_bytes).await.map_err(|e| e.to_string())?;
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum TransferState {
+    #[default]
+    Running,
+    Paused,
+}
 
-    // Wait for acceptance
-    let mut response = [0; 1];
-    stream.read_exact(&mut response).await.map_err(|e| e.to_string())?;
-    if response[0] != 1 {
-        return Err("File transfer rejected by recipient".to_string());
+impl TransferControl {
+    /// Switches to `state`, returning whether that changed anything.
+    fn set_state(&self, state: TransferState) -> bool {
+        let changed = std::mem::replace(&mut *self.state.lock().unwrap(), state) != state;
+        if changed && state == TransferState::Running {
+            self.resumed.notify_waiters();
+        }
+        changed
     }
 
-    for path_str in &file_paths {
-        let mut file = tokio::fs::File::open(path_str).await.map_err(|e| e.to_string())?;
-        let file_size = file.metadata().await.map_err(|e| e.to_string())?.len();
-        let mut sent_for_file: u64 = 0;
-        
-        let mut buffer = vec![0; 1024 * 1024]; // 1MB buffer
+    /// Returns once the transfer isn't paused, right away if it isn't.
+    async fn wait_while_paused(&self) {
         loop {
-            let bytes_read = file.read(&mut buffer).await.map_err(|e| e.to_string())?;
-            if bytes_read == 0 {
-                break;
+            // Registered before checking, so a resume in between isn't missed
+            let resumed = self.resumed.notified();
+            if *self.state.lock().unwrap() == TransferState::Running {
+                return;
             }
-            stream.write_all(&buffer[..bytes_read]).await.map_err(|e| e.to_string())?;
-            
-            sent_for_file += bytes_read as u64;
-            app.emit("transfer-progress", FileTransferProgress {
-                file_path: Some(path_str.to_string()),
-                file_name: None,
-                progress: (sent_for_file as f64 / file_size as f64) * 100.0,
-            }).unwrap();
+            resumed.await;
         }
-        app.emit("transfer-complete", FileTransferComplete {
-            file_path: Some(path_str.to_string()),
-            file_name: None,
-            saved_path: None,
-        }).unwrap();
     }
+}
 
-    Ok(())
+/// Finished entries `get_queue` keeps showing, oldest are dropped first
+const MAX_FINISHED_QUEUE_ENTRIES: usize = 50;
+
+/// Outgoing transfers waiting for, or holding, one of the `max_outgoing_transfers` slots.
+#[derive(Default)]
+struct TransferQueue {
+    inner: Mutex<QueueInner>,
+    /// Woken when a job is added to `pending`
+    job_added: Notify,
 }
 
-#[tauri::command]
-async fn accept_file_offer(offer_id: String, offers: tauri::State<'_, FileOffers>) -> Result<(), String> {
-    if let Some(sender) = offers.lock().unwrap().remove(&offer_id) {
-        sender.send(true).map_err(|_| "Failed to send acceptance".to_string())?;
-    }
-    Ok(())
+#[derive(Default)]
+struct QueueInner {
+    /// What the UI sees, in the order the transfers were queued
+    entries: Vec<QueueEntry>,
+    pending: VecDeque<QueuedJob>,
 }
 
-#[tauri::command]
-async fn reject_file_offer(offer_id: String, offers: tauri::State<'_, FileOffers>) -> Result<(), String> {
-    if let Some(sender) = offers.lock().unwrap().remove(&offer_id) {
-        sender.send(false).map_err(|_| "Failed to send rejection".to_string())?;
-    }
-    Ok(())
+struct QueuedJob {
+    id: String,
+    recipient: String,
+    file_paths: Vec<String>,
+    options: SendOptions,
+    done: oneshot::Sender<Result<(), TransferError>>,
 }
 
-#[tauri::command]
-fn show_in_folder(path: String) {
-    #[cfg(target_os = "windows")]
-    {
-        std::process::Command::new("explorer")
-            .args(["/select,", &path])
-            .spawn()
-            .expect("failed to open explorer");
-    }
+#[derive(Debug, Clone, Serialize)]
+struct QueueEntry {
+    id: String,
+    recipient: String,
+    file_paths: Vec<String>,
+    status: QueueStatus,
+}
 
-    #[cfg(target_os = "linux")]
-    {
-        if let Some(parent) = std::path::Path::new(&path).parent() {
-            std::process::Command::new("xdg-open")
-                .arg(parent)
-                .spawn()
-                .expect("failed to open file manager");
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum QueueStatus {
+    Pending,
+    Active,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl TransferQueue {
+    /// Queues a send, the receiver resolves once it ran or was cancelled.
+    fn enqueue(&self, app: &AppHandle, recipient: String, file_paths: Vec<String>, options: SendOptions) -> oneshot::Receiver<Result<(), TransferError>> {
+        let (done, result) = oneshot::channel();
+        let id = Uuid::new_v4().to_string();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.entries.push(QueueEntry {
+                id: id.clone(),
+                recipient: recipient.clone(),
+                file_paths: file_paths.clone(),
+                status: QueueStatus::Pending,
+            });
+            inner.pending.push_back(QueuedJob { id, recipient, file_paths, options, done });
         }
+        self.job_added.notify_one();
+        self.emit_updated(app);
+        result
     }
 
-    #[cfg(target_os = "macos")]
-    {
-        std::process::Command::new("open")
-            .args(["-R", &path])
-            .spawn()
-            .expect("failed to open finder");
+    fn next_job(&self) -> Option<QueuedJob> {
+        let mut inner = self.inner.lock().unwrap();
+        let job = inner.pending.pop_front()?;
+        inner.set_status(&job.id, QueueStatus::Active);
+        Some(job)
     }
-}
 
+    /// Takes a job out of the queue before it started, returning whether it was still pending.
+    fn cancel_pending(&self, id: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(index) = inner.pending.iter().position(|job| job.id == id) else {
+            return false;
+        };
+        let job = inner.pending.remove(index).unwrap();
+        inner.set_status(id, QueueStatus::Cancelled);
+        let _ = job.done.send(Err(TransferError::Cancelled));
+        true
+    }
 
-#[derive(Clone, serde::Serialize, Deserialize, Debug)]
-struct FileMetadata {
-    name: String,
-    size: u64,
+    fn finish(&self, id: &str, status: QueueStatus) {
+        self.inner.lock().unwrap().set_status(id, status);
+    }
+
+    fn snapshot(&self) -> Vec<QueueEntry> {
+        self.inner.lock().unwrap().entries.clone()
+    }
+
+    fn emit_updated(&self, app: &AppHandle) {
+        app.emit("queue-updated", self.snapshot()).unwrap();
+    }
 }
 
-#[derive(Clone, serde::Serialize, Debug)]
-struct FileTransferProgress {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    file_path: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    file_name: Option<String>,
-    progress: f64,
+impl QueueInner {
+    fn set_status(&mut self, id: &str, status: QueueStatus) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
+            entry.status = status;
+        }
+        let finished = |entry: &QueueEntry| !matches!(entry.status, QueueStatus::Pending | QueueStatus::Active);
+        let mut excess = self.entries.iter().filter(|entry| finished(entry)).count()
+            .saturating_sub(MAX_FINISHED_QUEUE_ENTRIES);
+        self.entries.retain(|entry| {
+            if excess > 0 && finished(entry) {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
 }
 
-#[derive(Clone, serde::Serialize, Debug)]
-struct FileTransferComplete {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    file_path: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    file_name: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    saved_path: Option<PathBuf>,
+/// Session-wide numbers for a transfer summary, see `get_transfer_stats`.
+#[derive(Default)]
+struct TransferStats {
+    inner: Mutex<StatsSnapshot>,
 }
 
-#[derive(Clone, serde::Serialize)]
-struct BatchFileOfferPayload {
-    id: String,
-    from: String,
-    files: Vec<FileMetadata>,
-    total_size: u64,
+#[derive(Debug, Default, Clone, Serialize)]
+struct StatsSnapshot {
+    active: usize,
+    /// Total size of the running transfers
+    bytes_in_flight: u64,
+    /// Moved in either direction since launch
+    bytes_transferred: u64,
+    completed_files: u64,
+    failed_files: u64,
+    /// Computed by `stats_task` from `bytes_transferred`
+    bytes_per_second: f64,
 }
 
-use std::error::Error;
+/// Counts a transfer as active until dropped, so every way out of it is covered.
+struct ActiveTransferStats {
+    stats: Arc<TransferStats>,
+    total_bytes: u64,
+}
 
-async fn handle_incoming_batch(
-    app: AppHandle,
-    mut stream: TcpStream,
-    remote_addr: std::net::SocketAddr,
-    offers: FileOffers,
-) {
-    let result: Result<(), Box<dyn Error + Send + Sync>> = async {
-        // Read metadata
-        let metadata_len = stream.read_u64().await? as usize;
-        let mut metadata_bytes = vec![0; metadata_len];
-        stream.read_exact(&mut metadata_bytes).await?;
-        let files: Vec<FileMetadata> = serde_json::from_slice(&metadata_bytes)
-            .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+impl TransferStats {
+    fn start(self: &Arc<Self>, total_bytes: u64) -> ActiveTransferStats {
+        let mut inner = self.inner.lock().unwrap();
+        inner.active += 1;
+        inner.bytes_in_flight += total_bytes;
+        ActiveTransferStats { stats: self.clone(), total_bytes }
+    }
 
-        let total_size = files.iter().map(|f| f.size).sum();
+    fn add_bytes(&self, bytes: u64) {
+        self.inner.lock().unwrap().bytes_transferred += bytes;
+    }
 
-        let offer_id = Uuid::new_v4().to_string();
-        let (tx, rx) = oneshot::channel();
-        offers.lock().unwrap().insert(offer_id.clone(), tx);
+    /// Counts a file recorded in the history.
+    fn add_file(&self, status: TransferStatus) {
+        let mut inner = self.inner.lock().unwrap();
+        match status {
+            TransferStatus::Completed | TransferStatus::Skipped => inner.completed_files += 1,
+            TransferStatus::Failed => inner.failed_files += 1,
+            TransferStatus::Rejected => {}
+        }
+    }
 
-        app.emit("file-offer", BatchFileOfferPayload {
-            id: offer_id.clone(),
-            from: remote_addr.ip().to_string(),
-            files: files.clone(),
-            total_size,
-        }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+    fn snapshot(&self) -> StatsSnapshot {
+        self.inner.lock().unwrap().clone()
+    }
+}
 
-        if let Ok(true) = rx.await {
-            // Send acceptance byte
-            stream.write_all(&[1]).await?;
+impl Drop for ActiveTransferStats {
+    fn drop(&mut self) {
+        let mut inner = self.stats.inner.lock().unwrap();
+        inner.active -= 1;
+        inner.bytes_in_flight -= self.total_bytes;
+    }
+}
 
-            let download_dir = match app.path().download_dir() {
-                Ok(path) => path,
-                Err(_) => return Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "Download directory not found")) as Box<dyn Error + Send + Sync>),
-            };
+/// How often `stats-updated` is emitted, also the window `bytes_per_second` is averaged over
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
-            for file_meta in files {
-                let file_path = download_dir.join(&file_meta.name);
-                let mut file = tokio::fs::File::create(&file_path).await?;
 
-                let mut received_for_file: u64 = 0;
-                let mut buffer = vec![0; 1024 * 1024]; // 1MB buffer
+/// How long exiting waits for incoming transfers to finish the file they're on
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(3);
 
-                while received_for_file < file_meta.size {
-                    let bytes_to_read = std::cmp::min(buffer.len() as u64, file_meta.size - received_for_file) as usize;
-                    let bytes_read = stream.read(&mut buffer[..bytes_to_read]).await?;
-                    if bytes_read == 0 {
-                        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Connection closed prematurely")));
-                    }
-                    file.write_all(&buffer[..bytes_read]).await?;
-                    received_for_file += bytes_read as u64;
-                    
-                    app.emit("transfer-progress", FileTransferProgress {
-                        file_path: None,
-                        file_name: Some(file_meta.name.clone()),
-                        progress: (received_for_file as f64 / file_meta.size as f64) * 100.0,
-                    }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
-                }
-                app.emit("transfer-complete", FileTransferComplete {
-                    file_path: None,
-                    file_name: Some(file_meta.name.clone()),
-                    saved_path: Some(file_path),
-                }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
-            }
-        } else {
-            // Send rejection byte
-            stream.write_all(&[0]).await?;
-            println!("File offer for batch rejected or timed out");
-        }
+/// The UDP sockets broadcast discovery sends from and listens on.
+struct DiscoverySockets {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+    /// The discovery port they're bound to, which is also the one peers listen on
+    port: u16,
+}
 
-        Ok(())
-    }.await;
+/// The sockets `discovery_task` currently uses, for `announce_now`. `None` until it bound
+/// them, and forever if broadcast discovery is off.
+#[derive(Default)]
+struct ActiveDiscovery(Mutex<Option<Arc<DiscoverySockets>>>);
 
-    if let Err(e) = result {
-        eprintln!("Error handling incoming file batch: {}", e);
-    }
+/// The ports from the settings the listeners are bound to. `update_settings` sends the new
+/// port when it changes and the task owning the socket rebinds.
+struct Ports {
+    discovery: tokio::sync::watch::Sender<u16>,
+    transfer: tokio::sync::watch::Sender<u16>,
 }
 
+impl Ports {
+    fn new(settings: &UserSettings) -> Self {
+        Self {
+            discovery: tokio::sync::watch::channel(settings.discovery_port).0,
+            transfer: tokio::sync::watch::channel(settings.transfer_port).0,
+        }
+    }
 
-async fn file_receiver_task(app: AppHandle, offers: FileOffers) {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", FILE_TRANSFER_PORT))
-        .await
-        .expect("Failed to bind TCP listener");
-
-    loop {
-        if let Ok((stream, remote_addr)) = listener.accept().await {
-            println!("Accepted connection from {}", remote_addr);
-            let app_clone = app.clone();
-            let offers_clone = offers.clone();
-            tokio::spawn(handle_incoming_batch(app_clone, stream, remote_addr, offers_clone));
+    fn update(&self, settings: &UserSettings) {
+        for (sender, port) in [(&self.discovery, settings.discovery_port), (&self.transfer, settings.transfer_port)] {
+            sender.send_if_modified(|current| std::mem::replace(current, port) != port);
         }
     }
 }
 
-async fn discovery_task(app_handle: tauri::AppHandle) {
-    let state = app_handle.state::<AppState>();
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", DISCOVERY_PORT))
-        .await
-        .expect("Не удалось привязать сокет");
-    socket
-        .set_broadcast(true)
-        .expect("Не удалось установить broadcast");
+/// The interfaces `get_network_interfaces` last listed, refreshed by `interface_watch_task`.
+/// `discovery_task` rebinds when they change, which joins the IPv6 group on new links.
+struct Interfaces(tokio::sync::watch::Sender<Vec<NetworkInterfaceInfo>>);
 
-    let mut broadcast_interval = interval(Duration::from_secs(1));
-    let mut recv_buf = vec![0u8; 1024];
+/// How often `interface_watch_task` looks for interfaces coming and going
+const INTERFACE_POLL_INTERVAL: Duration = Duration::from_secs(3);
 
+/// Emits `interfaces_changed` with the new list whenever an interface or one of its
+/// addresses comes or goes, e.g. on docking or toggling a VPN.
+async fn interface_watch_task(app: AppHandle) {
+    let mut shutdown = app.state::<Shutdown>().signal.subscribe();
+    let mut poll = interval(INTERFACE_POLL_INTERVAL);
     loop {
         tokio::select! {
-            _ = broadcast_interval.tick() => {
-                // Peer cleanup
-                {
-                    let mut state = state.0.lock().unwrap();
-                    let now = Instant::now();
-                    let old_peer_count = state.peers.len();
-                    state.peers.retain(|peer| {
-                        if let Some(last_seen) = peer.last_seen {
-                            now.duration_since(last_seen).as_secs() < PEER_TIMEOUT_SECS
-                        } else {
-                            false
-                        }
-                    });
-                    if state.peers.len() < old_peer_count {
-                        app_handle.emit("peers_updated", ()).unwrap();
+            _ = shutdown.recv() => return,
+            _ = poll.tick() => {
+                let current = tokio::task::spawn_blocking(get_network_interfaces).await.unwrap_or_default();
+                let changed = app.state::<Interfaces>().0.send_if_modified(|known| {
+                    if *known == current {
+                        return false;
                     }
+                    *known = current.clone();
+                    true
+                });
+                if changed {
+                    info!(interfaces = current.len(), "Network interfaces changed");
+                    app.emit("interfaces_changed", current).unwrap();
                 }
+            }
+        }
+    }
+}
 
-                // Broadcasting
-                let (username, broadcasting_enabled, broadcast_address) = {
-                    let state = state.0.lock().unwrap();
-                    (   
-                        state.settings.username.clone(),
-                        state.settings.broadcasting_enabled,
-                        state.settings.broadcast_address.clone(),
-                    )
-                };
+/// Least time between two `peers_updated`, changes in between go out together
+const PEERS_UPDATED_INTERVAL: Duration = Duration::from_millis(250);
 
-                if broadcasting_enabled {
-                    let message = Message::Presence(username);
-                    let bytes = serde_json::to_vec(&message).unwrap();
-
-                    if broadcast_address == "255.255.255.255" {
-                        // "All" mode: broadcast on all interfaces
-                        if let Ok(ifaces) = network_interface::NetworkInterface::show() {
-                            for iface in ifaces {
-                                for addr in &iface.addr {
-                                    if let Some(broadcast) = addr.broadcast() {
-                                        let target_addr = format!("{}:{}", broadcast, DISCOVERY_PORT);
-                                        if let Err(e) = socket.send_to(&bytes, &target_addr).await {
-                                            eprintln!("Не удалось отправить broadcast на {}: {}", target_addr, e);
-                                        }
-                                    }
-                                }
-                            }
+/// Changes to the peer list waiting for `peers_updated_task`, see `emit_peers_updated`.
+#[derive(Default)]
+struct PeersUpdated(Notify);
+
+/// Has `peers_updated_task` emit `peers_updated`. A busy network changes the peer list
+/// many times a second, which the UI shouldn't redraw for every time. `peer_joined` and
+/// `peer_left` still go out right away.
+fn emit_peers_updated(app: &AppHandle) {
+    app.state::<PeersUpdated>().0.notify_one();
+}
+
+async fn peers_updated_task(app: AppHandle) {
+    let mut shutdown = app.state::<Shutdown>().signal.subscribe();
+    let changes = app.state::<PeersUpdated>();
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = changes.0.notified() => {}
+        }
+        app.emit("peers_updated", ()).unwrap();
+        // Changes meanwhile leave a permit behind, picked up once this is over
+        tokio::time::sleep(PEERS_UPDATED_INTERVAL).await;
+    }
+}
+
+/// Stops the background tasks on exit.
+struct Shutdown {
+    signal: tokio::sync::broadcast::Sender<()>,
+    /// Running `handle_incoming_batch` tasks
+    incoming: tokio_util::task::TaskTracker,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        Self {
+            signal: tokio::sync::broadcast::channel(1).0,
+            incoming: tokio_util::task::TaskTracker::new(),
+        }
+    }
+
+    /// Signals every task and waits up to `SHUTDOWN_GRACE_PERIOD` for incoming transfers.
+    async fn run(&self) {
+        let _ = self.signal.send(());
+        self.incoming.close();
+        if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, self.incoming.wait()).await.is_err() {
+            warn!("Incoming transfers still running at exit, abandoning them");
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SharedState {
+    peers: HashSet<Peer>,
+    settings: UserSettings,
+    /// Our own stable id, see `load_device_id`
+    device_id: String,
+    /// Peer id -> alias, kept in its own file so it outlives the peer going offline
+    aliases: HashMap<String, String>,
+    pairing: Pairing,
+    /// How binding the transfer listener went, see `get_listen_status`
+    listen_status: ListenStatus,
+    /// When a presence datagram last arrived from each source, whether or not it was
+    /// accepted, see `diagnose_peer`
+    presence_heard: HashMap<IpAddr, Instant>,
+}
+
+/// Whether `file_receiver_task` is listening, and on which port. That's the one announced,
+/// which differs from the setting when it was taken and a fallback was used.
+#[derive(Debug, Default, Clone, Serialize)]
+struct ListenStatus {
+    bound: bool,
+    port: Option<u16>,
+    /// Why binding failed, when it did
+    error: Option<String>,
+}
+
+/// Receiving side: who has paired with us and the PIN shown to new peers.
+/// Sending side: PINs the user entered for peers that asked for one.
+#[derive(Debug, Default)]
+struct Pairing {
+    paired_peers: HashSet<String>,
+    pin: String,
+    failed_attempts: u32,
+    /// Peer address -> PIN to send it
+    pins_for_peers: HashMap<String, String>,
+}
+
+/// A fresh random 6-digit PIN
+fn generate_pin() -> String {
+    format!("{:06}", Uuid::new_v4().as_u128() % 1_000_000)
+}
+
+impl SharedState {
+    fn presence_heard_from(&mut self, ip: IpAddr) {
+        // Spoofed sources could grow the map without bound, like `PresenceFilter::windows`
+        if self.presence_heard.len() >= MAX_PRESENCE_SOURCES {
+            let peer_timeout = Duration::from_secs(self.settings.peer_timeout_secs);
+            self.presence_heard.retain(|_, heard| heard.elapsed() < peer_timeout);
+        }
+        self.presence_heard.insert(ip.to_canonical(), Instant::now());
+    }
+
+    /// The port we receive on, as announced to peers.
+    fn transfer_port(&self) -> u16 {
+        self.listen_status.port.unwrap_or(self.settings.transfer_port)
+    }
+
+    /// Whether a sender has shown up in discovery (or was added by hand) where it connects
+    /// from. Its id only counts along with the address, every presence gives it away.
+    fn is_known_peer(&self, id: &str, address: &str) -> bool {
+        self.peers.iter().any(|peer| peer.address == address && (peer.id == id || peer.id == peer.address))
+    }
+
+    /// Whether a sender paired with us. Like for `is_auto_accepted`, it also has to connect
+    /// from where the peer with that id announced itself.
+    fn is_paired(&self, id: &str, address: &str) -> bool {
+        self.pairing.paired_peers.contains(id)
+            && self.peers.iter().any(|peer| peer.id == id && peer.address == address)
+    }
+
+    /// Whether offers from a sender skip the prompt. The id in the `Hello` is only the
+    /// sender's word, so it also has to connect from where that peer announced itself.
+    fn is_auto_accepted(&self, id: &str, address: &str) -> bool {
+        self.settings.auto_accept_trusted
+            && self.settings.auto_accept_peers.contains(id)
+            && self.peers.iter().any(|peer| peer.id == id && peer.address == address)
+    }
+
+    /// Where the peer at `address` receives files. Recipients we don't know (anymore) get the
+    /// port everyone used before it was configurable.
+    fn peer_transfer_port(&self, address: &str) -> u16 {
+        self.peers.iter()
+            .find(|peer| peer.address == address)
+            .map_or(DEFAULT_TRANSFER_PORT, |peer| peer.transfer_port)
+    }
+
+    /// Whether the peer at `address` announced `capability`, or never announced anything.
+    fn peer_supports(&self, address: &str, capability: Capability) -> bool {
+        self.peers.iter()
+            .find(|peer| peer.address == address)
+            .and_then(|peer| peer.capabilities.as_ref())
+            .is_none_or(|capabilities| capabilities.contains(&capability))
+    }
+
+    /// The protocol version of the peer at `address`, ours if it never said.
+    fn peer_protocol_version(&self, address: &str) -> u8 {
+        self.peers.iter()
+            .find(|peer| peer.address == address)
+            .and_then(|peer| peer.protocol_version)
+            .unwrap_or(PROTOCOL_VERSION)
+    }
+
+    /// Whether `ip` is on a network we share with, see `UserSettings::allowed_cidrs`.
+    fn is_allowed_ip(&self, ip: IpAddr) -> bool {
+        // Validated when saved, so invalid entries only come from a hand-edited file
+        ip_allowed(&parse_cidrs(&self.settings.allowed_cidrs).unwrap_or_default(), ip)
+    }
+
+    /// The alias or announced name of the peer with `id` at `address`, if we know it.
+    fn peer_name(&self, id: &str, address: &str) -> Option<String> {
+        let peer = self.peers.iter().find(|peer| peer.id == id && peer.address == address)?;
+        Some(self.aliases.get(&peer.id).cloned().unwrap_or_else(|| peer.username.clone()))
+    }
+
+    /// Whether a peer is on the block list, by its id or by the address it talks from.
+    fn is_blocked(&self, id: Option<&str>, address: &str) -> bool {
+        let blocked = &self.settings.blocked_peers;
+        blocked.contains(address)
+            || id.is_some_and(|id| blocked.contains(id))
+            // Connections don't carry the id, so go through the peers known at that address
+            || self.peers.iter().any(|peer| peer.address == address && blocked.contains(&peer.id))
+    }
+
+    /// Removes the peers `stale` picks and returns them.
+    fn remove_peers(&mut self, stale: impl Fn(&Peer) -> bool) -> Vec<Peer> {
+        let removed: Vec<Peer> = self.peers.iter().filter(|peer| stale(peer)).cloned().collect();
+        removed.iter().for_each(|peer| { self.peers.remove(peer); });
+        removed
+    }
+
+    /// Counts a discovery tick for every discovered peer, then removes and returns those
+    /// that missed more than `MAX_MISSED_BEACONS` presences in a row and weren't heard from
+    /// for `peer_timeout` either. A presence resets the count through `upsert_peer`.
+    fn expire_peers(&mut self, peer_timeout: Duration) -> Vec<Peer> {
+        self.peers = self.peers.drain()
+            .map(|mut peer| {
+                if !peer.manual {
+                    peer.missed_beacons += 1;
+                }
+                peer
+            })
+            .collect();
+        self.remove_peers(|peer| {
+            !peer.manual
+                && peer.missed_beacons > MAX_MISSED_BEACONS
+                && peer.last_seen.is_none_or(|last_seen| last_seen.elapsed() >= peer_timeout)
+        })
+    }
+
+    /// Inserts or refreshes a discovered peer, keeping it manual if it was added by hand.
+    /// Returns whether the peer list visibly changed.
+    fn upsert_peer(&mut self, mut peer: Peer) -> bool {
+        // Anything else known at this address is this peer now: a manual entry, a pre-id
+        // announcement, or a device that had the address before
+        let stale: Vec<Peer> = self.peers.iter()
+            .filter(|old| old.address == peer.address && old.id != peer.id)
+            .cloned()
+            .collect();
+        peer.manual = self.peers.get(&peer).is_some_and(|old| old.manual) || stale.iter().any(|old| old.manual);
+        // mDNS can't say, that doesn't undo what a signed presence proved
+        if peer.verified.is_none() {
+            peer.verified = self.peers.get(&peer).and_then(|old| old.verified);
+        }
+        for old in &stale {
+            self.peers.remove(old);
+        }
+
+        match self.peers.replace(peer.clone()) {
+            None => true, // It's a new peer
+            // It's an existing peer, check if anything it announces changed
+            Some(old) => !stale.is_empty()
+                || old.address != peer.address
+                || old.username != peer.username
+                || old.os != peer.os
+                || old.device_type != peer.device_type
+                || old.transfer_port != peer.transfer_port
+                || old.capabilities != peer.capabilities
+                || old.protocol_version != peer.protocol_version
+                || old.status != peer.status
+                || old.verified != peer.verified,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AppState(Arc<Mutex<SharedState>>);
+
+/// Payload of `peer_joined`, emitted next to `peers_updated` for a peer we didn't know.
+#[derive(Clone, Serialize)]
+struct PeerJoined {
+    peer_id: String,
+    username: String,
+    address: String,
+}
+
+/// Payload of `peer_left`, emitted next to `peers_updated` for every peer that's gone.
+#[derive(Clone, Serialize)]
+struct PeerLeft {
+    peer_id: String,
+    username: String,
+}
+
+fn emit_peer_joined(app: &AppHandle, peer: &Peer) {
+    app.emit("peer_joined", PeerJoined {
+        peer_id: peer.id.clone(),
+        username: peer.username.clone(),
+        address: peer.address.clone(),
+    }).unwrap();
+}
+
+fn emit_peer_left(app: &AppHandle, peer: &Peer) {
+    app.emit("peer_left", PeerLeft {
+        peer_id: peer.id.clone(),
+        username: peer.username.clone(),
+    }).unwrap();
+}
+
+/// Drops peers not heard from in `older_than_secs`, without waiting for the presence
+/// timeout. Manual peers are kept unless `include_manual` is set, in which case the ones
+/// that never announced themselves go too. Returns how many were removed.
+#[tauri::command]
+fn clear_stale_peers(app: AppHandle, older_than_secs: u64, include_manual: Option<bool>, state: tauri::State<AppState>) -> usize {
+    let include_manual = include_manual.unwrap_or(false);
+    let max_age = Duration::from_secs(older_than_secs);
+    let removed = state.0.lock().unwrap().remove_peers(|peer| {
+        (include_manual || !peer.manual) && peer.last_seen.is_none_or(|last_seen| last_seen.elapsed() >= max_age)
+    });
+    emit_peers_removed(&app, &removed);
+    removed.len()
+}
+
+/// Forgets every known peer, manual ones included, e.g. after switching networks.
+/// Discovered peers that are still around come back with their next presence.
+#[tauri::command]
+fn clear_all_peers(app: AppHandle, state: tauri::State<AppState>) -> usize {
+    let removed = state.0.lock().unwrap().remove_peers(|_| true);
+    emit_peers_removed(&app, &removed);
+    removed.len()
+}
+
+fn emit_peers_removed(app: &AppHandle, removed: &[Peer]) {
+    if removed.is_empty() {
+        return;
+    }
+    for peer in removed {
+        info!(peer_id = %peer.id, username = %peer.username, "Peer cleared");
+        emit_peer_left(app, peer);
+    }
+    emit_peers_updated(app);
+}
+
+#[tauri::command]
+fn get_listen_status(state: tauri::State<AppState>) -> ListenStatus {
+    state.0.lock().unwrap().listen_status.clone()
+}
+
+#[tauri::command]
+fn get_network_interfaces() -> Vec<NetworkInterfaceInfo> {
+    let mut interfaces = vec![];
+    if let Ok(ifaces) = network_interface::NetworkInterface::show() {
+        for iface in ifaces {
+            if iface.name == "lo" {
+                continue;
+            }
+            let mut has_v6_entry = false;
+            for addr in iface.addr {
+                match addr.ip() {
+                    IpAddr::V4(ipv4) => {
+                        if let Some(broadcast) = addr.broadcast() {
+                            interfaces.push(NetworkInterfaceInfo {
+                                name: iface.name.clone(),
+                                ip: ipv4.to_string(),
+                                broadcast: broadcast.to_string(),
+                            });
+                        }
+                    }
+                    // One entry per link is enough, they all share the same multicast scope
+                    IpAddr::V6(ipv6) if !ipv6.is_loopback() && !has_v6_entry => {
+                        has_v6_entry = true;
+                        interfaces.push(NetworkInterfaceInfo {
+                            name: iface.name.clone(),
+                            ip: ipv6.to_string(),
+                            broadcast: format!("{}%{}", DISCOVERY_MULTICAST_V6, iface.index),
+                        });
+                    }
+                    IpAddr::V6(_) => {}
+                }
+            }
+        }
+    }
+    interfaces.push(NetworkInterfaceInfo {
+                            name: "All".to_string(),
+                            ip: "255.255.255.255".to_string(),
+                            broadcast: "255.255.255.255".to_string(),
+                        });
+    interfaces
+}
+
+/// The interface picked with `bind_interface`, as far as binding needs it.
+#[derive(Debug, Clone)]
+struct BindInterface {
+    name: String,
+    ip: Ipv4Addr,
+    index: u32,
+}
+
+/// Looks up the `bind_interface` setting. `None` means binding every interface, either
+/// because none is selected or because it's gone, which is logged and reported to the UI
+/// as `bind-interface-unavailable`.
+fn resolve_bind_interface(app: &AppHandle) -> Option<BindInterface> {
+    let name = app.state::<AppState>().0.lock().unwrap().settings.bind_interface.clone()?;
+    let found = NetworkInterface::show().ok()?.into_iter()
+        .filter(|iface| iface.name == name)
+        .find_map(|iface| iface.addr.iter().find_map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Some(BindInterface { name: name.clone(), ip, index: iface.index }),
+            IpAddr::V6(_) => None,
+        }));
+    match &found {
+        Some(interface) => info!(interface = %interface.name, ip = %interface.ip, "Binding to a single interface"),
+        None => {
+            warn!(interface = %name, "Selected interface not found, binding all interfaces");
+            let _ = app.emit("bind-interface-unavailable", &name);
+        }
+    }
+    found
+}
+
+#[tauri::command]
+fn get_users(state: tauri::State<AppState>) -> Vec<Peer> {
+    let state = state.0.lock().unwrap();
+    state.peers.iter()
+        .map(|peer| Peer {
+            alias: state.aliases.get(&peer.id).cloned(),
+            last_seen_ms_ago: peer.last_seen.map(|last_seen| last_seen.elapsed().as_millis() as u64),
+            ..peer.clone()
+        })
+        .collect()
+}
+
+/// Sets or, with an empty or missing alias, clears the local name for a peer.
+#[tauri::command]
+fn set_peer_alias(
+    app: AppHandle,
+    peer_id: String,
+    alias: Option<String>,
+    state: tauri::State<AppState>,
+) -> Result<(), String> {
+    let path = app.path().app_config_dir().map_err(|e| e.to_string())?.join(ALIASES_FILE);
+    {
+        let mut state = state.0.lock().unwrap();
+        match alias.map(|alias| alias.trim().to_string()).filter(|alias| !alias.is_empty()) {
+            Some(alias) => state.aliases.insert(peer_id, alias),
+            None => state.aliases.remove(&peer_id),
+        };
+        write_json_atomic(&path, &state.aliases).map_err(|e| e.to_string())?;
+    }
+    emit_peers_updated(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn add_manual_peer(
+    app: AppHandle,
+    address: String,
+    username: Option<String>,
+    port: Option<u16>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    let address = address.trim().to_string();
+    let transfer_port = port.unwrap_or(DEFAULT_TRANSFER_PORT);
+    probe_peer(&address, transfer_port).await.map_err(|e| format!("Peer {} is unreachable: {}", address, e))?;
+
+    let peer = Peer {
+        id: address.clone(),
+        username: username.filter(|name| !name.trim().is_empty()).unwrap_or_else(|| address.clone()),
+        address,
+        os: None,
+        device_type: None,
+        alias: None,
+        last_seen_ms_ago: None,
+        last_seen: None,
+        missed_beacons: 0,
+        manual: true,
+        transfer_port,
+        status: None,
+        capabilities: None,
+        protocol_version: None,
+        verified: None,
+    };
+    if state.0.lock().unwrap().peers.replace(peer.clone()).is_none() {
+        emit_peer_joined(&app, &peer);
+    }
+    emit_peers_updated(&app);
+    Ok(())
+}
+
+/// Checks that something accepts connections on the peer's transfer port.
+async fn probe_peer(address: &str, port: u16) -> Result<(), String> {
+    let target_addr = peer_socket_addr(address, port);
+    match tokio::time::timeout(MANUAL_PEER_CONNECT_TIMEOUT, TcpStream::connect(&target_addr)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("connection timed out".to_string()),
+    }
+}
+
+/// What `diagnose_peer` found out about an address.
+#[derive(Debug, Serialize)]
+struct DiagnosticReport {
+    address: String,
+    /// Whether anything arrived on the discovery port from the address within the peer
+    /// timeout, even if it was then dropped
+    presence_received: bool,
+    /// Age of the last datagram from the address, `None` if nothing came since startup
+    last_presence_ms_ago: Option<u64>,
+    /// Whether the address is in the peer list
+    listed: bool,
+    /// The port it announced, or the default one
+    transfer_port: u16,
+    /// Whether a TCP connection to `transfer_port` could be opened, and how long it took
+    connect_ok: bool,
+    connect_ms: u64,
+    connect_error: Option<String>,
+    /// Whether `allowed_cidrs` lets the address through, always true when they're empty
+    allowed_by_cidrs: bool,
+    blocked: bool,
+}
+
+/// Looks into why a peer doesn't show up or can't be sent to, for bug reports more
+/// useful than "it doesn't work".
+#[tauri::command]
+async fn diagnose_peer(address: String, state: tauri::State<'_, AppState>) -> Result<DiagnosticReport, String> {
+    let address = address.trim().to_string();
+    let ip: IpAddr = address.parse().map_err(|_| format!("Not an IP address: {}", address))?;
+    let (last_heard, peer_timeout, listed, transfer_port, allowed_by_cidrs, blocked) = {
+        let state = state.0.lock().unwrap();
+        (
+            state.presence_heard.get(&ip.to_canonical()).copied(),
+            Duration::from_secs(state.settings.peer_timeout_secs),
+            state.peers.iter().any(|peer| peer.address == address),
+            state.peer_transfer_port(&address),
+            state.is_allowed_ip(ip),
+            state.is_blocked(None, &address),
+        )
+    };
+    let connecting = Instant::now();
+    let connected = probe_peer(&address, transfer_port).await;
+    Ok(DiagnosticReport {
+        presence_received: last_heard.is_some_and(|heard| heard.elapsed() < peer_timeout),
+        last_presence_ms_ago: last_heard.map(|heard| heard.elapsed().as_millis() as u64),
+        listed,
+        transfer_port,
+        connect_ok: connected.is_ok(),
+        connect_ms: connecting.elapsed().as_millis() as u64,
+        connect_error: connected.err(),
+        allowed_by_cidrs,
+        blocked,
+        address,
+    })
+}
+
+/// Transfers from the history that go into `export_diagnostics`
+const DIAGNOSTICS_HISTORY_LEN: usize = 50;
+
+/// What `export_diagnostics` puts into a report.
+#[derive(Serialize)]
+struct DiagnosticsExport {
+    version: &'static str,
+    protocol_version: u8,
+    os: &'static str,
+    /// With the shared secret redacted and the home directory shortened to `~`
+    settings: UserSettings,
+    listen_status: ListenStatus,
+    peers: Vec<Peer>,
+    interfaces: Vec<NetworkInterfaceInfo>,
+    /// Ids of the sends running right now
+    active_transfers: Vec<String>,
+    queue: Vec<QueueEntry>,
+    stats: StatsSnapshot,
+    recent_transfers: Vec<TransferRecord>,
+}
+
+/// `path` with the home directory replaced by `~`, which names the user on most systems.
+fn redact_home(app: &AppHandle, path: &str) -> String {
+    let home = app.path().home_dir().ok();
+    match home.as_deref().and_then(|home| std::path::Path::new(path).strip_prefix(home).ok()) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => format!("~{}{}", std::path::MAIN_SEPARATOR, rest.display()),
+        None => path.to_string(),
+    }
+}
+
+/// Everything a bug report needs about our settings, peers and transfers as one JSON
+/// document for the user to paste. The shared secret never goes in.
+#[tauri::command]
+fn export_diagnostics(app: AppHandle) -> Result<String, String> {
+    let mut settings = app.state::<AppState>().0.lock().unwrap().settings.clone();
+    settings.shared_secret = settings.shared_secret.map(|_| "<redacted>".to_string());
+    settings.download_dir = settings.download_dir.map(|dir| redact_home(&app, &dir));
+    let mut active_transfers: Vec<String> = app.state::<ActiveTransfers>().lock().unwrap().keys().cloned().collect();
+    active_transfers.sort();
+    let mut queue = app.state::<Arc<TransferQueue>>().snapshot();
+    for entry in &mut queue {
+        for path in &mut entry.file_paths {
+            *path = redact_home(&app, path);
+        }
+    }
+    let report = DiagnosticsExport {
+        version: env!("CARGO_PKG_VERSION"),
+        protocol_version: PROTOCOL_VERSION,
+        os: std::env::consts::OS,
+        settings,
+        listen_status: get_listen_status(app.state()),
+        peers: get_users(app.state()),
+        interfaces: get_network_interfaces(),
+        active_transfers,
+        queue,
+        stats: app.state::<Arc<TransferStats>>().snapshot(),
+        recent_transfers: get_transfer_history(app.clone(), DIAGNOSTICS_HISTORY_LEN)?,
+    };
+    serde_json::to_string_pretty(&report).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_settings(state: tauri::State<AppState>) -> UserSettings {
+    let state = state.0.lock().unwrap();
+    state.settings.clone()
+}
+
+#[tauri::command]
+fn update_settings(app: AppHandle, mut settings: UserSettings, state: tauri::State<AppState>) -> Result<(), String> {
+    if let Some(dir) = &settings.download_dir {
+        validate_download_dir(std::path::Path::new(dir))?;
+    }
+    if settings.discovery_port == 0 || settings.transfer_port == 0 {
+        return Err("Ports must be between 1 and 65535".to_string());
+    }
+    parse_cidrs(&settings.allowed_cidrs)?;
+    settings.broadcast_addresses.retain(|address| !address.trim().is_empty());
+    for address in std::iter::once(&settings.broadcast_address).chain(&settings.broadcast_addresses) {
+        validate_broadcast_address(address.trim())?;
+    }
+    settings.seed_peers.retain(|address| !address.trim().is_empty());
+    for address in &settings.seed_peers {
+        parse_seed_peer(address.trim(), settings.discovery_port)
+            .ok_or_else(|| format!("Not an IP address or ip:port: {:?}", address))?;
+    }
+    for pattern in &settings.auto_accept_patterns {
+        glob::Pattern::new(pattern).map_err(|e| format!("Invalid pattern {:?}: {}", pattern, e))?;
+    }
+    if !(MIN_TRANSFER_BUFFER_KIB..=MAX_TRANSFER_BUFFER_KIB).contains(&settings.transfer_buffer_kib) {
+        return Err(format!(
+            "Transfer buffer must be between {} and {} KiB",
+            MIN_TRANSFER_BUFFER_KIB, MAX_TRANSFER_BUFFER_KIB,
+        ));
+    }
+    settings.shared_secret = settings.shared_secret.filter(|secret| !secret.is_empty());
+    state.0.lock().unwrap().settings = settings.clone();
+    if let Some(ports) = app.try_state::<Ports>() {
+        ports.update(&settings);
+    }
+    save_settings(&app, &settings)
+}
+
+fn save_settings(app: &AppHandle, settings: &UserSettings) -> Result<(), String> {
+    let config_dir = app.path().app_config_dir().map_err(|e| e.to_string())?;
+    write_json_atomic(&config_dir.join(SETTINGS_FILE), settings).map_err(|e| e.to_string())
+}
+
+/// Blocks a peer by id or address: it disappears from the peer list and its offers are
+/// rejected without asking.
+#[tauri::command]
+fn block_peer(app: AppHandle, peer: String, state: tauri::State<AppState>) -> Result<(), String> {
+    let (settings, removed) = {
+        let mut state = state.0.lock().unwrap();
+        state.settings.blocked_peers.insert(peer.clone());
+        let removed: Vec<Peer> = state.peers.iter()
+            .filter(|known| known.id == peer || known.address == peer)
+            .cloned()
+            .collect();
+        removed.iter().for_each(|known| { state.peers.remove(known); });
+        (state.settings.clone(), removed)
+    };
+    removed.iter().for_each(|known| emit_peer_left(&app, known));
+    emit_peers_updated(&app);
+    save_settings(&app, &settings)
+}
+
+/// The PIN an unpaired peer has to enter to send to us.
+#[tauri::command]
+fn get_pairing_pin(state: tauri::State<AppState>) -> String {
+    state.0.lock().unwrap().pairing.pin.clone()
+}
+
+/// Remembers the PIN `address` showed, sent with every request to it until it knows us.
+#[tauri::command]
+fn set_pairing_pin(address: String, pin: String, state: tauri::State<AppState>) {
+    state.0.lock().unwrap().pairing.pins_for_peers.insert(address, pin.trim().to_string());
+}
+
+/// Forgets all peers that paired with us, they'll need the PIN again.
+#[tauri::command]
+fn clear_paired_peers(app: AppHandle, state: tauri::State<AppState>) -> Result<(), String> {
+    let config_dir = app.path().app_config_dir().map_err(|e| e.to_string())?;
+    let mut state = state.0.lock().unwrap();
+    state.pairing.paired_peers.clear();
+    write_json_atomic(&config_dir.join(PAIRED_PEERS_FILE), &state.pairing.paired_peers).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn unblock_peer(app: AppHandle, peer: String, state: tauri::State<AppState>) -> Result<(), String> {
+    let settings = {
+        let mut state = state.0.lock().unwrap();
+        state.settings.blocked_peers.remove(&peer);
+        state.settings.clone()
+    };
+    save_settings(&app, &settings)
+}
+
+/// Adds or removes a peer, by id, from the peers whose offers are accepted without asking.
+#[tauri::command]
+fn set_peer_auto_accept(app: AppHandle, peer_id: String, enabled: bool, state: tauri::State<AppState>) -> Result<(), String> {
+    let settings = {
+        let mut state = state.0.lock().unwrap();
+        if enabled {
+            state.settings.auto_accept_peers.insert(peer_id);
+        } else {
+            state.settings.auto_accept_peers.remove(&peer_id);
+        }
+        state.settings.clone()
+    };
+    save_settings(&app, &settings)
+}
+
+/// Checks that `dir` exists and that we can actually create files in it.
+fn validate_download_dir(dir: &std::path::Path) -> Result<(), String> {
+    if !dir.is_dir() {
+        return Err(format!("Download directory does not exist: {}", dir.display()));
+    }
+    let probe = dir.join(format!(".kitsunet-{}", Uuid::new_v4()));
+    std::fs::File::create(&probe)
+        .map_err(|e| format!("Download directory is not writable: {}", e))?;
+    let _ = std::fs::remove_file(probe);
+    Ok(())
+}
+
+/// The configured download directory, or the system one when none is set.
+fn resolve_download_dir(app: &AppHandle) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let configured = app.state::<AppState>().0.lock().unwrap().settings.download_dir.clone();
+    match configured {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => app.path().download_dir().map_err(|_| {
+            Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "Download directory not found")) as Box<dyn Error + Send + Sync>
+        }),
+    }
+}
+
+/// Whether received files can be saved at all, checked before an offer is shown so the
+/// user isn't asked about files that would fail anyway.
+fn check_storage(app: &AppHandle) -> Result<(), String> {
+    let dir = resolve_download_dir(app).map_err(|e| e.to_string())?;
+    validate_download_dir(&dir)
+}
+
+fn offer_timeout(app: &AppHandle) -> Duration {
+    Duration::from_secs(app.state::<AppState>().0.lock().unwrap().settings.offer_timeout_secs)
+}
+
+/// Allowed range of `transfer_buffer_kib`. Compressed chunks are one buffer each, so the
+/// top is what any receiver takes, see `MAX_COMPRESSED_CHUNK_LEN`.
+const MIN_TRANSFER_BUFFER_KIB: usize = 16;
+const MAX_TRANSFER_BUFFER_KIB: usize = 16 * 1024;
+
+/// `transfer_buffer_kib` in bytes. Everything goes through TLS, which has to encrypt it
+/// in userspace, so there's no `sendfile` shortcut and the buffer size is what's left to tune.
+fn transfer_buffer_len(app: &AppHandle) -> usize {
+    let kib = app.state::<AppState>().0.lock().unwrap().settings.transfer_buffer_kib;
+    kib.clamp(MIN_TRANSFER_BUFFER_KIB, MAX_TRANSFER_BUFFER_KIB) * 1024
+}
+
+fn receive_timeout(app: &AppHandle) -> Duration {
+    Duration::from_secs(app.state::<AppState>().0.lock().unwrap().settings.receive_timeout_secs.max(1))
+}
+
+const LOG_FILE: &str = "kitsunet.log";
+/// Log filter, same syntax as `RUST_LOG` (e.g. `kitsunet_share=debug`)
+const LOG_FILTER_ENV: &str = "KITSUNET_LOG";
+
+/// Keeps the background log writer alive, dropped on exit to flush what's buffered.
+struct LogGuard(Mutex<Option<tracing_appender::non_blocking::WorkerGuard>>);
+
+/// Logs to `kitsunet.log` in `log_dir` and to stderr, `info` and up unless `KITSUNET_LOG`
+/// says otherwise.
+fn init_logging(log_dir: &std::path::Path) -> tracing_appender::non_blocking::WorkerGuard {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let (file_writer, guard) = tracing_appender::non_blocking(tracing_appender::rolling::never(log_dir, LOG_FILE));
+    let filter = tracing_subscriber::EnvFilter::try_from_env(LOG_FILTER_ENV)
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(file_writer).with_ansi(false))
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .init();
+    guard
+}
+
+/// Where the log file is, for attaching to bug reports.
+#[tauri::command]
+fn get_log_path(app: AppHandle) -> Result<String, String> {
+    let dir = app.path().app_log_dir().map_err(|e| e.to_string())?;
+    Ok(dir.join(LOG_FILE).to_string_lossy().into_owned())
+}
+
+/// Loads this installation's id from the config dir, generating it on first launch.
+/// Peers key on it, so a device keeps its identity when its IP changes.
+fn load_device_id(app: &AppHandle) -> String {
+    let Ok(dir) = app.path().app_config_dir() else {
+        return Uuid::new_v4().to_string();
+    };
+    let path = dir.join(DEVICE_ID_FILE);
+    if let Some(id) = std::fs::read_to_string(&path).ok().and_then(|id| Uuid::parse_str(id.trim()).ok()) {
+        return id.to_string();
+    }
+    let id = Uuid::new_v4().to_string();
+    if let Err(e) = write_atomic(&path, id.as_bytes()) {
+        error!(error = %e, "Failed to save device id");
+    }
+    id
+}
+
+fn load_aliases(app: &AppHandle) -> HashMap<String, String> {
+    app.path()
+        .app_config_dir()
+        .ok()
+        .and_then(|dir| std::fs::read(dir.join(ALIASES_FILE)).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn load_paired_peers(app: &AppHandle) -> HashSet<String> {
+    app.path()
+        .app_config_dir()
+        .ok()
+        .and_then(|dir| std::fs::read(dir.join(PAIRED_PEERS_FILE)).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Reads the persisted settings, falling back to defaults when the file is missing or malformed.
+fn load_settings(app: &AppHandle) -> UserSettings {
+    app.path()
+        .app_config_dir()
+        .ok()
+        .and_then(|dir| std::fs::read(dir.join(SETTINGS_FILE)).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Writes JSON to a temp file next to `path` and renames it into place, so a crash
+/// mid-write never leaves a truncated file behind.
+fn write_json_atomic<T: Serialize>(path: &std::path::Path, value: &T) -> std::io::Result<()> {
+    write_atomic(path, &serde_json::to_vec_pretty(value)?)
+}
+
+/// Temp file + rename, see `write_json_atomic`.
+fn write_atomic(path: &std::path::Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// `write_atomic` on the blocking threads, for writes made while a transfer is running.
+async fn spawn_write_atomic(path: PathBuf, bytes: Vec<u8>) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || write_atomic(&path, &bytes)).await.map_err(std::io::Error::other)?
+}
+
+#[tauri::command]
+async fn get_own_address() -> Result<String, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await.map_err(|e| e.to_string())?;
+    socket.connect("8.8.8.8:80").await.map_err(|e| e.to_string())?;
+    let local_addr = socket.local_addr().map_err(|e| e.to_string())?;
+    Ok(local_addr.ip().to_string())
+}
+
+/// Expands the selected paths into the list of files to send. Directories are walked
+/// recursively and their files get a `/`-separated path relative to the selected folder.
+async fn collect_files_metadata(file_paths: &[String]) -> Result<Vec<(PathBuf, FileMetadata)>, TransferError> {
+    let mut batch = Vec::new();
+    for path_str in file_paths {
+        let path = PathBuf::from(path_str);
+        let file_name = path.file_name()
+            .ok_or_else(|| TransferError::InvalidPath(path_str.clone()))?
+            .to_str()
+            .ok_or_else(|| TransferError::InvalidPath(path_str.clone()))?
+            .to_string();
+
+        // Walk directories with an explicit stack; symlinked directories are not followed
+        let mut pending = vec![(path, file_name)];
+        while let Some((path, relative_path)) = pending.pop() {
+            if let Some(symlink_target) = sendable_link_target(&path, &relative_path).await {
+                batch.push((path, FileMetadata {
+                    path: relative_path,
+                    size: 0,
+                    sha256: None,
+                    content_id: None,
+                    compressed: false,
+                    ranges: Vec::new(),
+                    mode: None,
+                    symlink_target: Some(symlink_target),
+                    streamed: false,
+                    encryption: None,
+                }));
+                continue;
+            }
+            let metadata = tokio::fs::metadata(&path).await.map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => TransferError::NotFound(path.to_string_lossy().into_owned()),
+                _ => e.into(),
+            })?;
+            if !metadata.is_dir() {
+                let content_id = content_id(&path, metadata.len()).await;
+                batch.push((path, FileMetadata {
+                    path: relative_path,
+                    size: metadata.len(),
+                    sha256: None,
+                    content_id,
+                    compressed: false,
+                    ranges: Vec::new(),
+                    mode: unix_mode(&metadata),
+                    symlink_target: None,
+                    streamed: false,
+                    encryption: None,
+                }));
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name()
+                    .into_string()
+                    .map_err(|name| TransferError::InvalidPath(name.to_string_lossy().into_owned()))?;
+                let file_type = entry.file_type().await?;
+                let entry_path = format!("{}/{}", relative_path, name);
+                if file_type.is_symlink()
+                    && sendable_link_target(&entry.path(), &entry_path).await.is_none()
+                    && tokio::fs::metadata(entry.path()).await.is_ok_and(|m| m.is_dir())
+                {
+                    continue;
+                }
+                pending.push((entry.path(), entry_path));
+            }
+        }
+    }
+    Ok(batch)
+}
+
+/// The target of the symlink at `path`, `/`-separated, if it's a link that can be sent as
+/// one from `relative_path`: see `safe_symlink_target`. Other links are followed instead,
+/// they'd point elsewhere on the recipient.
+async fn sendable_link_target(path: &std::path::Path, relative_path: &str) -> Option<String> {
+    let target = tokio::fs::read_link(path).await.ok().filter(|target| target.is_relative())?;
+    let parts: Option<Vec<&str>> = target.components()
+        .map(|component| match component {
+            std::path::Component::Normal(name) => name.to_str(),
+            std::path::Component::ParentDir => Some(".."),
+            std::path::Component::CurDir => Some("."),
+            _ => None,
+        })
+        .collect();
+    Some(parts?.join("/")).filter(|target| safe_symlink_target(relative_path, target).is_some())
+}
+
+#[cfg(unix)]
+fn unix_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Strips the folders from the relative paths of `batch`. Files that end up with the same
+/// name are numbered like `create_unique_file` does, so none overwrites another. Symlinks
+/// are left out, they point into the folders that are gone.
+fn flatten_paths(batch: &mut Vec<(PathBuf, FileMetadata)>) {
+    batch.retain(|(_, file_meta)| file_meta.symlink_target.is_none());
+    let mut taken = HashSet::new();
+    for (_, file_meta) in batch {
+        let name = file_meta.path.rsplit('/').next().unwrap_or_default().to_string();
+        let mut candidate = name.clone();
+        let mut counter = 0;
+        while !taken.insert(candidate.clone()) {
+            counter += 1;
+            candidate = numbered_name(std::path::Path::new(&name), counter);
+        }
+        file_meta.path = candidate;
+    }
+}
+
+/// Bytes at the start of a file covered by its content id
+const CONTENT_ID_PREFIX_LEN: u64 = 1024 * 1024;
+
+/// Identifies a file's content without hashing all of it: the declared size plus the
+/// SHA-256 of its first megabyte. `None` if the file holds fewer bytes than that covers,
+/// which for a `.part` file means there's too little of it to be worth resuming.
+async fn content_id(path: &std::path::Path, size: u64) -> Option<String> {
+    let file = tokio::fs::File::open(path).await.ok()?;
+    let prefix_len = size.min(CONTENT_ID_PREFIX_LEN);
+    let mut prefix = Vec::with_capacity(prefix_len as usize);
+    file.take(prefix_len).read_to_end(&mut prefix).await.ok()?;
+    if (prefix.len() as u64) < prefix_len {
+        return None;
+    }
+    Some(format!("{}-{:x}", size, Sha256::digest(&prefix)))
+}
+
+/// How many bytes of `file_meta` can be kept from a previous attempt's `.part` file.
+async fn resume_offset(part_path: &std::path::Path, file_meta: &FileMetadata) -> u64 {
+    // Ranges are written out of order, a `.part` of them has no usable prefix
+    let Some(expected) = file_meta.content_id.as_ref().filter(|_| file_meta.ranges.is_empty()) else {
+        return 0;
+    };
+    let Ok(len) = tokio::fs::metadata(part_path).await.map(|m| m.len()) else {
+        return 0;
+    };
+    if len > file_meta.size {
+        return 0;
+    }
+    match content_id(part_path, file_meta.size).await {
+        Some(id) if id == *expected => len,
+        _ => 0,
+    }
+}
+
+/// Whether `target_path` already holds exactly the file described by `file_meta`.
+async fn is_duplicate(target_path: &std::path::Path, file_meta: &FileMetadata) -> bool {
+    let Some(expected) = &file_meta.sha256 else {
+        return false;
+    };
+    match tokio::fs::metadata(target_path).await {
+        Ok(metadata) if metadata.is_file() && metadata.len() == file_meta.size => {}
+        _ => return false,
+    }
+    hash_file(target_path).await.is_ok_and(|sha256| sha256 == *expected)
+}
+
+/// Feeds the first `len` bytes of `reader` into `hasher`, leaving the reader positioned
+/// right after them.
+async fn hash_prefix<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    len: u64,
+    hasher: &mut Sha256,
+    buffer: &mut [u8],
+) -> std::io::Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = std::cmp::min(buffer.len() as u64, remaining) as usize;
+        reader.read_exact(&mut buffer[..chunk]).await?;
+        hasher.update(&buffer[..chunk]);
+        remaining -= chunk as u64;
+    }
+    Ok(())
+}
+
+/// Where a file is written while it's being received: `<name>.part` next to the target.
+fn part_path(target_path: &std::path::Path) -> PathBuf {
+    let mut name = target_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    target_path.with_file_name(name)
+}
+
+/// How often `PartCheckpointer` rewrites the sidecar of a file being received
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+/// Directory levels below the download directory searched for `.part` files on startup
+const RESUMABLE_SCAN_DEPTH: usize = 4;
+
+/// What's known about a `.part` file, kept next to it in `<name>.part.json` so an
+/// interrupted transfer can still be resumed after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartCheckpoint {
+    peer_id: String,
+    /// Path of the file relative to the batch root, as the sender named it
+    file_name: String,
+    size: u64,
+    bytes_received: u64,
+    /// Hex SHA-256 of the first `bytes_received` bytes
+    sha256_so_far: String,
+}
+
+fn sidecar_path(part_path: &std::path::Path) -> PathBuf {
+    let mut name = part_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".json");
+    part_path.with_file_name(name)
+}
+
+/// Writes the sidecar of a `.part` file at most every `CHECKPOINT_INTERVAL`.
+struct PartCheckpointer {
+    sidecar: PathBuf,
+    checkpoint: PartCheckpoint,
+    last_saved: Option<Instant>,
+}
+
+impl PartCheckpointer {
+    fn new(part_path: &std::path::Path, peer_id: &str, file_meta: &FileMetadata) -> Self {
+        Self {
+            sidecar: sidecar_path(part_path),
+            checkpoint: PartCheckpoint {
+                peer_id: peer_id.to_string(),
+                file_name: file_meta.path.clone(),
+                size: file_meta.size,
+                bytes_received: 0,
+                sha256_so_far: String::new(),
+            },
+            last_saved: None,
+        }
+    }
+
+    /// Records that `bytes_received` bytes hashing to `hasher` are on disk. Failing to write
+    /// the sidecar only costs the resume after a restart, so it's just logged.
+    async fn update(&mut self, bytes_received: u64, hasher: &Sha256) {
+        if self.last_saved.is_some_and(|last_saved| last_saved.elapsed() < CHECKPOINT_INTERVAL) {
+            return;
+        }
+        self.last_saved = Some(Instant::now());
+        self.checkpoint.bytes_received = bytes_received;
+        self.checkpoint.sha256_so_far = format!("{:x}", hasher.clone().finalize());
+        let written = match serde_json::to_vec_pretty(&self.checkpoint) {
+            Ok(bytes) => spawn_write_atomic(self.sidecar.clone(), bytes).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = written {
+            warn!(file = %self.checkpoint.file_name, error = %e, "Failed to write resume checkpoint");
+        }
+    }
+}
+
+/// An interrupted incoming file found on startup, see `get_resumable_transfers`.
+#[derive(Debug, Clone, Serialize)]
+struct ResumableTransfer {
+    part_path: PathBuf,
+    /// Bytes in the `.part` file, what a resume would start from
+    bytes_received: u64,
+    /// From the sidecar, `None` for `.part` files left without one
+    #[serde(flatten)]
+    checkpoint: Option<PartCheckpoint>,
+}
+
+/// The `.part` files `file_receiver_task` found in the download directory when it started.
+#[derive(Default)]
+struct ResumableParts(Mutex<Vec<ResumableTransfer>>);
+
+/// Looks for `.part` files under `dir`, without following links, along with their sidecars.
+fn find_resumable_transfers(dir: &std::path::Path, depth: usize, found: &mut Vec<ResumableTransfer>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_dir() && depth > 0 {
+            find_resumable_transfers(&path, depth - 1, found);
+        } else if file_type.is_file() && path.extension().is_some_and(|extension| extension == "part") {
+            let checkpoint = std::fs::read(sidecar_path(&path))
+                .ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+            found.push(ResumableTransfer {
+                bytes_received: entry.metadata().map_or(0, |metadata| metadata.len()),
+                part_path: path,
+                checkpoint,
+            });
+        }
+    }
+}
+
+/// The interrupted incoming files found on startup that are still there, so the user can
+/// ask their senders to send them again, which picks up from the `.part` file.
+#[tauri::command]
+fn get_resumable_transfers(parts: tauri::State<ResumableParts>) -> Vec<ResumableTransfer> {
+    let mut parts = parts.0.lock().unwrap();
+    parts.retain(|part| part.part_path.is_file());
+    parts.clone()
+}
+
+/// Batches `BatchJournal` remembers at once, the oldest is forgotten beyond that
+const MAX_JOURNALED_BATCHES: usize = 100;
+
+/// A file of an unfinished batch that was received and saved, see `BatchJournal`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompletedFile {
+    index: usize,
+    sha256: String,
+    /// Where it was saved, which isn't the offered path if that name was taken
+    saved_path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournaledBatch {
+    key: String,
+    files: Vec<CompletedFile>,
+}
+
+/// The files received so far of batches that didn't finish, kept in the config dir so a
+/// sender offering the batch again, even after a restart on either side, only sends the
+/// rest. Files already on disk under their own name are found by `is_duplicate` anyway,
+/// this also finds the ones saved as `name (1).ext`.
+struct BatchJournal {
+    path: PathBuf,
+    batches: Mutex<VecDeque<JournaledBatch>>,
+    /// Held from taking a snapshot of `batches` until it's written, so an older one
+    /// never overwrites a newer one
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl BatchJournal {
+    fn load(path: PathBuf) -> Self {
+        let batches = std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Self { path, batches: Mutex::new(batches), saving: tokio::sync::Mutex::new(()) }
+    }
+
+    /// What identifies a batch across attempts: its sender and files. The transfer id
+    /// doesn't, every attempt gets a new one. `None` unless all files carry a checksum.
+    fn key(sender_id: &str, files: &[FileMetadata]) -> Option<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(sender_id.as_bytes());
+        for file in files {
+            hasher.update([0]);
+            hasher.update(file.path.as_bytes());
+            hasher.update(file.size.to_be_bytes());
+            hasher.update(file.sha256.as_deref()?.as_bytes());
+        }
+        Some(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Where file `index` of the batch was saved, if it was and is still there.
+    async fn saved_path(&self, key: &str, index: usize, file_meta: &FileMetadata) -> Option<PathBuf> {
+        let saved_path = {
+            let batches = self.batches.lock().unwrap();
+            let batch = batches.iter().find(|batch| batch.key == key)?;
+            let file = batch.files.iter().find(|file| file.index == index)?;
+            (Some(&file.sha256) == file_meta.sha256.as_ref()).then(|| file.saved_path.clone())?
+        };
+        // Trusting the checksum we verified when it arrived, as long as nothing replaced it
+        match tokio::fs::metadata(&saved_path).await {
+            Ok(metadata) if metadata.is_file() && metadata.len() == file_meta.size => Some(saved_path),
+            _ => None,
+        }
+    }
+
+    async fn file_saved(&self, key: &str, index: usize, file_meta: &FileMetadata, saved_path: &std::path::Path) {
+        let Some(sha256) = file_meta.sha256.clone() else {
+            return;
+        };
+        let _saving = self.saving.lock().await;
+        let snapshot = {
+            let mut batches = self.batches.lock().unwrap();
+            let position = match batches.iter().position(|batch| batch.key == key) {
+                Some(position) => position,
+                None => {
+                    if batches.len() >= MAX_JOURNALED_BATCHES {
+                        batches.pop_front();
+                    }
+                    batches.push_back(JournaledBatch { key: key.to_string(), files: Vec::new() });
+                    batches.len() - 1
+                }
+            };
+            batches[position].files.push(CompletedFile { index, sha256, saved_path: saved_path.to_path_buf() });
+            serde_json::to_vec_pretty(&*batches)
+        };
+        self.save(snapshot).await;
+    }
+
+    /// Forgets a batch that went through, there's nothing left to resume.
+    async fn finish(&self, key: &str) {
+        let _saving = self.saving.lock().await;
+        let snapshot = {
+            let mut batches = self.batches.lock().unwrap();
+            let before = batches.len();
+            batches.retain(|batch| batch.key != key);
+            if batches.len() == before {
+                return;
+            }
+            serde_json::to_vec_pretty(&*batches)
+        };
+        self.save(snapshot).await;
+    }
+
+    /// Writes a snapshot of the batches off the async threads. Losing the journal only costs
+    /// resending files, so failing to write it is just logged.
+    async fn save(&self, snapshot: serde_json::Result<Vec<u8>>) {
+        let written = match snapshot {
+            Ok(bytes) => spawn_write_atomic(self.path.clone(), bytes).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = written {
+            warn!(error = %e, "Failed to save batch progress");
+        }
+    }
+}
+
+/// Moves a fully received `.part` file to its final name, picking a free name on collision.
+/// Files only ever get their real name once they're complete and verified, so nothing in
+/// the download directory without `.part` is a partial file.
+async fn finalize_part(part_path: &std::path::Path, target_path: &std::path::Path) -> std::io::Result<PathBuf> {
+    let (reserved, final_path) = create_unique_file(target_path).await?;
+    drop(reserved);
+    if let Err(e) = tokio::fs::rename(part_path, &final_path).await {
+        // Don't leave the empty placeholder looking like a received file
+        let _ = tokio::fs::remove_file(&final_path).await;
+        return Err(e);
+    }
+    let _ = tokio::fs::remove_file(sidecar_path(part_path)).await;
+    Ok(final_path)
+}
+
+/// Creates `path`, or `name (1).ext`, `name (2).ext`, ... if it already exists, the way
+/// browsers do. `create_new` makes the check and the creation a single atomic step.
+async fn create_unique_file(path: &std::path::Path) -> std::io::Result<(tokio::fs::File, PathBuf)> {
+    let mut candidate = path.to_path_buf();
+    let mut counter = 0;
+    loop {
+        match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&candidate).await {
+            Ok(file) => return Ok((file, candidate)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                counter += 1;
+                candidate = path.with_file_name(numbered_name(path, counter));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// `name (counter).ext` for the file name of `path`.
+fn numbered_name(path: &std::path::Path, counter: usize) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    format!("{} ({}){}", stem, counter, extension)
+}
+
+/// Turns a `/`-separated relative path from a peer into a path that is guaranteed to stay
+/// inside the download directory. Returns `None` for absolute paths, `..`, drive prefixes
+/// and anything else that isn't a plain file or folder name.
+fn safe_relative_path(path: &str) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for part in path.split('/') {
+        if part.contains('\\') {
+            return None;
+        }
+        let mut components = std::path::Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(std::path::Component::Normal(name)), None) => result.push(name),
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+/// Folder under the download directory `organize_by_peer` puts the per-sender folders in
+const PEER_FOLDERS_DIR: &str = "Kitsunet";
+/// Longest per-sender folder name, in characters
+const MAX_PEER_FOLDER_LEN: usize = 64;
+
+/// Where files from the peer with `sender_id` at `peer` go: a folder of its own under
+/// `download_dir` with `organize_by_peer`, `download_dir` itself otherwise.
+fn receive_dir(app: &AppHandle, download_dir: &std::path::Path, sender_id: &str, peer: &str) -> PathBuf {
+    let state = app.state::<AppState>();
+    let state = state.0.lock().unwrap();
+    if !state.settings.organize_by_peer {
+        return download_dir.to_path_buf();
+    }
+    let name = state.peer_name(sender_id, peer)
+        .or_else(|| state.aliases.get(sender_id).cloned());
+    let folder = name.as_deref()
+        .and_then(peer_folder_name)
+        .unwrap_or_else(|| peer_folder_name(peer).unwrap_or_default());
+    download_dir.join(PEER_FOLDERS_DIR).join(folder)
+}
+
+/// Makes a sender's name usable as a folder name on any OS: characters Windows rejects
+/// become `_`, trailing dots and spaces go, and reserved device names like `CON` get a `_`.
+/// `None` if nothing usable is left, `.` and `..` included.
+fn peer_folder_name(name: &str) -> Option<String> {
+    let replaced: String = name.chars()
+        .take(MAX_PEER_FOLDER_LEN)
+        .map(windows_safe_char)
+        .collect();
+    let trimmed = replaced.trim().trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() || trimmed.chars().all(|c| c == '.') {
+        return None;
+    }
+    Some(if is_reserved_name(trimmed) { format!("{}_", trimmed) } else { trimmed.to_string() })
+}
+
+/// `_` for the characters Windows rejects in file names
+fn windows_safe_char(c: char) -> char {
+    if c.is_control() || "<>:\"/\\|?*".contains(c) { '_' } else { c }
+}
+
+/// Whether Windows takes `name` for a device like `CON` or `com1.txt`, whatever the extension.
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().to_uppercase();
+    matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4 && (stem.starts_with("COM") || stem.starts_with("LPT")) && stem.ends_with(|c: char| c.is_ascii_digit()))
+}
+
+/// Longest name a received file gets, in bytes (UTF-16 units on Windows). File systems
+/// allow 255, this leaves room for ` (1)` and the `.part.json` sidecar suffix.
+const MAX_FILE_NAME_LEN: usize = 240;
+
+/// Makes each name of a `/`-separated path from a peer one this OS can create: too long
+/// names are cut short keeping their extension and, on Windows, characters it rejects
+/// become `_`, trailing dots and spaces go and device names get a `_`. Names that are
+/// fine here are left alone, whatever the sender's OS. The result still has to go
+/// through `safe_relative_path`.
+fn portable_path(path: &str) -> String {
+    path.split('/').map(portable_name).collect::<Vec<_>>().join("/")
+}
+
+fn portable_name(name: &str) -> String {
+    // `.` and `..` are for `safe_relative_path` to refuse, not to be turned into a name
+    if name == "." || name == ".." {
+        return name.to_string();
+    }
+    let mut name = if cfg!(windows) { name.chars().map(windows_safe_char).collect() } else { name.to_string() };
+    let len = |s: &str| if cfg!(windows) { s.encode_utf16().count() } else { s.len() };
+    if len(&name) > MAX_FILE_NAME_LEN {
+        let (stem, extension) = match name.rfind('.') {
+            Some(dot) if dot > 0 && len(&name[dot..]) <= MAX_FILE_NAME_LEN / 4 => name.split_at(dot),
+            _ => (name.as_str(), ""),
+        };
+        let mut stem = stem.to_string();
+        while len(&stem) + len(extension) > MAX_FILE_NAME_LEN {
+            stem.pop();
+        }
+        name = stem + extension;
+    }
+    if cfg!(windows) {
+        let trimmed = name.trim_end_matches(['.', ' ']);
+        name = match trimmed {
+            "" => "_".to_string(),
+            trimmed if is_reserved_name(trimmed) => format!("{}_", trimmed),
+            trimmed => trimmed.to_string(),
+        };
+    }
+    name
+}
+
+/// Per-send choices of `send_files` and `send_files_to_all`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SendOptions {
+    /// Send the batch as one stream, see `pack_into`
+    pack: bool,
+    /// Offer every file under its bare name, so folders arrive as their files side by side
+    flatten: bool,
+    /// Shown to the recipient as the sender of this batch instead of our username
+    sender_label: Option<String>,
+    /// Encrypt the files with this, see `send_files_encrypted`. Never leaves the queue.
+    #[serde(skip)]
+    password: Option<String>,
+}
+
+const MAX_SENDER_LABEL_LEN: usize = 64;
+
+/// `label` fit for showing as a sender: without control characters, trimmed and at most
+/// `MAX_SENDER_LABEL_LEN` characters. None if nothing is left.
+fn clean_sender_label(label: &str) -> Option<String> {
+    let label: String = label.chars().filter(|c| !c.is_control()).collect();
+    let label: String = label.trim().chars().take(MAX_SENDER_LABEL_LEN).collect();
+    let label = label.trim_end();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// Turns the `symlink_target` of the file at `link_path` into a relative path, if the link
+/// resolves to somewhere inside the download directory. The link itself may not exist yet
+/// there, so this only looks at the path.
+fn safe_symlink_target(link_path: &str, target: &str) -> Option<PathBuf> {
+    // Where the link sits, inside the download dir
+    let mut depth = safe_relative_path(link_path)?.components().count().checked_sub(1)?;
+    let mut result = PathBuf::new();
+    for part in target.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => depth = depth.checked_sub(1)?,
+            _ => {
+                safe_relative_path(part)?;
+                depth += 1;
+            }
+        }
+        result.push(part);
+    }
+    (!result.as_os_str().is_empty()).then_some(result)
+}
+
+/// Recreates a symlink sent as `file_meta` at `target_path`, picking a free name on
+/// collision like `create_unique_file`.
+async fn create_symlink(target_path: &std::path::Path, file_meta: &FileMetadata) -> std::io::Result<PathBuf> {
+    let target = file_meta.symlink_target.as_deref()
+        .and_then(|target| safe_symlink_target(&file_meta.path, target))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Unsafe symlink target"))?;
+    if let Some(parent) = target_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut candidate = target_path.to_path_buf();
+    let mut counter = 0;
+    loop {
+        match symlink(&target, &candidate).await {
+            Ok(()) => return Ok(candidate),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                counter += 1;
+                candidate = target_path.with_file_name(numbered_name(target_path, counter));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn symlink(target: &std::path::Path, link: &std::path::Path) -> std::io::Result<()> {
+    tokio::fs::symlink(target, link).await
+}
+
+// Creating symlinks takes admin rights or developer mode on Windows
+#[cfg(not(unix))]
+async fn symlink(_target: &std::path::Path, _link: &std::path::Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Symlinks aren't supported on this system"))
+}
+
+/// Gives a received file the permission bits it was sent with. The owner always keeps read
+/// and write access to what it received.
+#[cfg(unix)]
+async fn apply_mode(path: &std::path::Path, mode: Option<u32>) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        // No setuid, setgid or sticky bits from a peer
+        Some(mode) => tokio::fs::set_permissions(path, std::fs::Permissions::from_mode((mode & 0o777) | 0o600)).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn apply_mode(_path: &std::path::Path, _mode: Option<u32>) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Gives a received file the mark of the web, a `Zone.Identifier` stream saying it came
+/// from the internet zone, so SmartScreen and protected view check it before it's opened.
+#[cfg(windows)]
+async fn mark_as_downloaded(path: &std::path::Path) -> std::io::Result<()> {
+    let mut zone_identifier = path.as_os_str().to_owned();
+    zone_identifier.push(":Zone.Identifier");
+    tokio::fs::write(zone_identifier, "[ZoneTransfer]\r\nZoneId=3\r\n").await
+}
+
+// Nothing else checks a mark like that before opening a file
+#[cfg(not(windows))]
+async fn mark_as_downloaded(_path: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[tauri::command]
+async fn send_files(
+    app: AppHandle,
+    recipients: Vec<String>,
+    file_paths: Vec<String>,
+    options: Option<SendOptions>,
+    queue: tauri::State<'_, Arc<TransferQueue>>,
+) -> Result<(), Vec<RecipientError>> {
+    let mut options = options.unwrap_or_default();
+    options.sender_label = options.sender_label.as_deref().and_then(clean_sender_label);
+    let (own, recipients) = split_own_addresses(recipients).await;
+    let mut failures: Vec<RecipientError> = own.into_iter()
+        .map(|recipient| RecipientError { recipient, error: TransferError::CannotSendToSelf })
+        .collect();
+
+    let recent = app.state::<RecentBatches>();
+    for recipient in &recipients {
+        recent.push(RecentBatch {
+            recipient: recipient.clone(),
+            file_paths: file_paths.clone(),
+            options: options.clone(),
+            sent_at: unix_secs(),
+        });
+    }
+
+    // One queued transfer per recipient, `transfer_queue_task` decides how many run at once
+    let results = recipients.iter()
+        .map(|recipient| queue.enqueue(&app, recipient.clone(), file_paths.clone(), options.clone()))
+        .collect::<Vec<_>>();
+    let results = futures::future::join_all(results).await;
+
+    // A failed recipient doesn't affect the others, report them all together at the end
+    failures.extend(recipients.into_iter()
+        .zip(results)
+        .filter_map(|(recipient, result)| {
+            result.unwrap_or(Err(TransferError::Cancelled)).err().map(|error| RecipientError { recipient, error })
+        }));
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+/// Sends `file_paths` to `recipient` through the queue like `send_files`, but encrypted
+/// with `password`, which the recipient is asked for when accepting. See `FileEncryption`.
+/// Encrypted batches aren't kept for `resend_batch`.
+#[tauri::command]
+async fn send_files_encrypted(
+    app: AppHandle,
+    recipient: String,
+    file_paths: Vec<String>,
+    password: String,
+    queue: tauri::State<'_, Arc<TransferQueue>>,
+) -> Result<(), TransferError> {
+    if password.is_empty() {
+        return Err(TransferError::EmptyPassword);
+    }
+    let (own, recipients) = split_own_addresses(vec![recipient]).await;
+    let Some(recipient) = recipients.into_iter().next().filter(|_| own.is_empty()) else {
+        return Err(TransferError::CannotSendToSelf);
+    };
+    require_protocol_version(&app, &recipient, ENCRYPTION_VERSION)?;
+    let options = SendOptions { password: Some(password), ..SendOptions::default() };
+    queue.enqueue(&app, recipient, file_paths, options).await.unwrap_or(Err(TransferError::Cancelled))
+}
+
+/// Fails unless the peer at `recipient` speaks at least `version`, for requests an older
+/// one would misread. Unlike a `Capability` there's nothing to fall back to for those.
+fn require_protocol_version(app: &AppHandle, recipient: &str, version: u8) -> Result<(), TransferError> {
+    let peer_version = app.state::<AppState>().0.lock().unwrap().peer_protocol_version(recipient);
+    if peer_version < version {
+        return Err(TransferError::Protocol(format!(
+            "Recipient speaks protocol version {}, this needs {}",
+            peer_version, version,
+        )));
+    }
+    Ok(())
+}
+
+/// Batches `RecentBatches` keeps for `resend_batch`
+const MAX_RECENT_BATCHES: usize = 10;
+
+/// A batch `send_files` sent, or tried to.
+#[derive(Debug, Clone, Serialize)]
+struct RecentBatch {
+    recipient: String,
+    file_paths: Vec<String>,
+    options: SendOptions,
+    /// Unix time in seconds
+    sent_at: u64,
+}
+
+/// The last `MAX_RECENT_BATCHES` batches, newest first. Only kept while the app runs.
+#[derive(Default)]
+struct RecentBatches(Mutex<VecDeque<RecentBatch>>);
+
+impl RecentBatches {
+    fn push(&self, batch: RecentBatch) {
+        let mut batches = self.0.lock().unwrap();
+        // Sending the same again moves it to the front rather than filling the list with it
+        batches.retain(|old| old.recipient != batch.recipient || old.file_paths != batch.file_paths);
+        batches.push_front(batch);
+        batches.truncate(MAX_RECENT_BATCHES);
+    }
+}
+
+#[tauri::command]
+fn get_recent_batches(recent: tauri::State<RecentBatches>) -> Vec<RecentBatch> {
+    recent.0.lock().unwrap().iter().cloned().collect()
+}
+
+/// Sends batch `index` of `get_recent_batches` again. Files that are gone since are left
+/// out and reported as `NotFound`, along with whatever else failed.
+#[tauri::command]
+async fn resend_batch(
+    app: AppHandle,
+    index: usize,
+    queue: tauri::State<'_, Arc<TransferQueue>>,
+) -> Result<(), Vec<TransferError>> {
+    let batch = app.state::<RecentBatches>().0.lock().unwrap().get(index).cloned()
+        .ok_or_else(|| vec![TransferError::NotFound(format!("Recent batch {}", index))])?;
+    let (file_paths, missing): (Vec<String>, Vec<String>) = batch.file_paths.into_iter()
+        .partition(|path| std::path::Path::new(path).exists());
+    let mut errors: Vec<TransferError> = missing.into_iter().map(TransferError::NotFound).collect();
+    if !file_paths.is_empty() {
+        let result = send_files(app.clone(), vec![batch.recipient], file_paths, Some(batch.options.clone()), queue).await;
+        if let Err(failures) = result {
+            errors.extend(failures.into_iter().map(|failure| failure.error));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Sends the files to every peer currently in the peer list, see `BroadcastSummary`.
+#[tauri::command]
+async fn send_files_to_all(
+    app: AppHandle,
+    file_paths: Vec<String>,
+    options: Option<SendOptions>,
+    state: tauri::State<'_, AppState>,
+    queue: tauri::State<'_, Arc<TransferQueue>>,
+) -> Result<BroadcastSummary, TransferError> {
+    let mut options = options.unwrap_or_default();
+    options.sender_label = options.sender_label.as_deref().and_then(clean_sender_label);
+    let peers: Vec<Peer> = {
+        let state = state.0.lock().unwrap();
+        state.peers.iter()
+            .filter(|peer| !state.is_blocked(Some(&peer.id), &peer.address))
+            .cloned()
+            .collect()
+    };
+
+    // Discovered peers announced themselves moments ago, manual ones have to prove they're up
+    let probes = peers.iter().map(|peer| async move {
+        let reachable = !peer.manual || probe_peer(&peer.address, peer.transfer_port).await.is_ok();
+        (peer.address.clone(), reachable)
+    });
+    let (recipients, unreachable): (Vec<_>, Vec<_>) = futures::future::join_all(probes).await
+        .into_iter()
+        .partition(|(_, reachable)| *reachable);
+    let recipients = recipients.into_iter().map(|(address, _)| address).collect();
+    let (own, recipients) = split_own_addresses(recipients).await;
+    let mut summary = BroadcastSummary {
+        failed: own.into_iter()
+            .map(|recipient| RecipientError { recipient, error: TransferError::CannotSendToSelf })
+            .collect(),
+        unreachable: unreachable.into_iter().map(|(address, _)| address).collect(),
+        ..Default::default()
+    };
+    // Nobody to send to isn't an error, the summary still says what happened to everyone
+    if recipients.is_empty() {
+        return Ok(summary);
+    }
+
+    // `failed` starts out with our own addresses, so they're part of the total
+    let total = summary.failed.len() + recipients.len();
+    let mut pending: futures::stream::FuturesUnordered<_> = recipients.into_iter()
+        .map(|recipient: String| {
+            let result = queue.enqueue(&app, recipient.clone(), file_paths.clone(), options.clone());
+            async move { (recipient, result.await.unwrap_or(Err(TransferError::Cancelled))) }
+        })
+        .collect();
+    while let Some((recipient, result)) = futures::StreamExt::next(&mut pending).await {
+        match result {
+            Ok(()) => summary.sent.push(recipient),
+            Err(error) => summary.failed.push(RecipientError { recipient, error }),
+        }
+        app.emit("broadcast-progress", BroadcastProgress {
+            total,
+            sent: summary.sent.len(),
+            failed: summary.failed.len(),
+        }).unwrap();
+    }
+    Ok(summary)
+}
+
+/// Separates the recipients that resolve to this machine, `(own, others)`. A manual peer
+/// or a broadcast answered by ourselves would otherwise copy our files into our downloads.
+async fn split_own_addresses(recipients: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let local_ips = local_ips();
+    let mut own = Vec::new();
+    let mut others = Vec::new();
+    for recipient in recipients {
+        // Drop the scope id of link-local addresses, `IpAddr` doesn't parse it
+        let ips: Vec<IpAddr> = match recipient.split('%').next().unwrap_or_default().parse::<IpAddr>() {
+            Ok(ip) => vec![ip],
+            // Hostnames of manual peers; if they don't resolve the connection will fail anyway
+            Err(_) => tokio::net::lookup_host(peer_socket_addr(&recipient, DEFAULT_TRANSFER_PORT)).await
+                .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+                .unwrap_or_default(),
+        };
+        if ips.iter().any(|ip| ip.is_loopback() || ip.is_unspecified() || local_ips.contains(ip)) {
+            own.push(recipient);
+        } else {
+            others.push(recipient);
+        }
+    }
+    (own, others)
+}
+
+/// Result of `preflight_send`, what `send_files` would run into without sending anything.
+#[derive(Debug, Serialize)]
+struct PreflightReport {
+    /// Why the recipient can't be sent to, `None` if it accepted a connection
+    recipient_error: Option<TransferError>,
+    files: Vec<PreflightFile>,
+    /// Bytes in all the valid files
+    total_size: u64,
+}
+
+/// One of the paths passed to `preflight_send`, a directory counts with everything in it.
+#[derive(Debug, Serialize)]
+struct PreflightFile {
+    file_path: String,
+    file_count: usize,
+    size: u64,
+    /// Why this path would fail the send, `None` if it's fine
+    error: Option<TransferError>,
+}
+
+/// Checks a send before starting it: collects the files the same way `send_files` does and
+/// tries a connection to the recipient.
+#[tauri::command]
+async fn preflight_send(
+    recipient: String,
+    file_paths: Vec<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<PreflightReport, String> {
+    let port = state.0.lock().unwrap().peer_transfer_port(&recipient);
+    let (own, _) = split_own_addresses(vec![recipient.clone()]).await;
+    let recipient_error = if !own.is_empty() {
+        Some(TransferError::CannotSendToSelf)
+    } else {
+        probe_peer(&recipient, port).await.err().map(TransferError::PeerUnreachable)
+    };
+
+    let mut files = Vec::new();
+    for file_path in file_paths {
+        files.push(preflight_file(file_path).await);
+    }
+    let total_size = files.iter().map(|file| file.size).sum();
+    Ok(PreflightReport { recipient_error, files, total_size })
+}
+
+/// Collects one path of `preflight_send` and checks that every file in it can be read.
+async fn preflight_file(file_path: String) -> PreflightFile {
+    match collect_files_metadata(std::slice::from_ref(&file_path)).await {
+        // A readable file always gets a content id, see `content_id`. Symlinks carry no
+        // data and never get one.
+        Ok(batch) => match batch.iter().find(|(_, file_meta)| file_meta.content_id.is_none() && file_meta.symlink_target.is_none()) {
+            Some((path, _)) => PreflightFile {
+                file_path,
+                file_count: batch.len(),
+                size: 0,
+                error: Some(TransferError::Io(format!("Can't read {}", path.display()))),
+            },
+            None => PreflightFile {
+                file_path,
+                file_count: batch.len(),
+                size: batch.iter().map(|(_, file_meta)| file_meta.size).sum(),
+                error: None,
+            },
+        },
+        Err(error) => PreflightFile { file_path, file_count: 0, size: 0, error: Some(error) },
+    }
+}
+
+#[tauri::command]
+fn get_transfer_stats(stats: tauri::State<Arc<TransferStats>>) -> StatsSnapshot {
+    stats.snapshot()
+}
+
+/// Works out the combined throughput and emits `stats-updated` every `STATS_INTERVAL`.
+async fn stats_task(app: AppHandle) {
+    let stats = app.state::<Arc<TransferStats>>().inner().clone();
+    let mut shutdown = app.state::<Shutdown>().signal.subscribe();
+    let mut ticker = interval(STATS_INTERVAL);
+    let mut last_sample: Option<(Instant, u64)> = None;
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => break,
+            _ = ticker.tick() => {}
+        }
+        {
+            let mut inner = stats.inner.lock().unwrap();
+            let now = Instant::now();
+            if let Some((at, bytes)) = last_sample {
+                let elapsed = now.duration_since(at).as_secs_f64();
+                if elapsed > 0.0 {
+                    inner.bytes_per_second = (inner.bytes_transferred - bytes) as f64 / elapsed;
+                }
+            }
+            last_sample = Some((now, inner.bytes_transferred));
+        }
+        app.emit("stats-updated", stats.snapshot()).unwrap();
+    }
+}
+
+#[tauri::command]
+fn get_queue(queue: tauri::State<Arc<TransferQueue>>) -> Vec<QueueEntry> {
+    queue.snapshot()
+}
+
+/// Drops a transfer that hasn't started yet, running ones are stopped with `cancel_transfer`.
+#[tauri::command]
+fn cancel_queued(app: AppHandle, id: String, queue: tauri::State<Arc<TransferQueue>>) -> Result<(), String> {
+    if !queue.cancel_pending(&id) {
+        return Err("Transfer is no longer queued".to_string());
+    }
+    queue.emit_updated(&app);
+    Ok(())
+}
+
+/// Starts queued transfers as slots free up, at most `max_outgoing_transfers` at once.
+async fn transfer_queue_task(app: AppHandle) {
+    let queue = app.state::<Arc<TransferQueue>>().inner().clone();
+    let max_outgoing = app.state::<AppState>().0.lock().unwrap().settings.max_outgoing_transfers;
+    let semaphore = Arc::new(Semaphore::new(max_outgoing.max(1)));
+    let mut shutdown = app.state::<Shutdown>().signal.subscribe();
+
+    loop {
+        let Ok(permit) = semaphore.clone().acquire_owned().await else {
+            return;
+        };
+        let job = loop {
+            // Registered before looking, so a job queued in between isn't missed
+            let job_added = queue.job_added.notified();
+            if let Some(job) = queue.next_job() {
+                break job;
+            }
+            tokio::select! {
+                _ = shutdown.recv() => return,
+                _ = job_added => {}
+            }
+        };
+        queue.emit_updated(&app);
+
+        let app = app.clone();
+        let queue = queue.clone();
+        tauri::async_runtime::spawn(async move {
+            let result = run_transfer(&app, &job.id, &job.recipient, &job.file_paths, &job.options).await;
+            drop(permit);
+            let status = match &result {
+                Ok(()) => QueueStatus::Completed,
+                Err(TransferError::Cancelled) => QueueStatus::Cancelled,
+                Err(_) => QueueStatus::Failed,
+            };
+            queue.finish(&job.id, status);
+            queue.emit_updated(&app);
+            let _ = job.done.send(result);
+        });
+    }
+}
+
+/// Runs one transfer taken off the queue, reporting its start and failure to the UI.
+async fn run_transfer(app: &AppHandle, transfer_id: &str, recipient: &str, file_paths: &[String], options: &SendOptions) -> Result<(), TransferError> {
+    let registered = RegisteredTransfer::new(app, transfer_id, TransferDirection::Sent, recipient);
+    app.emit("transfer-started", TransferStarted {
+        transfer_id: transfer_id.to_string(),
+        recipient: recipient.to_string(),
+    }).unwrap();
+
+    let result = send_batch(app, transfer_id, recipient, file_paths, options, &registered.control).await;
+    drop(registered);
+    if let Err(reason) = &result {
+        app.emit("transfer-failed", FileTransferFailed {
+            recipient: Some(recipient.to_string()),
+            offer_id: None,
+            file_path: None,
+            file_name: None,
+            reason: reason.to_string(),
+            kind: None,
+        }).unwrap();
+    }
+    result
+}
+
+#[tracing::instrument(skip(app, file_paths, control))]
+async fn send_batch(
+    app: &AppHandle,
+    transfer_id: &str,
+    recipient: &str,
+    file_paths: &[String],
+    options: &SendOptions,
+    control: &TransferControl,
+) -> Result<(), TransferError> {
+    let mut batch = collect_files_metadata(file_paths).await?;
+    if options.flatten {
+        flatten_paths(&mut batch);
+    }
+    let encryption = match &options.password {
+        Some(password) => {
+            let salt = encode_hex(&random_bytes(ENCRYPTION_SALT_LEN));
+            let key = derive_encryption_key(password, &salt).await.map_err(TransferError::Io)?;
+            Some((salt, key))
+        }
+        None => None,
+    };
+    // Features the recipient didn't announce are left out rather than failing on its end
+    let (compress, parallel_streams, pack) = {
+        let state = app.state::<AppState>();
+        let state = state.0.lock().unwrap();
+        let settings = &state.settings;
+        let parallel_streams = if state.peer_supports(recipient, Capability::Ranges) {
+            settings.parallel_streams.min(MAX_PARALLEL_RANGES)
+        } else {
+            1
+        };
+        (
+            settings.compress_transfers && state.peer_supports(recipient, Capability::Compression),
+            parallel_streams,
+            options.pack && encryption.is_none() && state.peer_supports(recipient, Capability::Pack),
+        )
+    };
+    for (source_path, file_meta) in &mut batch {
+        if file_meta.symlink_target.is_some() {
+            continue;
+        }
+        // Sent as is, with no checksum or content id that would tell what's in it
+        if let Some((salt, _)) = &encryption {
+            file_meta.encryption = Some(FileEncryption {
+                salt: salt.clone(),
+                nonce: encode_hex(&random_bytes(ENCRYPTION_NONCE_LEN)),
+            });
+            file_meta.streamed = true;
+            file_meta.content_id = None;
+            continue;
+        }
+        // Up front so the recipient can tell whether it already has the file
+        file_meta.sha256 = Some(hash_file(source_path).await?);
+        // Packing compresses the whole batch already
+        if pack {
+            continue;
+        }
+        // Ranges go out raw, seeking into a compressed stream isn't possible
+        if parallel_streams > 1 && file_meta.size >= PARALLEL_MIN_FILE_SIZE {
+            file_meta.ranges = split_ranges(file_meta.size, parallel_streams);
+        } else if compress {
+            file_meta.compressed = is_compressible(&file_meta.path);
+        }
+    }
+    let _stats = app.state::<Arc<TransferStats>>().start(batch.iter().map(|(_, file_meta)| file_meta.size).sum());
+    let mut outcome = BatchOutcome::default();
+    let key = encryption.as_ref().map(|(_, key)| key);
+    let result = stream_batch(app, transfer_id, recipient, &batch, pack, options.sender_label.clone(), key, control, &mut outcome).await;
+    match &result {
+        Ok(()) => info!(files = batch.len(), "Batch sent"),
+        Err(reason) => warn!(done = outcome.done, files = batch.len(), reason = %reason, "Batch send failed"),
+    }
+
+    // Files are dealt with in order, so everything from `done` on didn't make it
+    let failed_status = match &result {
+        Err(TransferError::Rejected) => TransferStatus::Rejected,
+        _ => TransferStatus::Failed,
+    };
+    for (index, (_, file_meta)) in batch.iter().enumerate() {
+        let status = if outcome.skipped.contains(&index) {
+            TransferStatus::Skipped
+        } else if outcome.declined.contains(&index) {
+            TransferStatus::Rejected
+        } else if index < outcome.done {
+            TransferStatus::Completed
+        } else {
+            failed_status
+        };
+        record_transfer(app, TransferRecord::new(transfer_id, TransferDirection::Sent, recipient, file_meta, status));
+    }
+    result
+}
+
+/// What became of the files of a batch, filled in by `stream_batch` as it goes.
+#[derive(Debug, Default)]
+struct BatchOutcome {
+    /// Files fully sent, skipped or declined. They're dealt with in order, so these are the
+    /// first `done` files of the batch.
+    done: usize,
+    /// Files the recipient already had
+    skipped: Vec<usize>,
+    /// Files the recipient didn't accept
+    declined: Vec<usize>,
+}
+
+/// Offers `batch` to `recipient` and streams the files it accepts, all through `pack_into`
+/// if `pack` is set.
+#[allow(clippy::too_many_arguments)]
+async fn stream_batch(
+    app: &AppHandle,
+    transfer_id: &str,
+    recipient: &str,
+    batch: &[(PathBuf, FileMetadata)],
+    pack: bool,
+    sender_label: Option<String>,
+    key: Option<&chacha20poly1305::Key>,
+    control: &TransferControl,
+    outcome: &mut BatchOutcome,
+) -> Result<(), TransferError> {
+    let files_metadata: Vec<FileMetadata> = batch.iter().map(|(_, meta)| meta.clone()).collect();
+    let batch_progress = BatchTracker::new(transfer_id, &files_metadata);
+
+    let mut stream = connect_to_peer(app, recipient).await?;
+    // Canceling while the recipient decides drops the connection, which withdraws the offer
+    control.awaiting_answer.store(true, Ordering::SeqCst);
+    let answer = tokio::select! {
+        answer = offer_batch(&mut stream, files_metadata, Some(transfer_id.to_string()), pack, sender_label.clone(), offer_timeout(app)) => answer,
+        _ = control.cancel.cancelled() => Err(TransferError::Cancelled),
+    };
+    control.awaiting_answer.store(false, Ordering::SeqCst);
+    let OfferAnswer { accepted, offsets, already_there } = answer?;
+    let events = AppEvents { app, recipient: Some(recipient), offer_id: None, batch: &batch_progress };
+    let buffer_len = transfer_buffer_len(app);
+
+    // When packing, the files are written to a pipe that `pack_into` compresses from
+    let (pipe, packed) = tokio::io::duplex(PACK_PIPE_LEN);
+    let (output, packed_stream): (Box<dyn Connection + '_>, _) = if pack {
+        (Box::new(pipe), Some(&mut stream))
+    } else {
+        (Box::new(&mut stream), None)
+    };
+    let packing = async move {
+        match packed_stream {
+            Some(stream) => pack_into(packed, stream).await,
+            None => Ok(()),
+        }
+    };
+    let sending = async {
+        // Dropped when done, which ends the packed stream
+        let mut output = output;
+        for (index, ((source_path, file_meta), offset)) in batch.iter().zip(offsets).enumerate() {
+            let path_str = source_path.to_string_lossy();
+            let not_sent = if !accepted.contains(&index) {
+                Some(("transfer-declined", &mut outcome.declined))
+            } else if already_there.contains(&index) {
+                Some(("transfer-skipped", &mut outcome.skipped))
+            } else {
+                None
+            };
+            if let Some((event, indexes)) = not_sent {
+                app.emit(event, FileTransferComplete {
+                    recipient: Some(recipient.to_string()),
+                    offer_id: None,
+                    file_path: Some(path_str.to_string()),
+                    file_name: None,
+                    saved_path: None,
+                    original_name: None,
+                    timing: None,
+                }).unwrap();
+                indexes.push(index);
+                outcome.done += 1;
+                batch_progress.file_settled(app, file_meta);
+                continue;
+            }
+            let started = Instant::now();
+            if file_meta.symlink_target.is_some() {
+                // The recipient creates it from the metadata alone
+            } else if !file_meta.ranges.is_empty() {
+                if offset != 0 {
+                    return Err(TransferError::protocol("Recipient asked to resume a file sent in ranges"));
+                }
+                send_ranged_file(app, &mut output, transfer_id, recipient, index, source_path, file_meta, control, &events).await?;
+            } else if let (Some(_), Some(key)) = (&file_meta.encryption, key) {
+                if offset != 0 {
+                    return Err(TransferError::protocol("Recipient asked to resume an encrypted file"));
+                }
+                send_encrypted_file(&mut output, source_path, file_meta, key, control, &events).await?;
+            } else {
+                send_inline_file(&mut output, source_path, file_meta, offset, buffer_len, control, &events).await?;
+            }
+
+            app.emit("transfer-complete", FileTransferComplete {
+                recipient: Some(recipient.to_string()),
+                offer_id: None,
+                file_path: Some(path_str.to_string()),
+                file_name: None,
+                saved_path: None,
+                original_name: None,
+                timing: file_meta.symlink_target.is_none().then(|| TransferTiming::since(started, file_meta.size.saturating_sub(offset))),
+            }).unwrap();
+            outcome.done += 1;
+            batch_progress.file_settled(app, file_meta);
+        }
+        Ok(())
+    };
+    let (sent, packed) = tokio::join!(sending, packing);
+    sent?;
+    packed?;
+
+    // Sends the TLS close_notify so the receiver sees a clean end of stream
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// What the recipient answered to an accepted offer.
+#[derive(Debug, PartialEq, Eq)]
+struct OfferAnswer {
+    /// Indexes of the files it wants
+    accepted: Vec<usize>,
+    /// Where to resume each file of the batch from, one entry per file
+    offsets: Vec<u64>,
+    /// Indexes of the files it has already and won't be sent
+    already_there: Vec<usize>,
+}
+
+/// Offers `files` and waits up to `offer_timeout` for the recipient's answer.
+async fn offer_batch<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    files: Vec<FileMetadata>,
+    batch_id: Option<String>,
+    packed: bool,
+    sender_label: Option<String>,
+    offer_timeout: Duration,
+) -> Result<OfferAnswer, TransferError> {
+    let file_count = files.len();
+    write_json(stream, &TransferRequest::Files { files, batch_id, packed, sender_label }).await?;
+
+    let verdict = tokio::time::timeout(offer_timeout, stream.read_u8())
+        .await
+        .map_err(|_| TransferError::Timeout)??;
+    match verdict {
+        1 => {}
+        PAIRING_REJECTED => return Err(TransferError::PairingRequired),
+        AUTH_REJECTED => return Err(TransferError::AuthRequired),
+        STORAGE_UNAVAILABLE => return Err(TransferError::StorageUnavailable),
+        _ => return Err(TransferError::Rejected),
+    }
+    let accepted: Vec<usize> = read_json(stream).await.map_err(TransferError::protocol)?;
+    let offsets: Vec<u64> = read_json(stream).await.map_err(TransferError::protocol)?;
+    if offsets.len() != file_count {
+        return Err(TransferError::protocol("Recipient sent an invalid resume list"));
+    }
+    let already_there: Vec<usize> = read_json(stream).await.map_err(TransferError::protocol)?;
+    Ok(OfferAnswer { accepted, offsets, already_there })
+}
+
+/// Streams a file over the batch connection from `offset` on, then its trailer.
+async fn send_inline_file<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    source_path: &std::path::Path,
+    file_meta: &FileMetadata,
+    offset: u64,
+    buffer_len: usize,
+    control: &TransferControl,
+    events: &impl TransferEvents,
+) -> Result<(), TransferError> {
+    let path_str = source_path.to_string_lossy();
+    let mut file = tokio::fs::File::open(source_path).await?;
+    let file_size = file.metadata().await?.len();
+    if offset > file_size {
+        return Err(TransferError::protocol("Recipient asked to resume past the end of a file"));
+    }
+    let mut tracker = ProgressTracker::new(file_size, offset);
+    let mut hasher = Sha256::new();
+
+    let mut buffer = vec![0; buffer_len];
+    // Skip what the recipient already has, hashing it on the way since the trailer
+    // checksum covers the whole file
+    hash_prefix(&mut file, offset, &mut hasher, &mut buffer).await?;
+    loop {
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        let frame;
+        let out = if file_meta.compressed {
+            frame = compress_chunk(&buffer[..bytes_read])?;
+            &frame[..]
+        } else {
+            &buffer[..bytes_read]
+        };
+        // While paused the connection just goes quiet, the receiver waits for up to its
+        // `receive_timeout_secs`
+        tokio::select! {
+            _ = control.wait_while_paused() => {}
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        }
+        // Dropping the stream on cancellation closes the connection, which the receiver
+        // treats as a failed transfer
+        tokio::select! {
+            result = stream.write_all(out) => result?,
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        }
+        hasher.update(&buffer[..bytes_read]);
+        events.bytes_transferred(bytes_read as u64);
+
+        if tracker.advance(bytes_read as u64) {
+            events.progress(tracker.progress(Some(path_str.to_string()), None));
+        }
+    }
+    if tracker.never_reported() {
+        events.progress(tracker.progress(Some(path_str.to_string()), None));
+    }
+
+    // Send the file's metadata again as a trailer, now with the hash computed while streaming
+    let trailer = FileMetadata {
+        sha256: Some(format!("{:x}", hasher.finalize())),
+        ..file_meta.clone()
+    };
+    write_json(stream, &trailer).await?;
+    Ok(())
+}
+
+/// Largest chunk of a `streamed` file, a longer length prefix is a broken sender
+const MAX_STREAM_CHUNK: usize = 1024 * 1024;
+
+/// Sends everything `reader` gives until it ends as the `streamed` file `file_meta`,
+/// followed by the trailer with the checksum.
+async fn send_streamed_file<S: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    stream: &mut S,
+    reader: &mut R,
+    file_meta: &FileMetadata,
+    size: Option<u64>,
+    control: &TransferControl,
+    events: &impl TransferEvents,
+) -> Result<u64, TransferError> {
+    let mut tracker = ProgressTracker::streamed(size);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; MAX_STREAM_CHUNK];
+    loop {
+        let bytes_read = tokio::select! {
+            read = reader.read(&mut buffer) => read?,
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        };
+        tokio::select! {
+            _ = control.wait_while_paused() => {}
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        }
+        let chunk = &buffer[..bytes_read];
+        tokio::select! {
+            result = async {
+                stream.write_u32(bytes_read as u32).await?;
+                stream.write_all(chunk).await
+            } => result?,
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        }
+        // The empty chunk is the end
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(chunk);
+        events.bytes_transferred(bytes_read as u64);
+        if tracker.advance(bytes_read as u64) {
+            events.progress(tracker.progress(Some(file_meta.path.clone()), None));
+        }
+    }
+    let trailer = FileMetadata {
+        size: tracker.transferred,
+        sha256: Some(format!("{:x}", hasher.finalize())),
+        ..file_meta.clone()
+    };
+    write_json(stream, &trailer).await?;
+    Ok(tracker.transferred)
+}
+
+/// Sends the file at `source_path` encrypted with `key` as `file_meta.encryption` says,
+/// one encrypted chunk per `streamed` chunk. The trailer's checksum is of what went over
+/// the connection, one of the plaintext would tell anyone what's being sent.
+async fn send_encrypted_file<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    source_path: &std::path::Path,
+    file_meta: &FileMetadata,
+    key: &chacha20poly1305::Key,
+    control: &TransferControl,
+    events: &impl TransferEvents,
+) -> Result<(), TransferError> {
+    let nonce = file_meta.encryption.as_ref()
+        .and_then(encryption_nonce)
+        .ok_or_else(|| TransferError::protocol("Invalid encryption nonce"))?;
+    let mut encryptor = Some(EncryptorBE32::<ChaCha20Poly1305>::new(key, GenericArray::from_slice(&nonce)));
+    let mut file = tokio::fs::File::open(source_path).await?;
+    let mut tracker = ProgressTracker::streamed(Some(file_meta.size));
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; ENCRYPTED_CHUNK_LEN];
+    while let Some(mut current) = encryptor.take() {
+        let mut len = 0;
+        while len < buffer.len() {
+            match file.read(&mut buffer[len..]).await? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        // A short chunk is the last, which may be empty when the file fills its chunks exactly
+        let sealed = if len == ENCRYPTED_CHUNK_LEN {
+            let sealed = current.encrypt_next(&buffer[..len]);
+            encryptor = Some(current);
+            sealed
+        } else {
+            current.encrypt_last(&buffer[..len])
+        }.map_err(|_| TransferError::Io("Encrypting the file failed".to_string()))?;
+        tokio::select! {
+            _ = control.wait_while_paused() => {}
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        }
+        tokio::select! {
+            result = async {
+                stream.write_u32(sealed.len() as u32).await?;
+                stream.write_all(&sealed).await
+            } => result?,
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        }
+        hasher.update(&sealed);
+        events.bytes_transferred(len as u64);
+        if tracker.advance(len as u64) {
+            events.progress(tracker.progress(Some(file_meta.path.clone()), None));
+        }
+    }
+    stream.write_u32(0).await?;
+    if tracker.never_reported() {
+        events.progress(tracker.progress(Some(file_meta.path.clone()), None));
+    }
+    let trailer = FileMetadata {
+        size: tracker.transferred,
+        sha256: Some(format!("{:x}", hasher.finalize())),
+        ..file_meta.clone()
+    };
+    write_json(stream, &trailer).await?;
+    Ok(())
+}
+
+/// Splits `size` bytes into `count` contiguous ranges of about the same length.
+fn split_ranges(size: u64, count: usize) -> Vec<ByteRange> {
+    let len = size.div_ceil(count as u64);
+    (0..count as u64)
+        .map(|index| index * len)
+        .take_while(|offset| *offset < size)
+        .map(|offset| ByteRange { offset, len: len.min(size - offset) })
+        .collect()
+}
+
+/// Sends a file whose metadata declares `ranges`, each over its own connection, and then
+/// the trailer over the batch connection. The checksum is computed by reading the file
+/// once more alongside, since the ranges arrive out of order.
+#[allow(clippy::too_many_arguments)]
+async fn send_ranged_file<S: AsyncRead + AsyncWrite + Unpin>(
+    app: &AppHandle,
+    stream: &mut S,
+    batch_id: &str,
+    recipient: &str,
+    file_index: usize,
+    source_path: &std::path::Path,
+    file_meta: &FileMetadata,
+    control: &TransferControl,
+    events: &impl TransferEvents,
+) -> Result<(), TransferError> {
+    // The recipient says when it's ready to take the range connections
+    if stream.read_u8().await? != 1 {
+        return Err(TransferError::protocol("Recipient isn't ready for ranges"));
+    }
+
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let ranges = futures::future::try_join_all(file_meta.ranges.iter().enumerate().map(|(range_index, range)| {
+        let request = TransferRequest::Range { batch_id: batch_id.to_string(), file_index, range_index };
+        send_range(app, recipient, request, source_path, *range, control, progress_tx.clone())
+    }));
+    drop(progress_tx);
+    let checksum = async { hash_file(source_path).await.map_err(TransferError::from) };
+    let path_str = source_path.to_string_lossy();
+    let progress = async {
+        let mut tracker = ProgressTracker::new(file_meta.size, 0);
+        while let Some(bytes) = progress_rx.recv().await {
+            events.bytes_transferred(bytes);
+            if tracker.advance(bytes) {
+                events.progress(tracker.progress(Some(path_str.to_string()), None));
+            }
+        }
+        Ok(())
+    };
+    let (_, sha256, ()) = tokio::try_join!(ranges, checksum, progress)?;
+
+    write_json(stream, &FileMetadata { sha256: Some(sha256), ..file_meta.clone() }).await?;
+    Ok(())
+}
+
+/// Streams one byte range of a file over a connection of its own, reporting every chunk
+/// written to `progress`.
+async fn send_range(
+    app: &AppHandle,
+    recipient: &str,
+    request: TransferRequest,
+    source_path: &std::path::Path,
+    range: ByteRange,
+    control: &TransferControl,
+    progress: mpsc::UnboundedSender<u64>,
+) -> Result<(), TransferError> {
+    let mut stream = connect_to_peer(app, recipient).await?;
+    write_json(&mut stream, &request).await?;
+    match stream.read_u8().await? {
+        1 => {}
+        PAIRING_REJECTED => return Err(TransferError::PairingRequired),
+        AUTH_REJECTED => return Err(TransferError::AuthRequired),
+        _ => return Err(TransferError::protocol("Recipient refused a range connection")),
+    }
+
+    let mut file = tokio::fs::File::open(source_path).await?;
+    file.seek(std::io::SeekFrom::Start(range.offset)).await?;
+    let mut buffer = vec![0; transfer_buffer_len(app)];
+    let mut remaining = range.len;
+    while remaining > 0 {
+        let chunk = std::cmp::min(buffer.len() as u64, remaining) as usize;
+        file.read_exact(&mut buffer[..chunk]).await?;
+        tokio::select! {
+            _ = control.wait_while_paused() => {}
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        }
+        tokio::select! {
+            result = stream.write_all(&buffer[..chunk]) => result?,
+            _ = control.cancel.cancelled() => return Err(TransferError::Cancelled),
+        }
+        remaining -= chunk as u64;
+        let _ = progress.send(chunk as u64);
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Hex SHA-256 of the whole file at `path`.
+async fn hash_file(path: &std::path::Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let mut hasher = Sha256::new();
+    hash_prefix(&mut file, len, &mut hasher, &mut vec![0; 1024 * 1024]).await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn connect_to_peer(app: &AppHandle, recipient: &str) -> Result<tokio_rustls::client::TlsStream<TcpStream>, TransferError> {
+    let tcp = connect_with_retry(app, recipient).await?;
+    let mut stream = app.state::<tls::TlsState>().connect(recipient, tcp).await?;
+    let mut challenge = [0; AUTH_CHALLENGE_LEN];
+    tokio::time::timeout(receive_timeout(app), stream.read_exact(&mut challenge))
+        .await
+        .map_err(|_| TransferError::Timeout)??;
+    let hello = {
+        let state = app.state::<AppState>();
+        let state = state.0.lock().unwrap();
+        Hello {
+            sender_id: state.device_id.clone(),
+            pin: state.pairing.pins_for_peers.get(recipient).cloned(),
+            auth: state.settings.shared_secret.as_deref()
+                .map(|secret| shared_secret_response(secret, &challenge)),
+            username: Some(state.settings.username.clone()),
+        }
+    };
+    write_preamble(&mut stream, &hello).await?;
+    Ok(stream)
+}
+
+/// Opens the TCP connection to `recipient`, retrying with exponential backoff since a peer
+/// that just appeared may not be listening yet. Each attempt is reported as `transfer-connecting`.
+async fn connect_with_retry(app: &AppHandle, recipient: &str) -> Result<TcpStream, TransferError> {
+    let (target_addr, attempts) = {
+        let state = app.state::<AppState>();
+        let state = state.0.lock().unwrap();
+        (peer_socket_addr(recipient, state.peer_transfer_port(recipient)), state.settings.connect_attempts.max(1))
+    };
+    let mut delay = CONNECT_RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        app.emit("transfer-connecting", TransferConnecting {
+            recipient: recipient.to_string(),
+            attempt,
+            attempts,
+        }).unwrap();
+        match TcpStream::connect(&target_addr).await {
+            Ok(tcp) => {
+                enable_keepalive(app, &tcp);
+                return Ok(tcp);
+            }
+            Err(e) if attempt >= attempts => return Err(TransferError::PeerUnreachable(e.to_string())),
+            Err(e) => {
+                debug!(recipient, attempt, error = %e, "Connecting failed, retrying");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Turns on TCP keepalive for a transfer connection.
+///
+/// A paused transfer, or a receiver waiting for the user to answer an offer, leaves the
+/// connection silent for minutes, and stateful firewalls and NATs often forget idle
+/// connections after as little as 30 seconds. Keepalive probes carry no payload, so they
+/// keep the connection alive without a heartbeat in the protocol, and a peer that vanished
+/// is noticed after a few unanswered probes instead of never. Failing to set it only costs
+/// that, so errors are logged and otherwise ignored.
+fn enable_keepalive(app: &AppHandle, tcp: &TcpStream) {
+    let secs = app.state::<AppState>().0.lock().unwrap().settings.keepalive_interval_secs;
+    if secs == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(secs);
+    let keepalive = socket2::TcpKeepalive::new().with_time(interval);
+    // Not every platform lets the probe interval be set separately
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    let keepalive = keepalive.with_interval(interval);
+    if let Err(e) = socket2::SockRef::from(tcp).set_tcp_keepalive(&keepalive) {
+        warn!(error = %e, "Failed to enable TCP keepalive");
+    }
+}
+
+/// Why sending to a peer failed. Serialized as `{ "kind": "Rejected" }` or, for variants
+/// with details, `{ "kind": "Io", "message": "..." }`, so the UI can react per kind.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", content = "message")]
+enum TransferError {
+    /// No connection could be opened to the peer
+    PeerUnreachable(String),
+    /// The user on the other side declined
+    Rejected,
+    /// The recipient wants its pairing PIN first, see `set_pairing_pin`
+    PairingRequired,
+    /// The recipient has a `shared_secret` and ours is missing or different
+    AuthRequired,
+    /// The recipient's download directory is missing or not writable
+    StorageUnavailable,
+    /// Nobody answered the offer in time
+    Timeout,
+    Cancelled,
+    /// The recipient is this machine
+    CannotSendToSelf,
+    /// A file to send doesn't exist (anymore)
+    NotFound(String),
+    /// A file to send has a path that can't be offered
+    InvalidPath(String),
+    /// A URL to send isn't an http or https one
+    InvalidUrl(String),
+    /// `send_files_encrypted` was given an empty password
+    EmptyPassword,
+    /// The peer broke the protocol or speaks another version of it
+    Protocol(String),
+    Io(String),
+}
+
+impl TransferError {
+    fn protocol(e: impl std::fmt::Display) -> Self {
+        TransferError::Protocol(e.to_string())
+    }
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::PeerUnreachable(e) => write!(f, "Peer is unreachable: {}", e),
+            TransferError::Rejected => write!(f, "Rejected by recipient"),
+            TransferError::PairingRequired => write!(f, "Recipient requires pairing: PIN missing or incorrect"),
+            TransferError::AuthRequired => write!(f, "Recipient requires the shared secret: missing or incorrect"),
+            TransferError::StorageUnavailable => write!(f, "Recipient has no usable download directory"),
+            TransferError::Timeout => write!(f, "Offer timed out"),
+            TransferError::Cancelled => write!(f, "Transfer cancelled"),
+            TransferError::CannotSendToSelf => write!(f, "Cannot send files to this device"),
+            TransferError::NotFound(path) => write!(f, "File not found: {}", path),
+            TransferError::InvalidPath(path) => write!(f, "Invalid file path: {}", path),
+            TransferError::InvalidUrl(e) => write!(f, "Invalid URL: {}", e),
+            TransferError::EmptyPassword => write!(f, "The password is empty"),
+            TransferError::Protocol(e) => write!(f, "Protocol error: {}", e),
+            TransferError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for TransferError {
+    fn from(e: std::io::Error) -> Self {
+        TransferError::Io(e.to_string())
+    }
+}
+
+/// One entry of the error list `send_files` returns when some recipients failed
+#[derive(Debug, Serialize)]
+struct RecipientError {
+    recipient: String,
+    error: TransferError,
+}
+
+/// Outcome of `send_files_to_all`, one list per kind of result
+#[derive(Debug, Default, Serialize)]
+struct BroadcastSummary {
+    sent: Vec<String>,
+    failed: Vec<RecipientError>,
+    /// Manual peers that didn't answer, nothing was sent to them
+    unreachable: Vec<String>,
+}
+
+/// Emitted as `broadcast-progress` each time one recipient of `send_files_to_all` finishes
+#[derive(Clone, Serialize)]
+struct BroadcastProgress {
+    /// Reachable recipients, our own addresses included, `sent + failed` once all are done
+    total: usize,
+    sent: usize,
+    failed: usize,
+}
+
+#[tauri::command]
+async fn send_text(app: AppHandle, recipient: String, text: String) -> Result<(), TransferError> {
+    let mut stream = connect_to_peer(&app, &recipient).await?;
+    write_json(&mut stream, &TransferRequest::Text { text }).await?;
+
+    // Wait for delivery confirmation
+    let mut response = [0; 1];
+    tokio::time::timeout(offer_timeout(&app), stream.read_exact(&mut response))
+        .await
+        .map_err(|_| TransferError::Timeout)??;
+    match response[0] {
+        1 => {}
+        PAIRING_REJECTED => return Err(TransferError::PairingRequired),
+        AUTH_REJECTED => return Err(TransferError::AuthRequired),
+        _ => return Err(TransferError::Rejected),
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Has `recipient` download `url` itself instead of us relaying the file, saved as `name`
+/// if given, else as what the URL ends in. Done once the recipient accepted.
+#[tauri::command]
+async fn send_url(app: AppHandle, recipient: String, url: String, name: Option<String>) -> Result<(), TransferError> {
+    let url = validate_fetch_url(&url).map_err(TransferError::InvalidUrl)?;
+    require_protocol_version(&app, &recipient, URL_VERSION)?;
+    let mut stream = connect_to_peer(&app, &recipient).await?;
+    write_json(&mut stream, &TransferRequest::Url { url: url.to_string(), name }).await?;
+
+    let verdict = tokio::time::timeout(offer_timeout(&app), stream.read_u8())
+        .await
+        .map_err(|_| TransferError::Timeout)??;
+    match verdict {
+        1 => {}
+        PAIRING_REJECTED => return Err(TransferError::PairingRequired),
+        AUTH_REJECTED => return Err(TransferError::AuthRequired),
+        STORAGE_UNAVAILABLE => return Err(TransferError::StorageUnavailable),
+        _ => return Err(TransferError::Rejected),
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The metadata `send_stream` offers its data under.
+fn streamed_metadata(name: &str, size: Option<u64>) -> FileMetadata {
+    FileMetadata {
+        path: name.to_string(),
+        size: size.unwrap_or(0),
+        sha256: None,
+        content_id: None,
+        compressed: false,
+        ranges: Vec::new(),
+        mode: None,
+        symlink_target: None,
+        streamed: true,
+        encryption: None,
+    }
+}
+
+/// Sends what `reader` gives, until it ends, to `recipient` as one file called `name`, for
+/// data that isn't in a file like the output of a command. The recipient is shown `size`
+/// when the length is known up front.
+async fn send_stream<R: AsyncRead + Unpin>(
+    app: &AppHandle,
+    transfer_id: &str,
+    recipient: &str,
+    name: &str,
+    size: Option<u64>,
+    reader: &mut R,
+    control: &TransferControl,
+) -> Result<(), TransferError> {
+    require_protocol_version(app, recipient, STREAMED_VERSION)?;
+    let file_meta = streamed_metadata(name, size);
+    let batch_progress = BatchTracker::new(transfer_id, std::slice::from_ref(&file_meta));
+    let mut stream = connect_to_peer(app, recipient).await?;
+    control.awaiting_answer.store(true, Ordering::SeqCst);
+    let answer = tokio::select! {
+        answer = offer_batch(&mut stream, vec![file_meta.clone()], Some(transfer_id.to_string()), false, None, offer_timeout(app)) => answer,
+        _ = control.cancel.cancelled() => Err(TransferError::Cancelled),
+    };
+    control.awaiting_answer.store(false, Ordering::SeqCst);
+    if answer?.accepted.is_empty() {
+        return Err(TransferError::Rejected);
+    }
+
+    let events = AppEvents { app, recipient: Some(recipient), offer_id: None, batch: &batch_progress };
+    let started = Instant::now();
+    let sent = send_streamed_file(&mut stream, reader, &file_meta, size, control, &events).await?;
+    app.emit("transfer-complete", FileTransferComplete {
+        recipient: Some(recipient.to_string()),
+        offer_id: None,
+        file_path: Some(name.to_string()),
+        file_name: None,
+        saved_path: None,
+        original_name: None,
+        timing: Some(TransferTiming::since(started, sent)),
+    }).unwrap();
+    batch_progress.file_settled(app, &file_meta);
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Streams `source` to `recipient` with `send_stream`: `-` for our stdin, or a path, which
+/// may be a named pipe. `name` defaults to the source's file name.
+#[tauri::command]
+async fn send_pipe(
+    app: AppHandle,
+    recipient: String,
+    source: String,
+    name: Option<String>,
+    size: Option<u64>,
+) -> Result<(), TransferError> {
+    let name = match name.filter(|name| !name.trim().is_empty()) {
+        Some(name) => name,
+        None if source == "-" => "stdin".to_string(),
+        None => std::path::Path::new(&source).file_name()
+            .map_or_else(|| "stream".to_string(), |name| name.to_string_lossy().into_owned()),
+    };
+    let mut reader: Box<dyn AsyncRead + Unpin + Send> = if source == "-" {
+        Box::new(tokio::io::stdin())
+    } else {
+        Box::new(tokio::fs::File::open(&source).await.map_err(|_| TransferError::NotFound(source.clone()))?)
+    };
+
+    let transfer_id = Uuid::new_v4().to_string();
+    let registered = RegisteredTransfer::new(&app, &transfer_id, TransferDirection::Sent, &recipient);
+    app.emit("transfer-started", TransferStarted {
+        transfer_id: transfer_id.clone(),
+        recipient: recipient.clone(),
+    }).unwrap();
+    let result = send_stream(&app, &transfer_id, &recipient, &name, size, &mut reader, &registered.control).await;
+    drop(registered);
+
+    let status = match &result {
+        Ok(()) => TransferStatus::Completed,
+        Err(TransferError::Rejected) => TransferStatus::Rejected,
+        Err(_) => TransferStatus::Failed,
+    };
+    record_transfer(&app, TransferRecord::new(&transfer_id, TransferDirection::Sent, &recipient, &streamed_metadata(&name, size), status));
+    if let Err(reason) = &result {
+        app.emit("transfer-failed", FileTransferFailed {
+            recipient: Some(recipient.clone()),
+            offer_id: None,
+            file_path: Some(name),
+            file_name: None,
+            reason: reason.to_string(),
+            kind: None,
+        }).unwrap();
+    }
+    result
+}
+
+/// Bytes `estimate_transfer` sends to measure throughput
+const CALIBRATION_LEN: u64 = 4 * 1024 * 1024;
+/// Most a calibration may ask the receiver to take
+const MAX_CALIBRATION_LEN: u64 = 16 * 1024 * 1024;
+/// A burst done quicker than this is mostly latency and buffering, not throughput
+const CALIBRATION_MIN_DURATION: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EstimateConfidence {
+    /// Measured with a burst long enough to be representative
+    High,
+    /// Measured, but the burst was over too quickly to trust the rate much
+    Low,
+    /// The recipient can't be calibrated against, only the connect time is known
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TransferEstimate {
+    /// How long sending `total_bytes` should take, `None` without a measured rate
+    estimate_ms: Option<u64>,
+    bytes_per_second: Option<u64>,
+    /// Time to connect and finish the TLS handshake, a few round trips
+    connect_ms: u64,
+    confidence: EstimateConfidence,
+}
+
+/// Estimates how long sending `total_bytes` to `recipient` would take by timing a short
+/// burst to it. Compression and the per-file overhead of many small files aren't counted.
+#[tauri::command]
+async fn estimate_transfer(app: AppHandle, recipient: String, total_bytes: u64) -> Result<TransferEstimate, TransferError> {
+    let (calibrate, port) = {
+        let state = app.state::<AppState>();
+        let state = state.0.lock().unwrap();
+        (state.peer_supports(&recipient, Capability::Calibrate), state.peer_transfer_port(&recipient))
+    };
+    let connecting = Instant::now();
+    if !calibrate {
+        // A plain TCP connect is enough to time, a full one would only be dropped unused
+        probe_peer(&recipient, port).await.map_err(TransferError::PeerUnreachable)?;
+        let connect_ms = connecting.elapsed().as_millis() as u64;
+        return Ok(TransferEstimate { estimate_ms: None, bytes_per_second: None, connect_ms, confidence: EstimateConfidence::Unknown });
+    }
+    let mut stream = connect_to_peer(&app, &recipient).await?;
+    let connect_ms = connecting.elapsed().as_millis() as u64;
+
+    write_json(&mut stream, &TransferRequest::Calibrate { len: CALIBRATION_LEN }).await?;
+    // The receiver would drop the connection after refusing, failing the burst before
+    // we ever read why
+    let verdict = tokio::time::timeout(offer_timeout(&app), stream.read_u8())
+        .await
+        .map_err(|_| TransferError::Timeout)??;
+    match verdict {
+        1 => {}
+        PAIRING_REJECTED => return Err(TransferError::PairingRequired),
+        AUTH_REJECTED => return Err(TransferError::AuthRequired),
+        _ => return Err(TransferError::Rejected),
+    }
+    let sending = Instant::now();
+    let chunk = vec![0; 64 * 1024];
+    let mut remaining = CALIBRATION_LEN;
+    while remaining > 0 {
+        let len = std::cmp::min(chunk.len() as u64, remaining) as usize;
+        stream.write_all(&chunk[..len]).await?;
+        remaining -= len as u64;
+    }
+    stream.flush().await?;
+    let confirmed = tokio::time::timeout(offer_timeout(&app), stream.read_u8())
+        .await
+        .map_err(|_| TransferError::Timeout)??;
+    if confirmed != 1 {
+        return Err(TransferError::protocol("Recipient didn't confirm the calibration burst"));
+    }
+    let elapsed = sending.elapsed();
+    stream.shutdown().await?;
+
+    let bytes_per_second = CALIBRATION_LEN as f64 / elapsed.as_secs_f64().max(0.001);
+    Ok(TransferEstimate {
+        estimate_ms: Some((total_bytes as f64 / bytes_per_second * 1000.0) as u64 + connect_ms),
+        bytes_per_second: Some(bytes_per_second as u64),
+        connect_ms,
+        confidence: if elapsed >= CALIBRATION_MIN_DURATION { EstimateConfidence::High } else { EstimateConfidence::Low },
+    })
+}
+
+#[tauri::command]
+fn cancel_transfer(transfer_id: String, transfers: tauri::State<ActiveTransfers>) {
+    if let Some(transfer) = transfers.lock().unwrap().get(&transfer_id) {
+        transfer.control.cancel.cancel();
+    }
+}
+
+/// A transfer in progress, as `get_active_transfers` lists it
+#[derive(Serialize)]
+struct ActiveTransferInfo {
+    id: String,
+    direction: TransferDirection,
+    peer: String,
+    paused: bool,
+    progress: Option<BatchProgress>,
+}
+
+/// Every send and receive in progress, in both directions. Queued sends that haven't
+/// started are in `get_queue`.
+#[tauri::command]
+fn get_active_transfers(transfers: tauri::State<ActiveTransfers>) -> Vec<ActiveTransferInfo> {
+    let mut active: Vec<ActiveTransferInfo> = transfers.lock().unwrap().iter()
+        .map(|(id, transfer)| ActiveTransferInfo {
+            id: id.clone(),
+            direction: transfer.direction,
+            peer: transfer.peer.clone(),
+            paused: *transfer.control.state.lock().unwrap() == TransferState::Paused,
+            progress: transfer.progress.clone(),
+        })
+        .collect();
+    active.sort_by(|a, b| a.id.cmp(&b.id));
+    active
+}
+
+/// Cancels every transfer in progress, e.g. before quitting, and returns how many there
+/// were. Queued sends are left alone, see `cancel_queued`.
+#[tauri::command]
+fn cancel_all_transfers(transfers: tauri::State<ActiveTransfers>) -> usize {
+    let transfers = transfers.lock().unwrap();
+    info!(transfers = transfers.len(), "Cancelling all transfers");
+    for transfer in transfers.values() {
+        transfer.control.cancel.cancel();
+    }
+    transfers.len()
+}
+
+/// Withdraws an offer the recipient hasn't answered yet. Fails once it has, the transfer
+/// is then canceled with `cancel_transfer`.
+#[tauri::command]
+fn cancel_pending_offer(transfer_id: String, transfers: tauri::State<ActiveTransfers>) -> Result<(), String> {
+    let transfers = transfers.lock().unwrap();
+    let control = &transfers.get(&transfer_id).ok_or_else(|| format!("No transfer {}", transfer_id))?.control;
+    if !control.awaiting_answer.load(Ordering::SeqCst) {
+        return Err("The offer was already answered".to_string());
+    }
+    info!(transfer_id = %transfer_id, "Withdrawing offer");
+    control.cancel.cancel();
+    Ok(())
+}
+
+#[tauri::command]
+fn pause_transfer(app: AppHandle, transfer_id: String, transfers: tauri::State<ActiveTransfers>) {
+    set_transfer_state(&app, transfer_id, &transfers, TransferState::Paused);
+}
+
+#[tauri::command]
+fn resume_transfer(app: AppHandle, transfer_id: String, transfers: tauri::State<ActiveTransfers>) {
+    set_transfer_state(&app, transfer_id, &transfers, TransferState::Running);
+}
+
+fn set_transfer_state(app: &AppHandle, transfer_id: String, transfers: &ActiveTransfers, state: TransferState) {
+    let changed = transfers.lock().unwrap()
+        .get(&transfer_id)
+        .filter(|transfer| transfer.direction == TransferDirection::Sent)
+        .is_some_and(|transfer| transfer.control.set_state(state));
+    if changed {
+        info!(transfer_id = %transfer_id, ?state, "Transfer state changed");
+        let event = match state {
+            TransferState::Paused => "transfer-paused",
+            TransferState::Running => "transfer-resumed",
+        };
+        app.emit(event, TransferStateChanged { transfer_id }).unwrap();
+    }
+}
+
+#[tauri::command]
+fn get_own_fingerprint(tls: tauri::State<tls::TlsState>) -> String {
+    tls.own_fingerprint().to_string()
+}
+
+/// The certificate fingerprint pinned for `address`, if we've sent to it before.
+#[tauri::command]
+fn get_peer_fingerprint(address: String, tls: tauri::State<tls::TlsState>) -> Option<String> {
+    tls.peer_fingerprint(&address)
+}
+
+#[tauri::command]
+fn forget_peer_fingerprint(address: String, tls: tauri::State<tls::TlsState>) -> Result<(), String> {
+    tls.forget_peer(&address).map_err(|e| e.to_string())
+}
+
+/// Accepts an offer, or some of its files. Encrypted files are only accepted with their
+/// `password`.
+#[tauri::command]
+async fn accept_file_offer(
+    offer_id: String,
+    accepted_indices: Option<Vec<usize>>,
+    password: Option<String>,
+    offers: tauri::State<'_, FileOffers>,
+) -> Result<(), String> {
+    let password = password.filter(|password| !password.is_empty());
+    if let Some(sender) = offers.lock().unwrap().remove(&offer_id) {
+        sender.send(OfferDecision::Accepted { indexes: accepted_indices, password }).map_err(|_| "Failed to send acceptance".to_string())?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn reject_file_offer(offer_id: String, offers: tauri::State<'_, FileOffers>) -> Result<(), String> {
+    if let Some(sender) = offers.lock().unwrap().remove(&offer_id) {
+        sender.send(OfferDecision::Rejected).map_err(|_| "Failed to send rejection".to_string())?;
+    }
+    Ok(())
+}
+
+/// Opens the file manager with `path` selected, or at least its folder.
+#[tauri::command]
+async fn show_in_folder(path: String) -> Result<(), String> {
+    let path = PathBuf::from(path);
+    // Launchers given a missing path tend to open the home folder instead of failing
+    if !path.exists() {
+        return Err(format!("File not found: {}", path.display()));
+    }
+    reveal_in_file_manager(&path).await.map_err(|e| format!("Failed to open the file manager: {}", e))
+}
+
+/// Opens a received file in its default application. Only files under the download
+/// directory, per-peer folders included, are opened, so the frontend can't be used to
+/// launch anything else.
+#[tauri::command]
+async fn open_file(app: AppHandle, path: String) -> Result<(), String> {
+    let path = std::fs::canonicalize(&path).map_err(|e| format!("File not found: {}: {}", path, e))?;
+    let allowed = resolve_download_dir(&app).ok()
+        .and_then(|root| std::fs::canonicalize(root).ok())
+        .is_some_and(|root| path.starts_with(root));
+    if !allowed {
+        return Err(format!("Not a received file: {}", path.display()));
+    }
+    open_with_default_app(&path).await.map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+}
+
+#[cfg(target_os = "windows")]
+async fn open_with_default_app(path: &std::path::Path) -> std::io::Result<()> {
+    // Goes through Explorer rather than `cmd /C start`, which would parse `&` and the like
+    // in the file name
+    tokio::process::Command::new("explorer").arg(path).spawn()?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+async fn open_with_default_app(path: &std::path::Path) -> std::io::Result<()> {
+    tokio::process::Command::new("xdg-open").arg(path).spawn()?;
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+async fn open_with_default_app(path: &std::path::Path) -> std::io::Result<()> {
+    let status = tokio::process::Command::new("open").arg(path).status().await?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("open exited with {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+async fn open_with_default_app(_path: &std::path::Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Not supported on this platform"))
+}
+
+#[cfg(target_os = "windows")]
+async fn reveal_in_file_manager(path: &std::path::Path) -> std::io::Result<()> {
+    // Explorer's exit code says nothing useful, it's non-zero even when it worked
+    tokio::process::Command::new("explorer").arg("/select,").arg(path).spawn()?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+async fn reveal_in_file_manager(path: &std::path::Path) -> std::io::Result<()> {
+    // The FileManager1 interface selects the file, but not every desktop provides it
+    if let Ok(uri) = url::Url::from_file_path(path) {
+        let shown = tokio::process::Command::new("dbus-send")
+            .args([
+                "--session",
+                "--print-reply",
+                "--reply-timeout=2000",
+                "--dest=org.freedesktop.FileManager1",
+                "--type=method_call",
+                "/org/freedesktop/FileManager1",
+                "org.freedesktop.FileManager1.ShowItems",
+            ])
+            // Commas separate dbus-send array items
+            .arg(format!("array:string:{}", uri.as_str().replace(',', "%2C")))
+            .arg("string:")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await
+            .is_ok_and(|status| status.success());
+        if shown {
+            return Ok(());
+        }
+    }
+    let parent = path.parent().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "File has no parent folder"))?;
+    tokio::process::Command::new("xdg-open").arg(parent).spawn()?;
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+async fn reveal_in_file_manager(path: &std::path::Path) -> std::io::Result<()> {
+    let status = tokio::process::Command::new("open").arg("-R").arg(path).status().await?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("open exited with {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+async fn reveal_in_file_manager(_path: &std::path::Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Not supported on this platform"))
+}
+
+#[derive(Clone, serde::Serialize, Deserialize, Debug)]
+struct FileMetadata {
+    /// Path relative to the batch root, always `/`-separated (e.g. `docs/readme.txt`)
+    path: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// See `content_id`, lets the receiver tell whether a `.part` file belongs to this file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_id: Option<String>,
+    /// Sent as zstd frames rather than raw bytes. `size` and progress stay in
+    /// uncompressed bytes either way.
+    #[serde(default)]
+    compressed: bool,
+    /// Contiguous byte ranges covering the file, each sent over its own connection instead
+    /// of the batch connection. Empty for files sent inline.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ranges: Vec<ByteRange>,
+    /// Unix permission bits of the file, `None` when sent from elsewhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    /// Set for a symlink, which is recreated pointing here instead of sending any data.
+    /// Always `/`-separated and relative, see `safe_symlink_target`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symlink_target: Option<String>,
+    /// Sent by `send_stream` from a source of unknown length, as chunks each prefixed with
+    /// its `u32` length until an empty one. `size` is just what the sender expects, 0 if
+    /// it has no idea.
+    #[serde(default)]
+    streamed: bool,
+    /// Set for a file sent with `send_files_encrypted`, which is always `streamed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<FileEncryption>,
+}
+
+/// How a file from `send_files_encrypted` is encrypted: ChaCha20-Poly1305 in the STREAM
+/// construction of `ENCRYPTED_CHUNK_LEN` byte chunks, keyed with Argon2id of the password
+/// and `salt`. Only someone with the password can read it, whoever the TLS connection
+/// turns out to be with.
+#[derive(Clone, serde::Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct FileEncryption {
+    /// Hex Argon2id salt, the same for every file of a batch
+    salt: String,
+    /// Hex STREAM nonce prefix, fresh for every file
+    nonce: String,
+}
+
+const ENCRYPTION_SALT_LEN: usize = 16;
+/// What ChaCha20-Poly1305's 12 byte nonce leaves to the prefix next to STREAM's counter
+const ENCRYPTION_NONCE_LEN: usize = 7;
+/// Plaintext bytes per encrypted chunk. Every chunk but the last one has this many, which
+/// is how the receiver tells the last one apart.
+const ENCRYPTED_CHUNK_LEN: usize = 64 * 1024;
+/// Poly1305 tag each encrypted chunk grows by
+const ENCRYPTION_TAG_LEN: usize = 16;
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Argon2id of `password` with the hex `salt`. Slow on purpose, so it runs off the async
+/// threads.
+async fn derive_encryption_key(password: &str, salt: &str) -> Result<chacha20poly1305::Key, String> {
+    let salt = decode_hex(salt).ok_or("Invalid salt")?;
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let mut key = chacha20poly1305::Key::default();
+        argon2::Argon2::default()
+            .hash_password_into(password.as_bytes(), &salt, &mut key)
+            .map_err(|e| e.to_string())?;
+        Ok(key)
+    }).await.map_err(|e| e.to_string())?
+}
+
+/// The STREAM nonce prefix of `encryption`, checked for length.
+fn encryption_nonce(encryption: &FileEncryption) -> Option<Vec<u8>> {
+    decode_hex(&encryption.nonce).filter(|nonce| nonce.len() == ENCRYPTION_NONCE_LEN)
+}
+
+#[derive(Clone, Copy, serde::Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct ByteRange {
+    offset: u64,
+    len: u64,
+}
+
+/// First frame on every transfer connection, says who is connecting.
+#[derive(Serialize, Deserialize, Debug)]
+struct Hello {
+    sender_id: String,
+    /// Our PIN as entered on the sender, only sent until the pairing succeeded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pin: Option<String>,
+    /// `shared_secret_response` to the receiver's challenge, when we have a `shared_secret`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth: Option<String>,
+    /// Our username, so receivers that haven't discovered us yet can show it. Missing
+    /// from older versions.
+    #[serde(default)]
+    username: Option<String>,
+}
+
+/// Hex HMAC-SHA256 of `challenge` keyed with the shared secret. The challenge is fresh
+/// random bytes per connection, so a recorded answer is no use to anyone replaying it.
+fn shared_secret_response(secret: &str, challenge: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(challenge);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A fresh challenge for `shared_secret_response`
+fn auth_challenge() -> [u8; AUTH_CHALLENGE_LEN] {
+    let mut challenge = [0; AUTH_CHALLENGE_LEN];
+    challenge[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+    challenge[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+    challenge
+}
+
+/// Whether `auth` proves the sender knows `secret`, compared in constant time.
+fn verify_shared_secret(secret: &str, challenge: &[u8], auth: Option<&str>) -> bool {
+    let Some(auth) = auth.and_then(decode_hex) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(challenge);
+    mac.verify_slice(&auth).is_ok()
+}
+
+/// Follows the `Hello`, telling the receiver what the connection is for.
+#[derive(Serialize, Deserialize, Debug)]
+enum TransferRequest {
+    /// A batch of files, streamed one after another once the recipient accepts.
+    /// `batch_id` is what range connections refer to, required if any file has ranges.
+    Files {
+        files: Vec<FileMetadata>,
+        #[serde(default)]
+        batch_id: Option<String>,
+        /// Everything after the answer goes through `pack_into`. Files in a packed batch are
+        /// neither `compressed` nor split into `ranges`.
+        #[serde(default)]
+        packed: bool,
+        /// Who the batch is from according to the sender, see `SendOptions::sender_label`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sender_label: Option<String>,
+    },
+    /// A text snippet, delivered without a prompt
+    Text { text: String },
+    /// One range of a file from an accepted batch. Sent on a connection of its own once the
+    /// receiver signalled on the batch connection that it's ready for that file's ranges.
+    Range {
+        batch_id: String,
+        file_index: usize,
+        range_index: usize,
+    },
+    /// `len` bytes to throw away. Answered once before they're sent, so a refusal arrives
+    /// before the burst, and again once they're all in so the sender can time them.
+    Calibrate { len: u64 },
+    /// A file the receiver downloads from `url` itself once accepted, saved as `name` if
+    /// given. Only http and https, see `validate_fetch_url`.
+    Url {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+}
+
+/// Files whose ranges are being received, keyed by batch id and file index.
+type RangeSlots = Arc<Mutex<HashMap<(String, usize), RangeSlot>>>;
+
+/// Where the range connections of one file write to, and who may open them.
+struct RangeSlot {
+    sender_id: String,
+    sender_ip: IpAddr,
+    part_path: PathBuf,
+    ranges: Vec<ByteRange>,
+    claimed: Vec<bool>,
+    events: mpsc::UnboundedSender<RangeEvent>,
+}
+
+/// What range connections report back to the batch connection's task.
+enum RangeEvent {
+    Received(u64),
+    /// The error keeps its kind, so a full drive is still recognized as such
+    Done(Result<(), std::io::Error>),
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct FileTransferProgress {
+    /// Set on the sending side, which may be sending the same files to several peers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipient: Option<String>,
+    /// Set on the receiving side, which may be receiving several offers from one peer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offer_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    progress: f64,
+    bytes_transferred: u64,
+    total_bytes: u64,
+    bytes_per_second: f64,
+}
+
+/// Minimum time between two `transfer-progress` events for the same file
+const PROGRESS_EMIT_INTERVAL: Duration = Duration::from_millis(100);
+/// Window over which `bytes_per_second` is measured
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Tracks how far a single file has got, measures the current throughput over a rolling
+/// window and throttles how often progress is reported.
+struct ProgressTracker {
+    total_bytes: u64,
+    transferred: u64,
+    samples: std::collections::VecDeque<(Instant, u64)>,
+    last_emit: Option<Instant>,
+    /// Streaming with no known end, `total_bytes` is 0 and progress stays at 0% until done
+    open_ended: bool,
+}
+
+impl ProgressTracker {
+    /// `already_transferred` is non-zero when resuming a file.
+    fn new(total_bytes: u64, already_transferred: u64) -> Self {
+        let mut samples = std::collections::VecDeque::new();
+        samples.push_back((Instant::now(), already_transferred));
+        Self { total_bytes, transferred: already_transferred, samples, last_emit: None, open_ended: false }
+    }
+
+    /// For a `streamed` file, `size` is `None` if the sender doesn't know how much it sends.
+    fn streamed(size: Option<u64>) -> Self {
+        Self { open_ended: size.is_none(), ..Self::new(size.unwrap_or(0), 0) }
+    }
+
+    /// Records `bytes` more bytes and returns whether a progress event is due.
+    fn advance(&mut self, bytes: u64) -> bool {
+        let now = Instant::now();
+        self.transferred += bytes;
+        self.samples.push_back((now, self.transferred));
+        // Keep the newest sample that is at least a full window old as the baseline
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= THROUGHPUT_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let due = (!self.open_ended && self.transferred >= self.total_bytes)
+            || self.last_emit.is_none_or(|last| now.duration_since(last) >= PROGRESS_EMIT_INTERVAL);
+        if due {
+            self.last_emit = Some(now);
+        }
+        due
+    }
+
+    /// Whether no progress was reported, as for an empty file, which still gets its 100%.
+    fn never_reported(&self) -> bool {
+        self.last_emit.is_none()
+    }
+
+    fn bytes_per_second(&self) -> f64 {
+        let (Some(&(start, start_bytes)), Some(&(end, end_bytes))) = (self.samples.front(), self.samples.back()) else {
+            return 0.0;
+        };
+        let elapsed = end.duration_since(start).as_secs_f64();
+        if elapsed > 0.0 {
+            (end_bytes - start_bytes) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn progress(&self, file_path: Option<String>, file_name: Option<String>) -> FileTransferProgress {
+        debug!(
+            file = file_path.as_deref().or(file_name.as_deref()).unwrap_or_default(),
+            bytes = self.transferred,
+            total = self.total_bytes,
+            bytes_per_second = self.bytes_per_second(),
+            "Transfer progress",
+        );
+        FileTransferProgress {
+            recipient: None,
+            offer_id: None,
+            file_path,
+            file_name,
+            // An empty file is done as soon as it starts, a stream may send more than expected
+            progress: if self.open_ended {
+                0.0
+            } else if self.total_bytes == 0 {
+                100.0
+            } else {
+                (self.transferred as f64 / self.total_bytes as f64 * 100.0).min(100.0)
+            },
+            bytes_transferred: self.transferred,
+            total_bytes: self.total_bytes,
+            bytes_per_second: self.bytes_per_second(),
+        }
+    }
+}
+
+/// Emitted as `preparing` while the receiver gets an accepted batch ready, before any file
+/// data arrives. Large batches spend a while here.
+#[derive(Clone, Serialize)]
+struct Preparing {
+    offer_id: String,
+    stage: PreparingStage,
+    done: usize,
+    total: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PreparingStage {
+    /// Looking for duplicates and `.part` files to resume
+    CheckingFiles,
+    CreatingFolders,
+}
+
+/// Least time between two `preparing` events of a batch
+const PREPARING_EMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Emits `preparing` when a stage starts and ends, and every `PREPARING_EMIT_INTERVAL` in between.
+struct PreparingEvents<'a> {
+    app: &'a AppHandle,
+    offer_id: &'a str,
+    last_emitted: Option<Instant>,
+}
+
+impl PreparingEvents<'_> {
+    fn report(&mut self, stage: PreparingStage, done: usize, total: usize) {
+        if done == 0 || self.last_emitted.is_none_or(|last| last.elapsed() >= PREPARING_EMIT_INTERVAL) {
+            self.emit(stage, done, total);
+        }
+    }
+
+    fn finish(&mut self, stage: PreparingStage, total: usize) {
+        self.emit(stage, total, total);
+    }
+
+    fn emit(&mut self, stage: PreparingStage, done: usize, total: usize) {
+        self.last_emitted = Some(Instant::now());
+        self.app.emit("preparing", Preparing { offer_id: self.offer_id.to_string(), stage, done, total }).unwrap();
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct BatchProgress {
+    /// Transfer id on the sending side, offer id on the receiving side
+    id: String,
+    /// By bytes, so a large file counts for more than a small one
+    overall_percent: f64,
+    files_done: usize,
+    files_total: usize,
+    bytes_done: u64,
+    bytes_total: u64,
+}
+
+/// Adds up the progress of the files of a batch for `batch-progress`. Files are dealt with
+/// one at a time, so it's the files settled so far plus the one in flight.
+struct BatchTracker {
+    id: String,
+    files_total: usize,
+    bytes_total: u64,
+    /// Files settled, their bytes, and the bytes of the current file so far
+    inner: Mutex<(usize, u64, u64)>,
+}
+
+impl BatchTracker {
+    fn new(id: &str, files: &[FileMetadata]) -> Self {
+        Self {
+            id: id.to_string(),
+            files_total: files.len(),
+            bytes_total: files.iter().map(|file_meta| file_meta.size).sum(),
+            inner: Mutex::new((0, 0, 0)),
+        }
+    }
+
+    /// The current file has `bytes` so far, counting what it was resumed from.
+    fn file_progress(&self, app: &AppHandle, bytes: u64) {
+        let progress = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.2 = bytes;
+            self.progress(&inner)
+        };
+        self.publish(app, progress);
+    }
+
+    /// A file is done with, whether it arrived, failed, was skipped or declined.
+    fn file_settled(&self, app: &AppHandle, file_meta: &FileMetadata) {
+        let progress = {
+            let mut inner = self.inner.lock().unwrap();
+            *inner = (inner.0 + 1, inner.1 + file_meta.size, 0);
+            self.progress(&inner)
+        };
+        self.publish(app, progress);
+    }
+
+    /// Emits `progress` and keeps it for `get_active_transfers`.
+    fn publish(&self, app: &AppHandle, progress: BatchProgress) {
+        if let Some(transfer) = app.state::<ActiveTransfers>().lock().unwrap().get_mut(&self.id) {
+            transfer.progress = Some(progress.clone());
+        }
+        app.emit("batch-progress", progress).unwrap();
+    }
+
+    fn progress(&self, &(files_done, settled_bytes, current_bytes): &(usize, u64, u64)) -> BatchProgress {
+        let bytes_done = (settled_bytes + current_bytes).min(self.bytes_total);
+        BatchProgress {
+            id: self.id.clone(),
+            overall_percent: if self.bytes_total == 0 {
+                files_done as f64 / self.files_total.max(1) as f64 * 100.0
+            } else {
+                bytes_done as f64 / self.bytes_total as f64 * 100.0
+            },
+            files_done,
+            files_total: self.files_total,
+            bytes_done,
+            bytes_total: self.bytes_total,
+        }
+    }
+}
+
+/// Where the streaming loops report to, so they don't depend on a running app.
+trait TransferEvents {
+    fn progress(&self, progress: FileTransferProgress);
+    /// Counts toward the session totals in `TransferStats`
+    fn bytes_transferred(&self, bytes: u64);
+}
+
+/// Emits `transfer-progress` and `batch-progress` and feeds `TransferStats`. `recipient` is
+/// set on the sending side, `offer_id` on the receiving one.
+struct AppEvents<'a> {
+    app: &'a AppHandle,
+    recipient: Option<&'a str>,
+    offer_id: Option<&'a str>,
+    batch: &'a BatchTracker,
+}
+
+impl TransferEvents for AppEvents<'_> {
+    fn progress(&self, progress: FileTransferProgress) {
+        self.batch.file_progress(self.app, progress.bytes_transferred);
+        self.app.emit("transfer-progress", FileTransferProgress {
+            recipient: self.recipient.map(str::to_string),
+            offer_id: self.offer_id.map(str::to_string),
+            ..progress
+        }).unwrap();
+    }
+
+    fn bytes_transferred(&self, bytes: u64) {
+        self.app.state::<Arc<TransferStats>>().add_bytes(bytes);
+    }
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct FileTransferComplete {
+    /// Set on the sending side, which may be sending the same files to several peers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipient: Option<String>,
+    /// Set on the receiving side, which may be receiving several offers from one peer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offer_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    saved_path: Option<PathBuf>,
+    /// The name the sender gave, set when it couldn't be used here, see `portable_path`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_name: Option<String>,
+    /// How moving the file went, `None` when nothing was transferred for it
+    #[serde(flatten)]
+    timing: Option<TransferTiming>,
+}
+
+/// Throughput of one file, counting only what went over the wire this time.
+#[derive(Clone, serde::Serialize, Debug)]
+struct TransferTiming {
+    duration_ms: u64,
+    /// Bytes transferred, less than the file size when it was resumed
+    total_bytes: u64,
+    average_bytes_per_sec: u64,
+}
+
+impl TransferTiming {
+    fn since(started: Instant, total_bytes: u64) -> Self {
+        let elapsed = started.elapsed();
+        Self {
+            duration_ms: elapsed.as_millis() as u64,
+            total_bytes,
+            average_bytes_per_sec: (total_bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64,
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct FileTransferFailed {
+    /// Set on the sending side, which may be sending the same files to several peers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipient: Option<String>,
+    /// Set on the receiving side, which may be receiving several offers from one peer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offer_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    reason: String,
+    /// Set for failures the UI explains on its own, `reason` has the details
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<FailureKind>,
+}
+
+#[derive(Clone, Copy, serde::Serialize, Debug, PartialEq, Eq)]
+enum FailureKind {
+    /// The download drive is full, the batch was aborted
+    DiskFull,
+    /// Canceled on this side, the batch was aborted
+    Cancelled,
+}
+
+/// Whether `e` is a write that failed because the drive ran out of space.
+fn is_disk_full(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| matches!(e.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::WriteZero))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TransferDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TransferStatus {
+    Completed,
+    Failed,
+    Rejected,
+    /// The recipient already had the file, so it wasn't sent
+    Skipped,
+}
+
+/// One line of the transfer history, one per file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct TransferRecord {
+    /// Transfer id on the sending side, offer id on the receiving side, shared by all
+    /// files of a batch
+    id: String,
+    direction: TransferDirection,
+    /// Address of the other side
+    peer: String,
+    file_name: String,
+    size: u64,
+    /// Unix time in milliseconds
+    timestamp: u64,
+    status: TransferStatus,
+}
+
+impl TransferRecord {
+    fn new(id: &str, direction: TransferDirection, peer: &str, file_meta: &FileMetadata, status: TransferStatus) -> Self {
+        Self {
+            id: id.to_string(),
+            direction,
+            peer: peer.to_string(),
+            file_name: file_meta.path.clone(),
+            size: file_meta.size,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            status,
+        }
+    }
+}
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// Appends a record to the history log. Failing to write history never fails a transfer.
+fn record_transfer(app: &AppHandle, record: TransferRecord) {
+    app.state::<Arc<TransferStats>>().add_file(record.status);
+    let result = app.path().app_data_dir().map_err(std::io::Error::other).and_then(|dir| {
+        std::fs::create_dir_all(&dir)?;
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(dir.join(HISTORY_FILE))?;
+        std::io::Write::write_all(&mut file, &line)
+    });
+    if let Err(e) = result {
+        error!(error = %e, "Failed to record transfer history");
+    }
+}
+
+/// The last `limit` history records, newest first. Lines that don't parse are skipped.
+#[tauri::command]
+fn get_transfer_history(app: AppHandle, limit: usize) -> Result<Vec<TransferRecord>, String> {
+    let path = app.path().app_data_dir().map_err(|e| e.to_string())?.join(HISTORY_FILE);
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok(contents.lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .take(limit)
+        .collect())
+}
+
+#[derive(Clone, serde::Serialize)]
+struct TransferStarted {
+    transfer_id: String,
+    recipient: String,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct TransferConnecting {
+    recipient: String,
+    /// Starts at 1
+    attempt: u32,
+    attempts: u32,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct TransferStateChanged {
+    transfer_id: String,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct TextReceived {
+    from: String,
+    text: String,
+}
+
+/// Offers and text snippets one address may send within `OFFER_RATE_WINDOW`, more is a flood
+const MAX_OFFERS_PER_WINDOW: usize = 20;
+const OFFER_RATE_WINDOW: Duration = Duration::from_secs(60);
+/// How long connections from a flooding address are refused
+const OFFER_FLOOD_COOLDOWN: Duration = Duration::from_secs(120);
+
+/// Recent offers per sender address, so one that sends them in a loop can't bury the UI
+/// in prompts.
+#[derive(Default)]
+struct OfferLimiter(Mutex<HashMap<IpAddr, OfferRate>>);
+
+#[derive(Debug, Default)]
+struct OfferRate {
+    offers: VecDeque<Instant>,
+    /// Connections are refused until then
+    throttled_until: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum OfferAdmission {
+    Admitted,
+    /// This offer went over the limit, the address is throttled from now on
+    FloodStarted,
+    Throttled,
+}
+
+impl OfferLimiter {
+    /// Whether connections from `ip` are refused right now.
+    fn is_throttled(&self, ip: IpAddr, now: Instant) -> bool {
+        self.0.lock().unwrap().get(&ip.to_canonical())
+            .and_then(|rate| rate.throttled_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Counts an offer from `ip`.
+    fn admit(&self, ip: IpAddr, now: Instant) -> OfferAdmission {
+        let mut rates = self.0.lock().unwrap();
+        // Addresses that went quiet are forgotten, the map only holds recent senders
+        rates.retain(|_, rate| {
+            while rate.offers.front().is_some_and(|at| now.duration_since(*at) >= OFFER_RATE_WINDOW) {
+                rate.offers.pop_front();
+            }
+            rate.throttled_until = rate.throttled_until.filter(|until| now < *until);
+            !rate.offers.is_empty() || rate.throttled_until.is_some()
+        });
+        let rate = rates.entry(ip.to_canonical()).or_default();
+        if rate.throttled_until.is_some() {
+            return OfferAdmission::Throttled;
+        }
+        rate.offers.push_back(now);
+        if rate.offers.len() <= MAX_OFFERS_PER_WINDOW {
+            return OfferAdmission::Admitted;
+        }
+        rate.offers.clear();
+        rate.throttled_until = Some(now + OFFER_FLOOD_COOLDOWN);
+        OfferAdmission::FloodStarted
+    }
+}
+
+/// Payload of `offer-flood-detected`, emitted once when an address starts to be throttled.
+#[derive(Clone, serde::Serialize)]
+struct OfferFlood {
+    address: String,
+    from: String,
+    cooldown_secs: u64,
+}
+
+/// Takes an offer out of `FileOffers` when dropped, so it doesn't stay behind however
+/// handling it ended.
+struct PendingOffer<'a> {
+    offers: &'a FileOffers,
+    id: &'a str,
+}
+
+impl Drop for PendingOffer<'_> {
+    fn drop(&mut self) {
+        self.offers.lock().unwrap().remove(self.id);
+    }
+}
+
+/// Payload of `offer-expired`, and of `offer-withdrawn` when the sender hung up first.
+#[derive(Clone, serde::Serialize)]
+struct OfferExpired {
+    id: String,
+}
+
+/// Emitted as `notify` next to `file-offer`, for raising an OS notification that can
+/// answer the offer with `accept_file_offer` or `reject_file_offer` right away.
+#[derive(Clone, serde::Serialize)]
+struct OfferNotification {
+    offer_id: String,
+    title: String,
+    /// Like "Anna wants to send 3 files (12 MB)"
+    body: String,
+    /// Id the sender introduced itself with
+    peer_id: String,
+    peer_name: String,
+    file_count: usize,
+    total_size: u64,
+}
+
+impl OfferNotification {
+    fn new(offer_id: &str, peer_id: &str, peer_name: String, files: &[FileMetadata], total_size: u64) -> Self {
+        let what = match files {
+            [file] => file.path.rsplit('/').next().unwrap_or_default().to_string(),
+            _ => format!("{} files", files.len()),
+        };
+        Self {
+            offer_id: offer_id.to_string(),
+            title: "Incoming files".to_string(),
+            body: format!("{} wants to send {} ({})", peer_name, what, format_bytes(total_size)),
+            peer_id: peer_id.to_string(),
+            peer_name,
+            file_count: files.len(),
+            total_size,
+        }
+    }
+}
+
+/// Formats a size the way the UI's `formatBytes` does, e.g. `12.5 MB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["Bytes", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    let rounded = format!("{:.2}", value);
+    format!("{} {}", rounded.trim_end_matches('0').trim_end_matches('.'), UNITS[unit])
+}
+
+#[derive(Clone, serde::Serialize)]
+struct BatchFileOfferPayload {
+    id: String,
+    /// The sender's name as we know it or as it introduced itself, its IP if neither
+    from: String,
+    /// The sender's IP
+    address: String,
+    files: Vec<FileMetadata>,
+    total_size: u64,
+    /// The sender isn't in our peer list, the UI should warn before accepting
+    unknown_sender: bool,
+    /// Where the one file will be downloaded from, for a `TransferRequest::Url`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+use std::error::Error;
+
+/// Writes a length-prefixed JSON frame, the same framing used for the batch metadata.
+async fn write_json<W, T>(stream: &mut W, value: &T) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+    T: Serialize,
+{
+    let bytes = serde_json::to_vec(value)?;
+    stream.write_u64(bytes.len() as u64).await?;
+    stream.write_all(&bytes).await?;
+    // TLS buffers writes, and the peer is usually waiting for this frame before replying
+    stream.flush().await
+}
+
+/// Opens a transfer connection: magic, protocol version, then the `Hello`.
+async fn write_preamble<W: AsyncWriteExt + Unpin>(stream: &mut W, hello: &Hello) -> std::io::Result<()> {
+    stream.write_all(PROTOCOL_MAGIC).await?;
+    stream.write_u8(PROTOCOL_VERSION).await?;
+    write_json(stream, hello).await
+}
+
+/// Answers an offer with a single byte: 0 declined, `PAIRING_REJECTED`, `AUTH_REJECTED`,
+/// `STORAGE_UNAVAILABLE`, or 1 for requests that need nothing else. Accepted batches get
+/// `answer_offer` instead.
+async fn write_verdict<W: AsyncWriteExt + Unpin>(stream: &mut W, verdict: u8) -> std::io::Result<()> {
+    stream.write_all(&[verdict]).await?;
+    stream.flush().await
+}
+
+/// Accepts a batch offer, telling the sender what to send, see `OfferAnswer`.
+async fn answer_offer<W: AsyncWriteExt + Unpin>(
+    stream: &mut W,
+    accepted: &[usize],
+    offsets: &[u64],
+    already_there: &[usize],
+) -> std::io::Result<()> {
+    stream.write_all(&[1]).await?;
+    write_json(stream, &accepted).await?;
+    write_json(stream, &offsets).await?;
+    write_json(stream, &already_there).await
+}
+
+/// Checks the magic and protocol version a peer sends before anything else, so a peer
+/// speaking a wire format we don't is turned away instead of being misparsed. Returns the
+/// peer's version, anything from `MIN_PROTOCOL_VERSION` up to ours.
+async fn read_preamble<R: AsyncReadExt + Unpin>(stream: &mut R) -> Result<u8, Box<dyn Error + Send + Sync>> {
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic).await?;
+    if &magic != PROTOCOL_MAGIC {
+        return Err("Not a Kitsunet Share connection (bad magic)".into());
+    }
+    let version = stream.read_u8().await?;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(format!(
+            "Unsupported protocol version {} (we speak {} to {})",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        ).into());
+    }
+    Ok(version)
+}
+
+/// Whether a sender may go on to the offer. With pairing required, unknown senders need
+/// the current PIN and are remembered once they've got it right.
+fn check_pairing(app: &AppHandle, state: &mut SharedState, hello: &Hello, address: &str, unknown_sender: bool) -> bool {
+    let required = state.settings.require_pairing || (unknown_sender && state.settings.pair_unknown_senders);
+    if !required || state.is_paired(&hello.sender_id, address) {
+        return true;
+    }
+    let pairing = &mut state.pairing;
+    if hello.pin.as_deref() != Some(pairing.pin.as_str()) {
+        if hello.pin.is_some() {
+            pairing.failed_attempts += 1;
+            if pairing.failed_attempts >= MAX_PIN_ATTEMPTS {
+                pairing.pin = generate_pin();
+                pairing.failed_attempts = 0;
+                app.emit("pairing-pin-changed", pairing.pin.clone()).unwrap();
+            }
+        }
+        return false;
+    }
+
+    pairing.paired_peers.insert(hello.sender_id.clone());
+    pairing.failed_attempts = 0;
+    match app.path().app_config_dir() {
+        Ok(dir) => {
+            if let Err(e) = write_json_atomic(&dir.join(PAIRED_PEERS_FILE), &pairing.paired_peers) {
+                error!(error = %e, "Failed to save paired peers");
+            }
+        }
+        Err(e) => error!(error = %e, "Failed to save paired peers"),
+    }
+    true
+}
+
+/// Upper bound for a compressed frame and for the data it expands to
+const MAX_COMPRESSED_CHUNK_LEN: usize = 16 * 1024 * 1024;
+/// Extensions of formats that are compressed already, where zstd would only cost CPU
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar",
+    "jpg", "jpeg", "png", "gif", "webp", "heic",
+    "mp3", "aac", "ogg", "flac", "m4a",
+    "mp4", "mkv", "mov", "avi", "webm",
+    "docx", "xlsx", "pptx", "odt", "apk", "jar",
+];
+
+fn is_compressible(path: &str) -> bool {
+    let extension = path.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+    !extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+}
+
+fn idle_timeout() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "Sender stopped sending")
+}
+
+/// Compresses one chunk of a file into a frame: u32 length, then the zstd data.
+fn compress_chunk(chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+    let compressed = zstd::bulk::compress(chunk, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    let mut frame = Vec::with_capacity(4 + compressed.len());
+    frame.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&compressed);
+    Ok(frame)
+}
+
+/// A transfer connection, or the pipe standing in for it while a batch is packed.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Compresses everything read from `plain` as a single zstd stream, so the files of a batch
+/// compress against each other, and writes it to `stream` in frames: u32 length, then that
+/// many bytes of the stream. A zero length ends it.
+async fn pack_into<R, W>(mut plain: R, stream: &mut W) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    let mut buffer = vec![0; 1024 * 1024]; // 1MB buffer
+    loop {
+        let bytes_read = plain.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        std::io::Write::write_all(&mut encoder, &buffer[..bytes_read])?;
+        // Ends the block but not the stream, the receiver gets the data without the
+        // compression starting over
+        std::io::Write::flush(&mut encoder)?;
+        write_pack_frame(stream, encoder.get_ref()).await?;
+        encoder.get_mut().clear();
+    }
+    write_pack_frame(stream, &encoder.finish()?).await?;
+    stream.write_u32(0).await?;
+    stream.flush().await
+}
+
+async fn write_pack_frame<W: AsyncWrite + Unpin>(stream: &mut W, frame: &[u8]) -> std::io::Result<()> {
+    if frame.is_empty() {
+        return Ok(());
+    }
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame).await
+}
+
+/// Reads the frames `pack_into` writes and writes what they decompress to into `plain`,
+/// closing it at the end. Fails if the sender goes quiet for `read_timeout`.
+async fn unpack_from<R, W>(stream: &mut R, mut plain: W, read_timeout: Duration) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+    let mut decoder = zstd::stream::raw::Decoder::new()?;
+    let mut output = vec![0; 1024 * 1024]; // 1MB buffer
+    loop {
+        let len = tokio::time::timeout(read_timeout, stream.read_u32())
+            .await
+            .map_err(|_| idle_timeout())?? as usize;
+        if len == 0 {
+            break;
+        }
+        if len > MAX_COMPRESSED_CHUNK_LEN {
+            return Err(format!("Packed frame too large: {} bytes", len).into());
+        }
+        let mut frame = vec![0; len];
+        tokio::time::timeout(read_timeout, stream.read_exact(&mut frame))
+            .await
+            .map_err(|_| idle_timeout())??;
+
+        // Decompressed a buffer at a time, however far the frame expands
+        let mut input = InBuffer::around(&frame);
+        loop {
+            let produced = {
+                let mut out = OutBuffer::around(&mut output[..]);
+                decoder.run(&mut input, &mut out)?;
+                out.pos()
+            };
+            plain.write_all(&output[..produced]).await?;
+            if input.pos() == frame.len() && produced < output.len() {
+                break;
+            }
+        }
+    }
+    plain.shutdown().await?;
+    Ok(())
+}
+
+/// Reads a frame written by `compress_chunk`, expecting at most `remaining` bytes of file data.
+async fn read_compressed_chunk<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    remaining: u64,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_COMPRESSED_CHUNK_LEN {
+        return Err(format!("Compressed chunk too large: {} bytes", len).into());
+    }
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame).await?;
+    let capacity = std::cmp::min(remaining, MAX_COMPRESSED_CHUNK_LEN as u64) as usize;
+    let chunk = zstd::bulk::decompress(&frame, capacity)?;
+    if chunk.is_empty() {
+        return Err("Empty compressed chunk".into());
+    }
+    Ok(chunk)
+}
+
+/// Largest JSON frame we accept, metadata for a batch of many thousands of files fits easily
+const MAX_FRAME_LEN: u64 = 10 * 1024 * 1024;
+/// Files at least this big are split over `parallel_streams` connections when that's above 1
+const PARALLEL_MIN_FILE_SIZE: u64 = 64 * 1024 * 1024;
+/// Buffer between the file loop and `pack_into`/`unpack_from` on packed batches
+const PACK_PIPE_LEN: usize = 1024 * 1024;
+/// Most ranges a file may be split into, which also caps the connections per file
+const MAX_PARALLEL_RANGES: usize = 16;
+/// Largest total batch size we're willing to offer to the user, anything beyond is a
+/// broken or hostile sender
+const MAX_BATCH_BYTES: u64 = 1 << 44; // 16 TiB
+
+/// Why an offer was declined without asking the user
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum AutoRejectReason {
+    /// Over the `auto_reject_over_bytes` setting
+    TooLarge { limit: u64 },
+    /// Doesn't fit in the download directory
+    InsufficientSpace { available_bytes: u64 },
+    /// `file` has an extension outside `allowed_extensions`
+    DisallowedType { file: String },
+    /// Our `presence_status` is `Busy`
+    Busy,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct OfferAutoRejected {
+    id: String,
+    from: String,
+    total_size: u64,
+    reason: AutoRejectReason,
+}
+
+/// An offer declined because `check_storage` failed, telling the user to pick a valid
+/// download directory.
+#[derive(Clone, serde::Serialize)]
+struct StorageUnavailable {
+    offer_id: String,
+    from: String,
+    reason: String,
+}
+
+/// Payload of `batch-received`, emitted once an accepted batch is over on the receiving
+/// side, however it went.
+#[derive(Clone, serde::Serialize)]
+struct BatchReceived {
+    offer_id: String,
+    from: String,
+    /// Files saved, symlinks included
+    received: usize,
+    /// Files that were there already
+    skipped: usize,
+    /// Files left unticked
+    declined: usize,
+    failed: usize,
+    result: BatchResult,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchResult {
+    /// Every accepted file was saved or already there
+    Complete,
+    /// Some were, others failed
+    Partial,
+    /// None of the accepted files arrived
+    Failed,
+}
+
+impl BatchResult {
+    fn new(done: usize, failed: usize) -> Self {
+        match (done, failed) {
+            (_, 0) => BatchResult::Complete,
+            (0, _) => BatchResult::Failed,
+            _ => BatchResult::Partial,
+        }
+    }
+}
+
+/// Whether a batch of `files` and `total_size` bytes should be declined before prompting.
+fn auto_reject_reason(app: &AppHandle, files: &[FileMetadata], total_size: u64) -> Option<AutoRejectReason> {
+    let (limit, allowed_extensions, status) = {
+        let state = app.state::<AppState>();
+        let settings = &state.0.lock().unwrap().settings;
+        (settings.auto_reject_over_bytes, settings.allowed_extensions.clone().unwrap_or_default(), settings.presence_status)
+    };
+    if status == PresenceStatus::Busy {
+        return Some(AutoRejectReason::Busy);
+    }
+    if !allowed_extensions.is_empty() {
+        let allowed: HashSet<String> = allowed_extensions.iter()
+            .map(|extension| extension.trim_start_matches('.').to_lowercase())
+            .collect();
+        let disallowed = files.iter().find(|file| {
+            std::path::Path::new(&file.path).extension()
+                .is_none_or(|extension| !allowed.contains(&extension.to_string_lossy().to_lowercase()))
+        });
+        if let Some(file) = disallowed {
+            return Some(AutoRejectReason::DisallowedType { file: file.path.clone() });
+        }
+    }
+    if let Some(limit) = limit.filter(|limit| total_size > *limit) {
+        return Some(AutoRejectReason::TooLarge { limit });
+    }
+    // If the free space can't be determined, let the user decide
+    let available_bytes = resolve_download_dir(app).ok().and_then(|dir| fs2::available_space(dir).ok())?;
+    (total_size > available_bytes).then_some(AutoRejectReason::InsufficientSpace { available_bytes })
+}
+
+/// The `auto_accept_patterns` that cover `files`, or `None` unless every file matches one.
+fn matching_accept_rules(patterns: &[String], files: &[FileMetadata]) -> Option<Vec<String>> {
+    let options = glob::MatchOptions { case_sensitive: false, ..glob::MatchOptions::new() };
+    let patterns: Vec<(&String, glob::Pattern)> = patterns.iter()
+        .filter_map(|pattern| glob::Pattern::new(pattern).ok().map(|compiled| (pattern, compiled)))
+        .collect();
+    if files.is_empty() || patterns.is_empty() {
+        return None;
+    }
+    let mut matched = Vec::new();
+    for file in files {
+        let (rule, _) = patterns.iter().find(|(_, compiled)| compiled.matches_with(&file.path, options))?;
+        if !matched.contains(*rule) {
+            matched.push(rule.to_string());
+        }
+    }
+    Some(matched)
+}
+
+/// Emitted as `auto-accepted-by-rule` for an offer `auto_accept_patterns` let through.
+#[derive(Clone, Serialize)]
+struct RuleAcceptedOffer {
+    #[serde(flatten)]
+    offer: BatchFileOfferPayload,
+    /// The patterns that matched its files
+    rules: Vec<String>,
+}
+
+/// Checks an incoming batch before the user sees it and returns its total size.
+fn validate_batch(files: &[FileMetadata], batch_id: Option<&str>, packed: bool) -> Result<u64, String> {
+    let mut total_size: u64 = 0;
+    for file in files {
+        if file.path.contains('\0') || safe_relative_path(&file.path).is_none() {
+            return Err(format!("Unsafe file path: {:?}", file.path));
+        }
+        if packed && (file.compressed || !file.ranges.is_empty()) {
+            return Err(format!("Packed file can't be compressed or ranged: {:?}", file.path));
+        }
+        if file.streamed && (file.compressed || !file.ranges.is_empty() || file.symlink_target.is_some()) {
+            return Err(format!("Streamed file can only be sent as chunks: {:?}", file.path));
+        }
+        if file.encryption.is_some() && (!file.streamed || packed) {
+            return Err(format!("Encrypted file can only be sent as chunks: {:?}", file.path));
+        }
+        if let Some(target) = &file.symlink_target {
+            if safe_symlink_target(&file.path, target).is_none() {
+                return Err(format!("Unsafe symlink target: {:?} -> {:?}", file.path, target));
+            }
+            if file.size != 0 || file.compressed || !file.ranges.is_empty() {
+                return Err(format!("Symlink can't carry data: {:?}", file.path));
+            }
+        }
+        if !file.ranges.is_empty() {
+            let mut expected_offset = 0;
+            let contiguous = file.ranges.iter().all(|range| {
+                let valid = range.offset == expected_offset && range.len > 0;
+                expected_offset = range.offset.saturating_add(range.len);
+                valid
+            });
+            if batch_id.is_none() || file.compressed || file.ranges.len() > MAX_PARALLEL_RANGES
+                || !contiguous || expected_offset != file.size
+            {
+                return Err(format!("Invalid ranges for {:?}", file.path));
+            }
+        }
+        total_size = total_size
+            .checked_add(file.size)
+            .filter(|total| *total <= MAX_BATCH_BYTES)
+            .ok_or_else(|| "Batch is implausibly large".to_string())?;
+    }
+    Ok(total_size)
+}
+
+/// Reads a length-prefixed JSON frame written by `write_json`.
+async fn read_json<R, T>(stream: &mut R) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    R: AsyncReadExt + Unpin,
+    T: serde::de::DeserializeOwned,
+{
+    let len = stream.read_u64().await?;
+    // The length comes from the peer before anything is authorized, don't let it pick
+    // how much we allocate
+    if len > MAX_FRAME_LEN {
+        return Err(format!("Frame too large: {} bytes", len).into());
+    }
+    let mut bytes = vec![0; len as usize];
+    stream.read_exact(&mut bytes).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+#[tracing::instrument(skip_all, fields(remote = %remote_addr))]
+async fn handle_incoming_batch<S>(
+    app: AppHandle,
+    mut stream: S,
+    remote_addr: std::net::SocketAddr,
+    offers: FileOffers,
+    slots: Arc<Semaphore>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let read_timeout = receive_timeout(&app);
+    let buffer_len = transfer_buffer_len(&app);
+    let quarantine = app.state::<AppState>().0.lock().unwrap().settings.quarantine_received;
+    let result: Result<(), Box<dyn Error + Send + Sync>> = async {
+        // Sent whether or not we have a secret, the sender can't know before reading it
+        let challenge = auth_challenge();
+        stream.write_all(&challenge).await?;
+        stream.flush().await?;
+        // A sender that connects and goes quiet would hold a connection open for nothing
+        let (version, hello): (u8, Hello) = tokio::time::timeout(read_timeout, async {
+            let version = read_preamble(&mut stream).await?;
+            Ok::<_, Box<dyn Error + Send + Sync>>((version, read_json(&mut stream).await?))
+        }).await.map_err(|_| idle_timeout())??;
+        debug!(sender = %hello.sender_id, version, "Sender introduced itself");
+
+        // Read metadata
+        let request: TransferRequest = tokio::time::timeout(read_timeout, read_json(&mut stream))
+            .await
+            .map_err(|_| idle_timeout())??;
+        let (verdict, unknown_sender, auto_accept) = {
+            let state = app.state::<AppState>();
+            let mut state = state.0.lock().unwrap();
+            let address = peer_address(&remote_addr);
+            // Nothing stops a host that never announced itself from connecting, so flag it
+            let unknown_sender = !state.is_known_peer(&hello.sender_id, &address);
+            let verdict = if state.is_blocked(Some(&hello.sender_id), &address) {
+                Some(0)
+            } else if state.settings.shared_secret.as_deref()
+                .is_some_and(|secret| !verify_shared_secret(secret, &challenge, hello.auth.as_deref()))
+            {
+                warn!(sender = %hello.sender_id, "Rejected a sender without the shared secret");
+                Some(AUTH_REJECTED)
+            } else if !check_pairing(&app, &mut state, &hello, &address, unknown_sender) {
+                Some(PAIRING_REJECTED)
+            } else {
+                None
+            };
+            // Only once the pairing check passed, a wrong PIN never gets anything through
+            let auto_accept = verdict.is_none() && state.is_auto_accepted(&hello.sender_id, &address);
+            (verdict, unknown_sender, auto_accept)
+        };
+        if let Some(verdict) = verdict {
+            write_verdict(&mut stream, verdict).await?;
+            return Ok(());
+        }
+        // What the user sees as the sender: the name we know it by, else the one it gave,
+        // else its IP for older versions that don't give one
+        let sender_name = app.state::<AppState>().0.lock().unwrap()
+            .peer_name(&hello.sender_id, &peer_address(&remote_addr))
+            .or_else(|| hello.username.clone().filter(|name| !name.trim().is_empty()))
+            .unwrap_or_else(|| remote_addr.ip().to_string());
+        // Calibrations count too, each one makes us read a burst
+        if matches!(request, TransferRequest::Files { .. } | TransferRequest::Text { .. } | TransferRequest::Url { .. } | TransferRequest::Calibrate { .. }) {
+            match app.state::<OfferLimiter>().admit(remote_addr.ip(), Instant::now()) {
+                OfferAdmission::Admitted => {}
+                OfferAdmission::FloodStarted => {
+                    warn!(remote = %remote_addr, cooldown_secs = OFFER_FLOOD_COOLDOWN.as_secs(), "Too many offers, refusing the sender for a while");
+                    app.emit("offer-flood-detected", OfferFlood {
+                        address: remote_addr.ip().to_string(),
+                        from: sender_name,
+                        cooldown_secs: OFFER_FLOOD_COOLDOWN.as_secs(),
+                    }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                    write_verdict(&mut stream, 0).await?;
+                    return Ok(());
+                }
+                OfferAdmission::Throttled => {
+                    write_verdict(&mut stream, 0).await?;
+                    return Ok(());
+                }
+            }
+        }
+        let (files, batch_id, packed, sender_name) = match request {
+            TransferRequest::Files { files, batch_id, packed, sender_label } => {
+                // Only for this batch, the peer keeps its name everywhere else
+                let sender_name = sender_label.as_deref().and_then(clean_sender_label).unwrap_or(sender_name);
+                (files, batch_id, packed, sender_name)
+            }
+            TransferRequest::Text { text } => {
+                app.emit("text-received", TextReceived {
+                    from: sender_name,
+                    text,
+                }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                write_verdict(&mut stream, 1).await?;
+                return Ok(());
+            }
+            TransferRequest::Range { batch_id, file_index, range_index } => {
+                return receive_range(&app, &mut stream, &hello, remote_addr.ip(), (batch_id, file_index), range_index).await;
+            }
+            TransferRequest::Calibrate { len } => {
+                return receive_calibration(&mut stream, len, read_timeout).await;
+            }
+            TransferRequest::Url { url, name } => {
+                return receive_url(&app, &mut stream, &offers, &hello, remote_addr, sender_name, unknown_sender, (url, name)).await;
+            }
+        };
+
+        // Held until the batch is done. Only batches count, the range connections of a
+        // batch that has a slot must not wait for another one.
+        let _permit = slots.acquire_owned().await?;
+
+        let total_size = match validate_batch(&files, batch_id.as_deref(), packed) {
+            Ok(total_size) => total_size,
+            Err(reason) => {
+                write_verdict(&mut stream, 0).await?;
+                return Err(reason.into());
+            }
+        };
+
+        let offer_id = Uuid::new_v4().to_string();
+        if let Err(reason) = check_storage(&app) {
+            warn!(offer_id = %offer_id, reason = %reason, "Download directory unusable, declining offer");
+            write_verdict(&mut stream, STORAGE_UNAVAILABLE).await?;
+            app.emit("storage-unavailable", StorageUnavailable {
+                offer_id: offer_id.clone(),
+                from: sender_name.clone(),
+                reason,
+            }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            let peer = peer_address(&remote_addr);
+            for file_meta in &files {
+                record_transfer(&app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, file_meta, TransferStatus::Failed));
+            }
+            return Ok(());
+        }
+        if let Some(reason) = auto_reject_reason(&app, &files, total_size) {
+            info!(offer_id = %offer_id, total_size, ?reason, "File offer auto-rejected");
+            write_verdict(&mut stream, 0).await?;
+            app.emit("file-offer-auto-rejected", OfferAutoRejected {
+                id: offer_id.clone(),
+                from: sender_name.clone(),
+                total_size,
+                reason,
+            }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            let peer = peer_address(&remote_addr);
+            for file_meta in &files {
+                record_transfer(&app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, file_meta, TransferStatus::Rejected));
+            }
+            return Ok(());
+        }
+
+        let payload = BatchFileOfferPayload {
+            id: offer_id.clone(),
+            from: sender_name.clone(),
+            address: remote_addr.ip().to_string(),
+            files: files.clone(),
+            total_size,
+            unknown_sender,
+            url: None,
+        };
+        // The password for encrypted files only comes from asking
+        let encrypted = files.iter().any(|file| file.encryption.is_some());
+        let auto_accept = auto_accept && !encrypted;
+        let accepted_by_rule = if auto_accept || encrypted {
+            None
+        } else {
+            let patterns = app.state::<AppState>().0.lock().unwrap().settings.auto_accept_patterns.clone();
+            matching_accept_rules(&patterns, &files)
+        };
+        let decision = if auto_accept {
+            info!(offer_id = %offer_id, files = files.len(), total_size, "File offer auto-accepted");
+            app.emit("file-offer-auto-accepted", payload)
+                .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            Some(OfferDecision::Accepted { indexes: None, password: None })
+        } else if let Some(rules) = accepted_by_rule {
+            info!(offer_id = %offer_id, files = files.len(), total_size, ?rules, "File offer accepted by rule");
+            app.emit("auto-accepted-by-rule", RuleAcceptedOffer { offer: payload, rules })
+                .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            Some(OfferDecision::Accepted { indexes: None, password: None })
+        } else {
+            let (tx, rx) = oneshot::channel();
+            offers.lock().unwrap().insert(offer_id.clone(), tx);
+            let _pending = PendingOffer { offers: &offers, id: &offer_id };
+
+            info!(offer_id = %offer_id, files = files.len(), total_size, "File offer received");
+            app.emit("file-offer", payload).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            app.emit("notify", OfferNotification::new(&offer_id, &hello.sender_id, sender_name.clone(), &files, total_size))
+                .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+
+            // The sender sends nothing until it has the answer, so anything arriving
+            // means it hung up
+            let mut probe = [0; 1];
+            tokio::select! {
+                answered = tokio::time::timeout(offer_timeout(&app), rx) => match answered {
+                    Ok(decision) => decision.ok(),
+                    Err(_) => {
+                        app.emit("offer-expired", OfferExpired { id: offer_id.clone() })
+                            .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                        None
+                    }
+                },
+                _ = stream.read(&mut probe) => {
+                    info!(offer_id = %offer_id, "File offer withdrawn");
+                    app.emit("offer-withdrawn", OfferExpired { id: offer_id.clone() })
+                        .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                    let peer = peer_address(&remote_addr);
+                    for file_meta in &files {
+                        record_transfer(&app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, file_meta, TransferStatus::Failed));
+                    }
+                    return Ok(());
+                }
+            }
+        };
+
+        let peer = peer_address(&remote_addr);
+        let record = |file_meta: &FileMetadata, status| {
+            record_transfer(&app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, file_meta, status));
+        };
+
+        let (mut accepted, password): (Vec<usize>, _) = match decision {
+            Some(OfferDecision::Accepted { indexes: None, password }) => ((0..files.len()).collect(), password),
+            Some(OfferDecision::Accepted { indexes: Some(mut indexes), password }) => {
+                indexes.sort_unstable();
+                indexes.dedup();
+                indexes.retain(|index| *index < files.len());
+                (indexes, password)
+            }
+            _ => (Vec::new(), None),
+        };
+
+        // One key per salt, which the sender uses for the whole batch. Encrypted files
+        // there's no key for are of no use and declined.
+        let mut keys = HashMap::new();
+        if let Some(password) = &password {
+            let salts: HashSet<&str> = accepted.iter()
+                .filter_map(|index| files[*index].encryption.as_ref())
+                .map(|encryption| encryption.salt.as_str())
+                .collect();
+            for salt in salts {
+                match derive_encryption_key(password, salt).await {
+                    Ok(key) => {
+                        keys.insert(salt.to_string(), key);
+                    }
+                    Err(e) => warn!(offer_id = %offer_id, error = %e, "Deriving the decryption key failed"),
+                }
+            }
+        }
+        let accepted_count = accepted.len();
+        accepted.retain(|index| files[*index].encryption.as_ref().is_none_or(|encryption| keys.contains_key(&encryption.salt)));
+        if accepted.len() < accepted_count {
+            warn!(offer_id = %offer_id, files = accepted_count - accepted.len(), "Declining encrypted files without a password");
+        }
+
+        if !accepted.is_empty() {
+            let download_dir = resolve_download_dir(&app)?;
+            let receive_dir = receive_dir(&app, &download_dir, &hello.sender_id, &peer);
+
+            // Work out where each file goes, whether it's there already, and otherwise how much
+            // of it an earlier attempt left behind
+            let mut targets = Vec::with_capacity(files.len());
+            let mut offsets = Vec::with_capacity(files.len());
+            let mut already_there = Vec::new();
+            let mut preparing = PreparingEvents { app: &app, offer_id: &offer_id, last_emitted: None };
+            let journal = app.state::<BatchJournal>();
+            let journal_key = BatchJournal::key(&hello.sender_id, &files);
+            let mut renamed = Vec::with_capacity(files.len());
+            for (index, file_meta) in files.iter().enumerate() {
+                preparing.report(PreparingStage::CheckingFiles, index, files.len());
+                let portable = portable_path(&file_meta.path);
+                if portable != file_meta.path {
+                    info!(file = %file_meta.path, saved_as = %portable, "Renaming file to a name this OS allows");
+                }
+                renamed.push(portable != file_meta.path);
+                let mut target_path = receive_dir.join(safe_relative_path(&portable).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unsafe file path: {}", file_meta.path))
+                })?);
+                let part_path = part_path(&target_path);
+                let saved_earlier = match &journal_key {
+                    Some(key) if accepted.contains(&index) => journal.saved_path(key, index, file_meta).await,
+                    _ => None,
+                };
+                if !accepted.contains(&index) || file_meta.symlink_target.is_some() {
+                    offsets.push(0);
+                } else if let Some(saved_path) = saved_earlier {
+                    already_there.push(index);
+                    offsets.push(0);
+                    target_path = saved_path;
+                } else if is_duplicate(&target_path, file_meta).await {
+                    already_there.push(index);
+                    offsets.push(0);
+                } else {
+                    offsets.push(resume_offset(&part_path, file_meta).await);
+                }
+                targets.push((target_path, part_path));
+            }
+            preparing.finish(PreparingStage::CheckingFiles, files.len());
+
+            // The space may have gone while the offer waited for an answer
+            let needed: u64 = files.iter().zip(&offsets).enumerate()
+                .filter(|(index, _)| accepted.contains(index) && !already_there.contains(index))
+                .map(|(_, (file_meta, offset))| file_meta.size - offset)
+                .sum();
+            if let Some(available) = fs2::available_space(&download_dir).ok().filter(|available| needed > *available) {
+                warn!(offer_id = %offer_id, needed, available, "Not enough disk space, declining accepted offer");
+                write_verdict(&mut stream, 0).await?;
+                app.emit("transfer-failed", FileTransferFailed {
+                    recipient: None,
+                    offer_id: Some(offer_id.clone()),
+                    file_path: None,
+                    file_name: None,
+                    reason: format!("Not enough disk space: {} bytes needed, {} available", needed, available),
+                    kind: Some(FailureKind::DiskFull),
+                }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                // Only what would have been received failed, the rest goes down as usual
+                for (index, file_meta) in files.iter().enumerate() {
+                    let status = if !accepted.contains(&index) {
+                        TransferStatus::Rejected
+                    } else if already_there.contains(&index) {
+                        TransferStatus::Skipped
+                    } else {
+                        TransferStatus::Failed
+                    };
+                    record(file_meta, status);
+                }
+                return Ok(());
+            }
+
+            let _stats = app.state::<Arc<TransferStats>>().start(needed);
+            let registered = RegisteredTransfer::new(&app, &offer_id, TransferDirection::Received, &peer);
+
+            answer_offer(&mut stream, &accepted, &offsets, &already_there).await?;
+
+            // Folders up front, a batch of a deep tree would otherwise stall on them file by file
+            let folders: std::collections::BTreeSet<&std::path::Path> = targets.iter().enumerate()
+                .filter(|(index, _)| accepted.contains(index) && !already_there.contains(index))
+                .filter_map(|(_, (target_path, _))| target_path.parent())
+                .collect();
+            for (done, folder) in folders.iter().enumerate() {
+                preparing.report(PreparingStage::CreatingFolders, done, folders.len());
+                tokio::fs::create_dir_all(folder).await?;
+            }
+            preparing.finish(PreparingStage::CreatingFolders, folders.len());
+            let batch_progress = BatchTracker::new(&offer_id, &files);
+            let events = AppEvents { app: &app, recipient: None, offer_id: Some(&offer_id), batch: &batch_progress };
+            let mut received_files = 0;
+
+            // Nothing arrives for these, so they're settled before the rest
+            for (index, (file_meta, (target_path, _))) in files.iter().zip(&targets).enumerate() {
+                if !accepted.contains(&index) {
+                    record(file_meta, TransferStatus::Rejected);
+                    batch_progress.file_settled(&app, file_meta);
+                } else if already_there.contains(&index) {
+                    info!(file = %file_meta.path, "File already received, skipped");
+                    app.emit("transfer-skipped", FileTransferComplete {
+                        recipient: None,
+                        offer_id: Some(offer_id.clone()),
+                        file_path: None,
+                        file_name: Some(file_meta.path.clone()),
+                        saved_path: Some(target_path.clone()),
+                        original_name: renamed[index].then(|| file_meta.path.clone()),
+                        timing: None,
+                    }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                    record(file_meta, TransferStatus::Skipped);
+                    batch_progress.file_settled(&app, file_meta);
+                } else if file_meta.symlink_target.is_some() {
+                    // A link that can't be created fails on its own, the rest of the batch goes on
+                    match create_symlink(target_path, file_meta).await {
+                        Ok(link_path) => {
+                            info!(file = %file_meta.path, "Symlink received");
+                            received_files += 1;
+                            app.emit("transfer-complete", FileTransferComplete {
+                                recipient: None,
+                                offer_id: Some(offer_id.clone()),
+                                file_path: None,
+                                file_name: Some(file_meta.path.clone()),
+                                saved_path: Some(link_path),
+                                original_name: renamed[index].then(|| file_meta.path.clone()),
+                                timing: None,
+                            }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                            record(file_meta, TransferStatus::Completed);
+                        }
+                        Err(e) => {
+                            warn!(file = %file_meta.path, error = %e, "Creating symlink failed");
+                            app.emit("transfer-failed", FileTransferFailed {
+                                recipient: None,
+                                offer_id: Some(offer_id.clone()),
+                                file_path: None,
+                                file_name: Some(file_meta.path.clone()),
+                                reason: e.to_string(),
+                                kind: None,
+                            }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                            record(file_meta, TransferStatus::Failed);
+                        }
+                    }
+                    batch_progress.file_settled(&app, file_meta);
+                }
+            }
+            let incoming: Vec<usize> = (0..files.len())
+                .filter(|index| accepted.contains(index) && !already_there.contains(index) && files[*index].symlink_target.is_none())
+                .collect();
+
+            // A packed batch is read back through the pipe `unpack_from` fills
+            let (pipe, unpacked) = tokio::io::duplex(PACK_PIPE_LEN);
+            let (input, packed_stream): (Box<dyn Connection + '_>, _) = if packed {
+                (Box::new(pipe), Some(&mut stream))
+            } else {
+                (Box::new(&mut stream), None)
+            };
+            let unpacking = async move {
+                match packed_stream {
+                    Some(stream) => unpack_from(stream, unpacked, read_timeout).await,
+                    None => Ok(()),
+                }
+            };
+            let mut failed_files = 0;
+            let receiving = async {
+                let mut input = input;
+                for (index, ((file_meta, (target_path, part_path)), offset)) in files.iter().zip(targets).zip(offsets).enumerate() {
+                    if !incoming.contains(&index) {
+                        continue;
+                    }
+                    if let Some(parent) = part_path.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    let mut file = tokio::fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .open(&part_path)
+                        .await?;
+                    // Drop anything past the verified prefix, or everything when starting over
+                    file.set_len(offset).await?;
+                    let started = Instant::now();
+
+                    let received: Result<bool, Box<dyn Error + Send + Sync>> = async {
+                        if let Some(batch_id) = batch_id.as_ref().filter(|_| !file_meta.ranges.is_empty()) {
+                            let (events_tx, range_events) = mpsc::unbounded_channel();
+                            let slot = RangeSlot {
+                                sender_id: hello.sender_id.clone(),
+                                sender_ip: remote_addr.ip(),
+                                part_path: part_path.clone(),
+                                ranges: file_meta.ranges.clone(),
+                                claimed: vec![false; file_meta.ranges.len()],
+                                events: events_tx,
+                            };
+                            return receive_ranged_file(&events, &mut input, &mut file, (batch_id.clone(), index), slot, range_events, file_meta).await;
+                        }
+                        if let Some(encryption) = &file_meta.encryption {
+                            let key = keys.get(&encryption.salt).ok_or("No key for an encrypted file")?;
+                            return receive_encrypted_file(&mut input, &mut file, file_meta, key, read_timeout, &events).await;
+                        }
+                        if file_meta.streamed {
+                            return receive_streamed_file(&mut input, &mut file, file_meta, read_timeout, &events).await;
+                        }
+                        let mut checkpointer = PartCheckpointer::new(&part_path, &hello.sender_id, file_meta);
+                        receive_inline_file(&mut input, &mut file, file_meta, offset, read_timeout, buffer_len, Some(&mut checkpointer), &events).await
+                    }.await;
+                    drop(file);
+
+                    // Only complete files ever get the real name
+                    let failure = match &received {
+                        Ok(true) => None,
+                        Ok(false) => Some("Checksum mismatch".to_string()),
+                        Err(e) => Some(e.to_string()),
+                    };
+                    if let Some(reason) = failure {
+                        let disk_full = received.as_ref().is_err_and(|e| is_disk_full(e.as_ref()));
+                        // A corrupted file can't be resumed, an interrupted one keeps its .part for
+                        // next time unless it's what filled the drive
+                        if matches!(received, Ok(false)) || disk_full {
+                            let _ = tokio::fs::remove_file(&part_path).await;
+                            let _ = tokio::fs::remove_file(sidecar_path(&part_path)).await;
+                        }
+                        warn!(file = %file_meta.path, reason = %reason, disk_full, "Receiving file failed");
+                        app.emit("transfer-failed", FileTransferFailed {
+                            recipient: None,
+                            offer_id: Some(offer_id.clone()),
+                            file_path: None,
+                            file_name: Some(file_meta.path.clone()),
+                            reason,
+                            kind: disk_full.then_some(FailureKind::DiskFull),
+                        }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                        record(file_meta, TransferStatus::Failed);
+                        batch_progress.file_settled(&app, file_meta);
+                        failed_files += 1;
+                        // A checksum mismatch leaves the stream in sync, a broken connection or a full
+                        // drive doesn't, so the rest of the batch is given up and the connection closed
+                        if received.is_err() {
+                            incoming.iter()
+                                .filter(|later| **later > index)
+                                .for_each(|later| record(&files[*later], TransferStatus::Failed));
+                        }
+                        received?;
+                        continue;
+                    }
+
+                    // Everything arrived and the stream is still in sync, so only this file fails.
+                    // Its .part is kept, sending it again just retries the rename.
+                    let file_path = match finalize_part(&part_path, &target_path).await {
+                        Ok(file_path) => file_path,
+                        Err(e) => {
+                            warn!(file = %file_meta.path, part = %part_path.display(), error = %e, "Moving received file into place failed");
+                            app.emit("transfer-failed", FileTransferFailed {
+                                recipient: None,
+                                offer_id: Some(offer_id.clone()),
+                                file_path: None,
+                                file_name: Some(file_meta.path.clone()),
+                                reason: format!("Saving the file failed: {}", e),
+                                kind: None,
+                            }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                            record(file_meta, TransferStatus::Failed);
+                            batch_progress.file_settled(&app, file_meta);
+                            failed_files += 1;
+                            continue;
+                        }
+                    };
+                    if let Some(key) = &journal_key {
+                        journal.file_saved(key, index, file_meta, &file_path).await;
+                    }
+                    if let Err(e) = apply_mode(&file_path, file_meta.mode).await {
+                        warn!(file = %file_meta.path, error = %e, "Applying file permissions failed");
+                    }
+                    if quarantine {
+                        if let Err(e) = mark_as_downloaded(&file_path).await {
+                            warn!(file = %file_meta.path, error = %e, "Marking file as downloaded failed");
+                        }
+                    }
+                    // A streamed file's size was only what the sender expected
+                    let received_bytes = if file_meta.streamed {
+                        tokio::fs::metadata(&file_path).await.map_or(0, |metadata| metadata.len())
+                    } else {
+                        file_meta.size.saturating_sub(offset)
+                    };
+                    app.emit("transfer-complete", FileTransferComplete {
+                        recipient: None,
+                        offer_id: Some(offer_id.clone()),
+                        file_path: None,
+                        file_name: Some(file_meta.path.clone()),
+                        saved_path: Some(file_path),
+                        original_name: renamed[index].then(|| file_meta.path.clone()),
+                        timing: Some(TransferTiming::since(started, received_bytes)),
+                    }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                    info!(file = %file_meta.path, size = file_meta.size, "File received");
+                    received_files += 1;
+                    record(file_meta, TransferStatus::Completed);
+                    batch_progress.file_settled(&app, file_meta);
+                }
+                Ok::<_, Box<dyn Error + Send + Sync>>(())
+            };
+            let (received, unpacked) = tokio::select! {
+                done = async { tokio::join!(receiving, unpacking) } => done,
+                _ = registered.control.cancel.cancelled() => {
+                    info!(offer_id = %offer_id, "Receiving canceled");
+                    app.emit("transfer-failed", FileTransferFailed {
+                        recipient: None,
+                        offer_id: Some(offer_id.clone()),
+                        file_path: None,
+                        file_name: None,
+                        reason: TransferError::Cancelled.to_string(),
+                        kind: Some(FailureKind::Cancelled),
+                    }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                    (Err(TransferError::Cancelled.to_string().into()), Ok(()))
+                }
+            };
+            // Whatever didn't arrive failed, including what a broken connection cut off
+            let failed = accepted.len() - already_there.len() - received_files;
+            let result = BatchResult::new(received_files + already_there.len(), failed);
+            info!(offer_id = %offer_id, received = received_files, failed, ?result, "Batch received");
+            app.emit("batch-received", BatchReceived {
+                offer_id: offer_id.clone(),
+                from: sender_name.clone(),
+                received: received_files,
+                skipped: already_there.len(),
+                declined: files.len() - accepted.len(),
+                failed,
+                result,
+            }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            received?;
+            unpacked?;
+            // Kept while a file is missing, sending the batch again only sends that one
+            if let Some(key) = journal_key.as_ref().filter(|_| failed_files == 0) {
+                journal.finish(key).await;
+            }
+        } else {
+            write_verdict(&mut stream, 0).await?;
+            files.iter().for_each(|file_meta| record(file_meta, TransferStatus::Rejected));
+            info!(offer_id = %offer_id, "File offer rejected or timed out");
+        }
+
+        Ok(())
+    }.await;
+
+    if let Err(e) = result {
+        error!(remote = %remote_addr, error = %e, "Error handling incoming file batch");
+    }
+}
+
+
+/// Receives a file sent over the batch connection into `file`, which holds the first
+/// `offset` bytes already. Returns whether the checksum in the trailer matched. Fails if
+/// the sender goes quiet for `read_timeout`.
+#[allow(clippy::too_many_arguments)]
+async fn receive_inline_file<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    file: &mut tokio::fs::File,
+    file_meta: &FileMetadata,
+    offset: u64,
+    read_timeout: Duration,
+    buffer_len: usize,
+    mut checkpointer: Option<&mut PartCheckpointer>,
+    events: &impl TransferEvents,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut tracker = ProgressTracker::new(file_meta.size, offset);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; buffer_len];
+
+    // The checksum covers the whole file, including what we already had
+    hash_prefix(file, offset, &mut hasher, &mut buffer).await?;
+
+    while tracker.transferred < file_meta.size {
+        let remaining = file_meta.size - tracker.transferred;
+        let decompressed;
+        let chunk = if file_meta.compressed {
+            decompressed = tokio::time::timeout(read_timeout, read_compressed_chunk(stream, remaining))
+                .await
+                .map_err(|_| idle_timeout())??;
+            &decompressed[..]
+        } else {
+            let bytes_to_read = std::cmp::min(buffer.len() as u64, remaining) as usize;
+            let bytes_read = tokio::time::timeout(read_timeout, stream.read(&mut buffer[..bytes_to_read]))
+                .await
+                .map_err(|_| idle_timeout())??;
+            if bytes_read == 0 {
+                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Connection closed prematurely")) as Box<dyn Error + Send + Sync>);
+            }
+            &buffer[..bytes_read]
+        };
+        file.write_all(chunk).await?;
+        hasher.update(chunk);
+        events.bytes_transferred(chunk.len() as u64);
+
+        if tracker.advance(chunk.len() as u64) {
+            events.progress(tracker.progress(None, Some(file_meta.path.clone())));
+        }
+        if let Some(checkpointer) = checkpointer.as_deref_mut() {
+            checkpointer.update(tracker.transferred, &hasher).await;
+        }
+    }
+    if tracker.never_reported() {
+        events.progress(tracker.progress(None, Some(file_meta.path.clone())));
+    }
+    file.flush().await?;
+
+    let trailer: FileMetadata = tokio::time::timeout(read_timeout, read_json(stream))
+        .await
+        .map_err(|_| idle_timeout())??;
+    let digest = format!("{:x}", hasher.finalize());
+    Ok(trailer.sha256.as_deref() == Some(digest.as_str()))
+}
+
+/// Receives a `streamed` file into `file` until its empty chunk. Returns whether the
+/// checksum in the trailer matched.
+async fn receive_streamed_file<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    file: &mut tokio::fs::File,
+    file_meta: &FileMetadata,
+    read_timeout: Duration,
+    events: &impl TransferEvents,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    // The size is only what the sender expected, 0 when it didn't know
+    let mut tracker = ProgressTracker::streamed(Some(file_meta.size).filter(|size| *size > 0));
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; MAX_STREAM_CHUNK];
+    loop {
+        let len = tokio::time::timeout(read_timeout, stream.read_u32())
+            .await
+            .map_err(|_| idle_timeout())?? as usize;
+        if len == 0 {
+            break;
+        }
+        if len > MAX_STREAM_CHUNK {
+            return Err(format!("Stream chunk too large: {} bytes", len).into());
+        }
+        tokio::time::timeout(read_timeout, stream.read_exact(&mut buffer[..len]))
+            .await
+            .map_err(|_| idle_timeout())??;
+        file.write_all(&buffer[..len]).await?;
+        hasher.update(&buffer[..len]);
+        events.bytes_transferred(len as u64);
+        if tracker.advance(len as u64) {
+            events.progress(tracker.progress(None, Some(file_meta.path.clone())));
+        }
+    }
+    if tracker.never_reported() {
+        events.progress(tracker.progress(None, Some(file_meta.path.clone())));
+    }
+    file.flush().await?;
+
+    let trailer: FileMetadata = tokio::time::timeout(read_timeout, read_json(stream))
+        .await
+        .map_err(|_| idle_timeout())??;
+    let digest = format!("{:x}", hasher.finalize());
+    Ok(trailer.size == tracker.transferred && trailer.sha256.as_deref() == Some(digest.as_str()))
+}
+
+/// Receives a file from `send_encrypted_file` into `file`, decrypting it with `key`.
+/// Returns whether the trailer matched. A chunk that doesn't decrypt is an error, it was
+/// encrypted with another password or tampered with.
+async fn receive_encrypted_file<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    file: &mut tokio::fs::File,
+    file_meta: &FileMetadata,
+    key: &chacha20poly1305::Key,
+    read_timeout: Duration,
+    events: &impl TransferEvents,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let nonce = file_meta.encryption.as_ref().and_then(encryption_nonce).ok_or("Invalid encryption nonce")?;
+    let mut decryptor = Some(DecryptorBE32::<ChaCha20Poly1305>::new(key, GenericArray::from_slice(&nonce)));
+    let mut tracker = ProgressTracker::streamed(Some(file_meta.size));
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; ENCRYPTED_CHUNK_LEN + ENCRYPTION_TAG_LEN];
+    loop {
+        let len = tokio::time::timeout(read_timeout, stream.read_u32())
+            .await
+            .map_err(|_| idle_timeout())?? as usize;
+        if len == 0 {
+            break;
+        }
+        if len > buffer.len() {
+            return Err(format!("Encrypted chunk too large: {} bytes", len).into());
+        }
+        tokio::time::timeout(read_timeout, stream.read_exact(&mut buffer[..len]))
+            .await
+            .map_err(|_| idle_timeout())??;
+        hasher.update(&buffer[..len]);
+        let mut current = decryptor.take().ok_or("Encrypted data goes on past its last chunk")?;
+        let opened = if len == buffer.len() {
+            let opened = current.decrypt_next(&buffer[..len]);
+            decryptor = Some(current);
+            opened
+        } else {
+            current.decrypt_last(&buffer[..len])
+        }.map_err(|_| "Decrypting failed: wrong password or tampered data")?;
+        file.write_all(&opened).await?;
+        events.bytes_transferred(opened.len() as u64);
+        if tracker.advance(opened.len() as u64) {
+            events.progress(tracker.progress(None, Some(file_meta.path.clone())));
+        }
+    }
+    // Without its last chunk the file was cut short
+    if decryptor.is_some() {
+        return Err("Encrypted data ended early".into());
+    }
+    if tracker.never_reported() {
+        events.progress(tracker.progress(None, Some(file_meta.path.clone())));
+    }
+    file.flush().await?;
+
+    let trailer: FileMetadata = tokio::time::timeout(read_timeout, read_json(stream))
+        .await
+        .map_err(|_| idle_timeout())??;
+    let digest = format!("{:x}", hasher.finalize());
+    Ok(trailer.size == tracker.transferred && trailer.sha256.as_deref() == Some(digest.as_str()))
+}
+
+/// Receives a file sent in ranges: registers `slot` for the range connections, tells the
+/// sender to open them, and collects their `events` until all are done. The trailer then
+/// follows on the batch connection as usual.
+async fn receive_ranged_file<S: AsyncRead + AsyncWrite + Unpin>(
+    progress: &AppEvents<'_>,
+    stream: &mut S,
+    file: &mut tokio::fs::File,
+    key: (String, usize),
+    slot: RangeSlot,
+    mut events: mpsc::UnboundedReceiver<RangeEvent>,
+    file_meta: &FileMetadata,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    // Ranges are written wherever they land, so the file needs its full length up front
+    file.set_len(file_meta.size).await?;
+    let slots = progress.app.state::<RangeSlots>().inner().clone();
+    slots.lock().unwrap().insert(key.clone(), slot);
+
+    let read_timeout = receive_timeout(progress.app);
+    let result: Result<bool, Box<dyn Error + Send + Sync>> = async {
+        stream.write_all(&[1]).await?;
+        stream.flush().await?;
+
+        let mut tracker = ProgressTracker::new(file_meta.size, 0);
+        let mut remaining_ranges = file_meta.ranges.len();
+        while remaining_ranges > 0 {
+            let event = tokio::time::timeout(read_timeout, events.recv())
+                .await
+                .map_err(|_| idle_timeout())?;
+            match event {
+                Some(RangeEvent::Received(bytes)) => {
+                    progress.bytes_transferred(bytes);
+                    if tracker.advance(bytes) {
+                        progress.progress(tracker.progress(None, Some(file_meta.path.clone())));
+                    }
+                }
+                Some(RangeEvent::Done(Ok(()))) => remaining_ranges -= 1,
+                Some(RangeEvent::Done(Err(e))) => {
+                    return Err(std::io::Error::new(e.kind(), format!("Receiving a range failed: {}", e)).into());
+                }
+                None => return Err("Range connections went away".into()),
+            }
+        }
+
+        let trailer: FileMetadata = tokio::time::timeout(read_timeout, read_json(stream))
+            .await
+            .map_err(|_| idle_timeout())??;
+        file.seek(std::io::SeekFrom::Start(0)).await?;
+        let mut hasher = Sha256::new();
+        hash_prefix(file, file_meta.size, &mut hasher, &mut vec![0; 1024 * 1024]).await?;
+        let digest = format!("{:x}", hasher.finalize());
+        Ok(trailer.sha256.as_deref() == Some(digest.as_str()))
+    }.await;
+
+    slots.lock().unwrap().remove(&key);
+    result
+}
+
+/// Parses the URL of a `TransferRequest::Url`. Only http and https, other schemes could
+/// have the receiver read its own files or reach things a download has no business with.
+fn validate_fetch_url(url: &str) -> Result<url::Url, String> {
+    let parsed = url::Url::parse(url.trim()).map_err(|e| format!("{}: {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("{}: only http and https URLs can be sent", url));
+    }
+    Ok(parsed)
+}
+
+/// What a file downloaded from `url` is saved as: the name the sender suggested, else the
+/// last segment of the URL's path, else its host. Always a single file name.
+fn fetch_file_name(url: &url::Url, suggested: Option<&str>) -> String {
+    let name = suggested.map(str::trim).filter(|name| !name.is_empty()).map(str::to_string)
+        .or_else(|| {
+            let segment = url.path_segments()?.next_back().filter(|segment| !segment.is_empty())?;
+            Some(percent_encoding::percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        })
+        .or_else(|| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    // A suggested name with folders in it only keeps its last part
+    let name = portable_path(name.rsplit(['/', '\\']).next().unwrap_or_default());
+    if safe_relative_path(&name).is_some() {
+        name
+    } else {
+        FETCH_FALLBACK_NAME.to_string()
+    }
+}
+
+/// Offers the file behind a `TransferRequest::Url` and, once accepted, downloads it into
+/// the download directory. The sender is answered before the download starts, it has
+/// nothing more to do with it.
+#[allow(clippy::too_many_arguments)]
+async fn receive_url<S: AsyncRead + AsyncWrite + Unpin>(
+    app: &AppHandle,
+    stream: &mut S,
+    offers: &FileOffers,
+    hello: &Hello,
+    remote_addr: std::net::SocketAddr,
+    sender_name: String,
+    unknown_sender: bool,
+    (url, name): (String, Option<String>),
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let url = match validate_fetch_url(&url) {
+        Ok(url) => url,
+        Err(reason) => {
+            write_verdict(stream, 0).await?;
+            return Err(reason.into());
+        }
+    };
+    let offer_id = Uuid::new_v4().to_string();
+    let peer = peer_address(&remote_addr);
+    let mut file_meta = streamed_metadata(&fetch_file_name(&url, name.as_deref()), None);
+    if let Err(reason) = check_storage(app) {
+        warn!(offer_id = %offer_id, reason = %reason, "Download directory unusable, declining URL offer");
+        write_verdict(stream, STORAGE_UNAVAILABLE).await?;
+        app.emit("storage-unavailable", StorageUnavailable {
+            offer_id: offer_id.clone(),
+            from: sender_name,
+            reason,
+        }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+        record_transfer(app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, &file_meta, TransferStatus::Failed));
+        return Ok(());
+    }
+    // The size is only known once the download starts, it's checked against the limit then
+    if let Some(reason) = auto_reject_reason(app, std::slice::from_ref(&file_meta), 0) {
+        info!(offer_id = %offer_id, ?reason, "URL offer auto-rejected");
+        write_verdict(stream, 0).await?;
+        app.emit("file-offer-auto-rejected", OfferAutoRejected {
+            id: offer_id.clone(),
+            from: sender_name,
+            total_size: 0,
+            reason,
+        }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+        record_transfer(app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, &file_meta, TransferStatus::Rejected));
+        return Ok(());
+    }
+
+    // Never auto-accepted, the user should see where the file comes from first
+    let (tx, rx) = oneshot::channel();
+    offers.lock().unwrap().insert(offer_id.clone(), tx);
+    let pending = PendingOffer { offers, id: &offer_id };
+    info!(offer_id = %offer_id, url = %url, "URL offer received");
+    app.emit("file-offer", BatchFileOfferPayload {
+        id: offer_id.clone(),
+        from: sender_name.clone(),
+        address: remote_addr.ip().to_string(),
+        files: vec![file_meta.clone()],
+        total_size: 0,
+        unknown_sender,
+        url: Some(url.to_string()),
+    }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+    app.emit("notify", OfferNotification::new(&offer_id, &hello.sender_id, sender_name.clone(), std::slice::from_ref(&file_meta), 0))
+        .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+    let mut probe = [0; 1];
+    let decision = tokio::select! {
+        answered = tokio::time::timeout(offer_timeout(app), rx) => match answered {
+            Ok(decision) => decision.ok(),
+            Err(_) => {
+                app.emit("offer-expired", OfferExpired { id: offer_id.clone() })
+                    .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+                None
+            }
+        },
+        _ = stream.read(&mut probe) => {
+            info!(offer_id = %offer_id, "URL offer withdrawn");
+            app.emit("offer-withdrawn", OfferExpired { id: offer_id.clone() })
+                .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            record_transfer(app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, &file_meta, TransferStatus::Failed));
+            return Ok(());
+        }
+    };
+    drop(pending);
+    if !matches!(decision, Some(OfferDecision::Accepted { .. })) {
+        write_verdict(stream, 0).await?;
+        record_transfer(app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, &file_meta, TransferStatus::Rejected));
+        return Ok(());
+    }
+    write_verdict(stream, 1).await?;
+    stream.shutdown().await?;
+
+    let receive_dir = receive_dir(app, &resolve_download_dir(app)?, &hello.sender_id, &peer);
+    let target_path = receive_dir.join(&file_meta.path);
+    // Not `part_path`, that's where a batch sending the same name keeps what it can resume from
+    let part_path = target_path.with_file_name(format!("{}.{}.part", file_meta.path, offer_id));
+    let batch_progress = BatchTracker::new(&offer_id, std::slice::from_ref(&file_meta));
+    let events = AppEvents { app, recipient: None, offer_id: Some(&offer_id), batch: &batch_progress };
+    let registered = RegisteredTransfer::new(app, &offer_id, TransferDirection::Received, &peer);
+    let started = Instant::now();
+    let fetching = async {
+        tokio::select! {
+            fetched = fetch_url(app, &url, &part_path, &file_meta.path, &events) => fetched,
+            _ = registered.control.cancel.cancelled() => Err(TransferError::Cancelled.to_string().into()),
+        }
+    };
+    let fetched = match fetching.await {
+        Ok(received_bytes) => finalize_part(&part_path, &target_path).await
+            .map(|saved_path| (saved_path, received_bytes))
+            .map_err(Into::into),
+        Err(e) => Err(e),
+    };
+    let result = match fetched {
+        Ok((saved_path, received_bytes)) => {
+            info!(offer_id = %offer_id, url = %url, size = received_bytes, "URL downloaded");
+            if app.state::<AppState>().0.lock().unwrap().settings.quarantine_received {
+                if let Err(e) = mark_as_downloaded(&saved_path).await {
+                    warn!(file = %file_meta.path, error = %e, "Marking file as downloaded failed");
+                }
+            }
+            file_meta.size = received_bytes;
+            app.emit("transfer-complete", FileTransferComplete {
+                recipient: None,
+                offer_id: Some(offer_id.clone()),
+                file_path: None,
+                file_name: Some(file_meta.path.clone()),
+                saved_path: Some(saved_path),
+                original_name: None,
+                timing: Some(TransferTiming::since(started, received_bytes)),
+            }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            record_transfer(app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, &file_meta, TransferStatus::Completed));
+            BatchResult::new(1, 0)
+        }
+        Err(e) => {
+            warn!(offer_id = %offer_id, url = %url, reason = %e, "URL download failed");
+            let _ = tokio::fs::remove_file(&part_path).await;
+            app.emit("transfer-failed", FileTransferFailed {
+                recipient: None,
+                offer_id: Some(offer_id.clone()),
+                file_path: None,
+                file_name: Some(file_meta.path.clone()),
+                reason: e.to_string(),
+                kind: is_disk_full(e.as_ref()).then_some(FailureKind::DiskFull),
+            }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            record_transfer(app, TransferRecord::new(&offer_id, TransferDirection::Received, &peer, &file_meta, TransferStatus::Failed));
+            BatchResult::new(0, 1)
+        }
+    };
+    batch_progress.file_settled(app, &file_meta);
+    app.emit("batch-received", BatchReceived {
+        offer_id,
+        from: sender_name,
+        received: usize::from(result == BatchResult::Complete),
+        skipped: 0,
+        declined: 0,
+        failed: usize::from(result == BatchResult::Failed),
+        result,
+    }).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+    Ok(())
+}
+
+/// Downloads `url` into `part_path`, refusing anything over `auto_reject_over_bytes` or
+/// the free space. Returns how many bytes it got. Running out of space fails with a
+/// `StorageFull` error, see `is_disk_full`.
+async fn fetch_url(
+    app: &AppHandle,
+    url: &url::Url,
+    part_path: &std::path::Path,
+    name: &str,
+    events: &impl TransferEvents,
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let read_timeout = receive_timeout(app);
+    let limit = app.state::<AppState>().0.lock().unwrap().settings.auto_reject_over_bytes;
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("Kitsunet-Share/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(URL_FETCH_CONNECT_TIMEOUT)
+        .build()?;
+    let mut response = client.get(url.clone()).send().await
+        .and_then(reqwest::Response::error_for_status)?;
+    let size = response.content_length();
+    if let (Some(size), Some(limit)) = (size, limit) {
+        if size > limit {
+            return Err(format!("File is {} bytes, over the {} byte limit", size, limit).into());
+        }
+    }
+    // The peer's folder with `organize_by_peer` may not be there yet
+    if let Some(parent) = part_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let not_enough_space = |needed: u64, available: u64| std::io::Error::new(
+        std::io::ErrorKind::StorageFull,
+        format!("Not enough disk space: {} bytes needed, {} available", needed, available),
+    );
+    let available = fs2::available_space(part_path.parent().unwrap_or(part_path)).ok();
+    if let (Some(size), Some(available)) = (size, available) {
+        if size > available {
+            return Err(not_enough_space(size, available).into());
+        }
+    }
+
+    let _stats = app.state::<Arc<TransferStats>>().start(size.unwrap_or(0));
+    let mut file = tokio::fs::OpenOptions::new().write(true).create_new(true).open(part_path).await?;
+    let mut tracker = ProgressTracker::streamed(size);
+    let mut received = 0u64;
+    // A server that stops sending would otherwise hold the download open forever
+    while let Some(chunk) = tokio::time::timeout(read_timeout, response.chunk()).await
+        .map_err(|_| format!("Server sent nothing for {} seconds", read_timeout.as_secs()))??
+    {
+        received += chunk.len() as u64;
+        // The header may be missing or lie
+        if let Some(limit) = limit.filter(|limit| received > *limit) {
+            return Err(format!("File is over the {} byte limit", limit).into());
+        }
+        // Stopped before the drive fills up, not once a write fails on a full one
+        if let Some(available) = available.filter(|available| received > *available) {
+            return Err(not_enough_space(received, available).into());
+        }
+        file.write_all(&chunk).await?;
+        events.bytes_transferred(chunk.len() as u64);
+        if tracker.advance(chunk.len() as u64) {
+            events.progress(tracker.progress(None, Some(name.to_string())));
+        }
+    }
+    if tracker.never_reported() {
+        events.progress(tracker.progress(None, Some(name.to_string())));
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    Ok(received)
+}
+
+/// Accepts a `TransferRequest::Calibrate`, then reads and drops its burst and confirms it.
+async fn receive_calibration<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    len: u64,
+    read_timeout: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if len > MAX_CALIBRATION_LEN {
+        write_verdict(stream, 0).await?;
+        return Err(format!("Calibration burst too large: {} bytes", len).into());
+    }
+    write_verdict(stream, 1).await?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = std::cmp::min(buffer.len() as u64, remaining) as usize;
+        let bytes_read = tokio::time::timeout(read_timeout, stream.read(&mut buffer[..chunk]))
+            .await
+            .map_err(|_| idle_timeout())??;
+        if bytes_read == 0 {
+            return Err("Connection closed during calibration".into());
+        }
+        remaining -= bytes_read as u64;
+    }
+    write_verdict(stream, 1).await?;
+    Ok(())
+}
+
+/// Handles a range connection: writes the range into the `.part` file of the batch it
+/// belongs to, if that batch is waiting for it and the connection comes from its sender.
+async fn receive_range<S: AsyncRead + AsyncWrite + Unpin>(
+    app: &AppHandle,
+    stream: &mut S,
+    hello: &Hello,
+    sender_ip: IpAddr,
+    key: (String, usize),
+    range_index: usize,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let claimed = {
+        let slots = app.state::<RangeSlots>();
+        let mut slots = slots.lock().unwrap();
+        slots.get_mut(&key)
+            .filter(|slot| slot.sender_id == hello.sender_id && slot.sender_ip == sender_ip)
+            .and_then(|slot| {
+                let range = *slot.ranges.get(range_index)?;
+                let claimed = slot.claimed.get_mut(range_index).filter(|claimed| !**claimed)?;
+                *claimed = true;
+                Some((slot.part_path.clone(), range, slot.events.clone()))
+            })
+    };
+    let Some((part_path, range, events)) = claimed else {
+        stream.write_all(&[0]).await?;
+        stream.flush().await?;
+        return Err("Unexpected range connection".into());
+    };
+    stream.write_all(&[1]).await?;
+    stream.flush().await?;
+
+    let read_timeout = receive_timeout(app);
+    let result: Result<(), Box<dyn Error + Send + Sync>> = async {
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(&part_path).await?;
+        file.seek(std::io::SeekFrom::Start(range.offset)).await?;
+        let mut buffer = vec![0; transfer_buffer_len(app)];
+        let mut remaining = range.len;
+        while remaining > 0 {
+            let bytes_to_read = std::cmp::min(buffer.len() as u64, remaining) as usize;
+            let bytes_read = tokio::time::timeout(read_timeout, stream.read(&mut buffer[..bytes_to_read]))
+                .await
+                .map_err(|_| idle_timeout())??;
+            if bytes_read == 0 {
+                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Connection closed prematurely")) as Box<dyn Error + Send + Sync>);
+            }
+            file.write_all(&buffer[..bytes_read]).await?;
+            remaining -= bytes_read as u64;
+            let _ = events.send(RangeEvent::Received(bytes_read as u64));
+        }
+        file.flush().await?;
+        Ok(())
+    }.await;
+
+    let _ = events.send(RangeEvent::Done(result.as_ref().map(|_| ()).map_err(|e| {
+        let kind = e.downcast_ref::<std::io::Error>().map_or(std::io::ErrorKind::Other, |e| e.kind());
+        std::io::Error::new(kind, e.to_string())
+    })));
+    result
+}
+
+async fn file_receiver_task(
+    app: AppHandle,
+    offers: FileOffers,
+    acceptor: tokio_rustls::TlsAcceptor,
+    interface: Option<BindInterface>,
+) {
+    let max_incoming = app.state::<AppState>().0.lock().unwrap().settings.max_incoming_transfers;
+    let semaphore = Arc::new(Semaphore::new(max_incoming.max(1)));
+    let (mut shutdown, tasks) = {
+        let shutdown = app.state::<Shutdown>();
+        (shutdown.signal.subscribe(), shutdown.incoming.clone())
+    };
+    let mut port = app.state::<Ports>().transfer.subscribe();
+    let bind_ip = interface.as_ref().map_or(Ipv4Addr::UNSPECIFIED, |interface| interface.ip);
+
+    // Whatever a previous run left unfinished
+    if let Ok(download_dir) = resolve_download_dir(&app) {
+        let found = tokio::task::spawn_blocking(move || {
+            let mut found = vec![];
+            find_resumable_transfers(&download_dir, RESUMABLE_SCAN_DEPTH, &mut found);
+            found
+        }).await.unwrap_or_default();
+        if !found.is_empty() {
+            info!(count = found.len(), "Found interrupted incoming transfers");
+        }
+        *app.state::<ResumableParts>().0.lock().unwrap() = found;
+    }
+
+    // Runs once per port setting, transfers accepted on the old port keep going
+    loop {
+        let wanted_port = *port.borrow_and_update();
+        let (listener, transfer_port) = match bind_transfer_listener(bind_ip, wanted_port).await {
+            Ok(bound) => bound,
+            Err(e) => {
+                error!(port = wanted_port, error = %e, "Failed to bind TCP listener");
+                app.state::<AppState>().0.lock().unwrap().listen_status = ListenStatus {
+                    bound: false,
+                    port: None,
+                    error: Some(e.to_string()),
+                };
+                tokio::select! {
+                    _ = shutdown.recv() => return,
+                    _ = port.changed() => continue,
+                }
+            }
+        };
+        if transfer_port != wanted_port {
+            warn!(port = wanted_port, fallback = transfer_port, "Transfer port taken, listening on a fallback");
+        }
+        app.state::<AppState>().0.lock().unwrap().listen_status = ListenStatus {
+            bound: true,
+            port: Some(transfer_port),
+            error: None,
+        };
+        // IPv6 is optional: the machine may have it disabled entirely. It's left out when
+        // receiving on a single interface, which is picked by its IPv4 address.
+        let listener_v6 = match interface {
+            Some(_) => None,
+            None => match bind_v6_listener(transfer_port) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    warn!(error = %e, "IPv6 file transfer listener unavailable");
+                    None
+                }
+            },
+        };
+        info!(port = transfer_port, "Listening for transfers");
+        loop {
+            let accepted = tokio::select! {
+                // Stop accepting, transfers already running get the grace period in `main`
+                _ = shutdown.recv() => return,
+                _ = port.changed() => break,
+                accepted = listener.accept() => accepted,
+                accepted = accept_optional(listener_v6.as_ref()) => accepted,
+            };
+            if let Ok((stream, remote_addr)) = accepted {
+                if !app.state::<AppState>().0.lock().unwrap().is_allowed_ip(remote_addr.ip()) {
+                    warn!(remote = %remote_addr, "Rejected transfer connection from outside the allowed networks");
+                    continue;
+                }
+                // Reported once when it started, anything more would be the flood itself
+                if app.state::<OfferLimiter>().is_throttled(remote_addr.ip(), Instant::now()) {
+                    debug!(remote = %remote_addr, "Refused transfer connection from a flooding sender");
+                    continue;
+                }
+                debug!(remote = %remote_addr, "Accepted transfer connection");
+                enable_keepalive(&app, &stream);
+                let app_clone = app.clone();
+                let offers_clone = offers.clone();
+                let acceptor = acceptor.clone();
+                let semaphore = semaphore.clone();
+                tasks.spawn(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => handle_incoming_batch(app_clone, stream, remote_addr, offers_clone, semaphore).await,
+                        Err(e) => warn!(remote = %remote_addr, error = %e, "TLS handshake failed"),
+                    }
+                });
+            }
+        }
+    }
+}
+
+async fn accept_optional(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Binds `port`, or the first free one of the `TRANSFER_PORT_FALLBACKS` after it. Fails
+/// with the error for `port` if none is free.
+async fn bind_transfer_listener(ip: Ipv4Addr, port: u16) -> std::io::Result<(TcpListener, u16)> {
+    let mut first_error = None;
+    for candidate in (port..=port.saturating_add(TRANSFER_PORT_FALLBACKS)).filter(|candidate| *candidate != 0) {
+        match TcpListener::bind((ip, candidate)).await {
+            Ok(listener) => return Ok((listener, candidate)),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "No port to bind")))
+}
+
+/// Binds an IPv6-only TCP listener so it doesn't clash with the IPv4 one on the same port.
+fn bind_v6_listener(port: u16) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds the IPv4 discovery socket, to `interface` only if one is given.
+fn bind_discovery_socket(port: u16, interface: Option<&BindInterface>) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_broadcast(true)?;
+    // Linux doesn't deliver broadcasts to a socket bound to a unicast address, so there
+    // the socket is tied to the device instead
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let bind_ip = {
+        if let Some(interface) = interface {
+            socket.bind_device(Some(interface.name.as_bytes()))?;
+        }
+        Ipv4Addr::UNSPECIFIED
+    };
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let bind_ip = interface.map_or(Ipv4Addr::UNSPECIFIED, |interface| interface.ip);
+    socket.bind(&SocketAddr::from((bind_ip, port)).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Binds the IPv6 discovery socket and joins the link-local all-nodes multicast group on
+/// `only_index`, or on every interface that has an IPv6 address.
+fn bind_v6_discovery_socket(port: u16, only_index: Option<u32>) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    let indexes = match only_index {
+        Some(index) => vec![index],
+        None => v6_interface_indexes(),
+    };
+    for index in indexes {
+        if let Err(e) = socket.join_multicast_v6(&DISCOVERY_MULTICAST_V6, index) {
+            warn!(interface = index, error = %e, "Failed to join IPv6 multicast group");
+        }
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Indexes of the non-loopback interfaces that have at least one IPv6 address.
+fn v6_interface_indexes() -> Vec<u32> {
+    let mut indexes = vec![];
+    if let Ok(ifaces) = NetworkInterface::show() {
+        for iface in ifaces {
+            let has_v6 = iface.addr.iter().any(|addr| matches!(addr.ip(), IpAddr::V6(ip) if !ip.is_loopback()));
+            if has_v6 && !indexes.contains(&iface.index) {
+                indexes.push(iface.index);
+            }
+        }
+    }
+    indexes
+}
+
+async fn recv_optional(socket: Option<&UdpSocket>, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Formats a peer address so link-local IPv6 peers keep their scope id (e.g. `fe80::1%3`),
+/// which is needed to connect back to them.
+fn peer_address(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(v6) if v6.scope_id() != 0 && v6.ip().is_unicast_link_local() => {
+            format!("{}%{}", v6.ip(), v6.scope_id())
+        }
+        _ => addr.ip().to_string(),
+    }
+}
+
+/// Builds a `host:port` string for a peer address, bracketing IPv6 addresses.
+fn peer_socket_addr(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+async fn send_presence(socket: &UdpSocket, bytes: &[u8], target_addr: &str) {
+    if let Err(e) = socket.send_to(bytes, target_addr).await {
+        warn!(target = target_addr, error = %e, "Failed to send presence");
+    }
+}
+
+/// All IP addresses assigned to this machine, used to ignore our own announcements.
+fn local_ips() -> HashSet<IpAddr> {
+    let mut local_ips = HashSet::new();
+    if let Ok(ifaces) = NetworkInterface::show() {
+        for iface in ifaces {
+            for addr in &iface.addr {
+                local_ips.insert(addr.ip());
+            }
+        }
+    }
+    local_ips
+}
+
+/// Merges a presence datagram into the peer list, returning whether it's from a new peer.
+fn handle_presence(app_handle: &AppHandle, state: &AppState, bytes: &[u8], remote_addr: SocketAddr) -> serde_json::Result<bool> {
+    if local_ips().contains(&remote_addr.ip()) || !state.0.lock().unwrap().is_allowed_ip(remote_addr.ip()) {
+        return Ok(false);
+    }
+
+    let Message::Presence(presence) = serde_json::from_slice(bytes)?;
+    let address = peer_address(&remote_addr);
+    let verified = {
+        let state = state.0.lock().unwrap();
+        if presence.id.as_deref() == Some(state.device_id.as_str()) || state.is_blocked(presence.id.as_deref(), &address) {
+            return Ok(false);
+        }
+        match state.settings.shared_secret.as_deref().map(|secret| presence.signature(secret)) {
+            None => None,
+            Some(PresenceSignature::Valid) => Some(true),
+            Some(PresenceSignature::Invalid) => {
+                debug!(remote = %remote_addr, username = %presence.username, "Dropped presence with a bad signature");
+                return Ok(false);
+            }
+            // Someone without the secret claiming the id of a peer that proved it has it
+            Some(PresenceSignature::Missing) if state.peers.iter()
+                .any(|peer| Some(&peer.id) == presence.id.as_ref() && peer.verified == Some(true)) =>
+            {
+                debug!(remote = %remote_addr, username = %presence.username, "Dropped unsigned presence for a verified peer");
+                return Ok(false);
+            }
+            Some(PresenceSignature::Missing) => Some(false),
+        }
+    };
+    let new_peer = Peer {
+        id: presence.id.unwrap_or_else(|| address.clone()),
+        username: presence.username,
+        address,
+        os: presence.os,
+        device_type: presence.device_type,
+        alias: None,
+        last_seen_ms_ago: None,
+        last_seen: Some(Instant::now()),
+        missed_beacons: 0,
+        manual: false,
+        transfer_port: presence.transfer_port.unwrap_or(DEFAULT_TRANSFER_PORT),
+        status: presence.status,
+        capabilities: Some(presence.capabilities),
+        protocol_version: presence.protocol_version,
+        verified,
+    };
+
+    let (is_new, changed) = {
+        let mut state = state.0.lock().unwrap();
+        (!state.peers.contains(&new_peer), state.upsert_peer(new_peer.clone()))
+    };
+    if is_new {
+        info!(peer_id = %new_peer.id, address = %new_peer.address, username = %new_peer.username, "Peer discovered");
+        emit_peer_joined(app_handle, &new_peer);
+    }
+    if changed {
+        emit_peers_updated(app_handle);
+    }
+    Ok(is_new)
+}
+
+/// Largest presence datagram parsed. Real ones are a few hundred bytes, bigger ones are
+/// dropped rather than parsed truncated.
+const MAX_PRESENCE_LEN: usize = 4096;
+/// Datagrams one address gets parsed per second, the rest are dropped unread
+const MAX_PRESENCE_PER_SECOND: u32 = 20;
+/// Source addresses `PresenceFilter` tracks before it forgets the quiet ones
+const MAX_PRESENCE_SOURCES: usize = 1024;
+
+/// Rate limits presence parsing per source address and counts the datagrams it drops,
+/// which `discovery_task` logs once per tick.
+#[derive(Default)]
+struct PresenceFilter {
+    /// Source address -> start of its current one second window and datagrams in it
+    windows: HashMap<IpAddr, (Instant, u32)>,
+    oversized: u64,
+    rate_limited: u64,
+    malformed: u64,
+}
+
+impl PresenceFilter {
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(1);
+        // Spoofed sources could grow the map without bound
+        if self.windows.len() >= MAX_PRESENCE_SOURCES {
+            self.windows.retain(|_, (start, _)| now.duration_since(*start) < window);
+        }
+        let (start, count) = self.windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= window {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= MAX_PRESENCE_PER_SECOND
+    }
+
+    /// Hands a datagram of `len` bytes read into `buf` to `handle_presence`, unless it's
+    /// too big or its sender is over its rate. Returns whether it came from a new peer.
+    fn receive(&mut self, app_handle: &AppHandle, state: &AppState, buf: &[u8], len: usize, remote_addr: SocketAddr) -> bool {
+        state.0.lock().unwrap().presence_heard_from(remote_addr.ip());
+        if !self.allow(remote_addr.ip()) {
+            self.rate_limited += 1;
+        } else if len > MAX_PRESENCE_LEN {
+            self.oversized += 1;
+        } else {
+            match handle_presence(app_handle, state, &buf[..len], remote_addr) {
+                Ok(is_new) => return is_new,
+                Err(e) => {
+                    self.malformed += 1;
+                    debug!(remote = %remote_addr, error = %e, "Malformed presence");
+                }
+            }
+        }
+        false
+    }
+
+    /// Logs what was dropped since the last report.
+    fn report(&mut self) {
+        if self.oversized + self.rate_limited + self.malformed > 0 {
+            warn!(oversized = self.oversized, rate_limited = self.rate_limited, malformed = self.malformed, "Dropped presence datagrams");
+        }
+        self.oversized = 0;
+        self.rate_limited = 0;
+        self.malformed = 0;
+    }
+}
+
+/// Sends one presence announcement to the configured broadcast targets, if broadcasting is on.
+async fn announce(state: &AppState, sockets: &DiscoverySockets) {
+    let (message, broadcasting_enabled, broadcast_addresses, seed_peers, allowed) = {
+        let state = state.0.lock().unwrap();
+        (
+            Message::Presence(Presence::local(&state)),
+            state.settings.broadcasting_enabled,
+            state.settings.all_broadcast_addresses(),
+            state.settings.seed_peers.clone(),
+            parse_cidrs(&state.settings.allowed_cidrs).unwrap_or_default(),
+        )
+    };
+
+    if !broadcasting_enabled {
+        return;
+    }
+    let bytes = serde_json::to_vec(&message).unwrap();
+    for target in all_broadcast_targets(&broadcast_addresses, &allowed, sockets.port) {
+        let socket = match (target.v6, &sockets.v6) {
+            (false, _) => &sockets.v4,
+            (true, Some(socket_v6)) => socket_v6,
+            (true, None) => continue,
+        };
+        send_presence(socket, &bytes, &target.target).await;
+    }
+    // Seeds that don't run us, or aren't up, just never answer
+    let seeds = seed_peers.iter()
+        .filter_map(|address| parse_seed_peer(address.trim(), sockets.port))
+        .filter(|target| ip_allowed(&allowed, target.ip()));
+    for target in seeds {
+        let socket = match (target.is_ipv6(), &sockets.v6) {
+            (false, _) => &sockets.v4,
+            (true, Some(socket_v6)) => socket_v6,
+            (true, None) => continue,
+        };
+        send_presence(socket, &bytes, &target.to_string()).await;
+    }
+}
+
+/// Announces us straight back to a peer we just discovered, so one that reached us from
+/// outside our broadcast domain, such as through its `seed_peers`, hears from us too.
+async fn answer_presence(state: &AppState, socket: &UdpSocket, remote_addr: SocketAddr) {
+    let message = {
+        let state = state.0.lock().unwrap();
+        if !state.settings.broadcasting_enabled {
+            return;
+        }
+        Message::Presence(Presence::local(&state))
+    };
+    send_presence(socket, &serde_json::to_vec(&message).unwrap(), &remote_addr.to_string()).await;
+}
+
+/// Where presence goes for the `broadcast_address` setting, restricted to the allowed
+/// networks.
+fn broadcast_targets(broadcast_address: &str, allowed: &[ipnet::IpNet], port: u16) -> Vec<BroadcastTarget> {
+    let ifaces = network_interface::NetworkInterface::show().unwrap_or_default();
+    let mut targets = vec![];
+    if broadcast_address == "255.255.255.255" {
+        // "All" mode: broadcast on all interfaces on an allowed network
+        for iface in &ifaces {
+            for addr in &iface.addr {
+                if let Some(IpAddr::V4(broadcast)) = addr.broadcast().filter(|_| ip_allowed(allowed, addr.ip())) {
+                    targets.push(BroadcastTarget {
+                        interface: iface.name.clone(),
+                        target: format!("{}:{}", broadcast, port),
+                        v6: false,
+                    });
+                }
+            }
+        }
+        // ... and multicast on every IPv6 link with an address on one
+        for index in v6_interface_indexes() {
+            let Some(iface) = ifaces.iter().find(|iface| iface.index == index) else { continue };
+            if iface.addr.iter().any(|addr| ip_allowed(allowed, addr.ip())) {
+                targets.push(BroadcastTarget {
+                    interface: iface.name.clone(),
+                    target: peer_socket_addr(&format!("{}%{}", DISCOVERY_MULTICAST_V6, index), port),
+                    v6: true,
+                });
+            }
+        }
+    } else if broadcast_address.contains(':') {
+        // Specific IPv6 interface mode: multicast to the given group and scope
+        let scope = broadcast_address.split_once('%').and_then(|(_, scope)| scope.parse::<u32>().ok());
+        targets.push(BroadcastTarget {
+            interface: ifaces.iter()
+                .find(|iface| Some(iface.index) == scope)
+                .map(|iface| iface.name.clone())
+                .unwrap_or_default(),
+            target: peer_socket_addr(broadcast_address, port),
+            v6: true,
+        });
+    } else if let Some(ip) = broadcast_address.parse().ok().filter(|ip| ip_allowed(allowed, *ip)) {
+        // Specific interface mode: broadcast to the given address
+        targets.push(BroadcastTarget {
+            interface: ifaces.iter()
+                .find(|iface| iface.addr.iter().any(|addr| addr.broadcast() == Some(ip)))
+                .map(|iface| iface.name.clone())
+                .unwrap_or_default(),
+            target: format!("{}:{}", broadcast_address, port),
+            v6: false,
+        });
+    }
+    targets
+}
+
+/// `broadcast_targets` of every address, each target once even if two addresses reach it.
+fn all_broadcast_targets(addresses: &[String], allowed: &[ipnet::IpNet], port: u16) -> Vec<BroadcastTarget> {
+    let mut targets: Vec<BroadcastTarget> = Vec::new();
+    for target in addresses.iter().flat_map(|address| broadcast_targets(address.trim(), allowed, port)) {
+        if !targets.iter().any(|known| known.target == target.target) {
+            targets.push(target);
+        }
+    }
+    targets
+}
+
+/// The targets the discovery loop currently announces to, empty while broadcasting is off.
+#[tauri::command]
+fn get_active_broadcast_targets(app: AppHandle) -> Result<Vec<BroadcastTarget>, String> {
+    let sockets = app.state::<ActiveDiscovery>().0.lock().unwrap()
+        .clone()
+        .ok_or_else(|| "Broadcast discovery is not running".to_string())?;
+    let (broadcasting_enabled, broadcast_addresses, allowed) = {
+        let state = app.state::<AppState>();
+        let state = state.0.lock().unwrap();
+        (
+            state.settings.broadcasting_enabled,
+            state.settings.all_broadcast_addresses(),
+            parse_cidrs(&state.settings.allowed_cidrs).unwrap_or_default(),
+        )
+    };
+    if !broadcasting_enabled {
+        return Ok(vec![]);
+    }
+    let has_v6 = sockets.v6.is_some();
+    Ok(all_broadcast_targets(&broadcast_addresses, &allowed, sockets.port)
+        .into_iter()
+        .filter(|target| has_v6 || !target.v6)
+        .collect())
+}
+
+/// Announces our presence right away instead of waiting for the next discovery tick.
+#[tauri::command]
+async fn announce_now(app: AppHandle) -> Result<(), String> {
+    let sockets = app.state::<ActiveDiscovery>().0.lock().unwrap()
+        .clone()
+        .ok_or_else(|| "Broadcast discovery is not running".to_string())?;
+    announce(&app.state::<AppState>(), &sockets).await;
+    Ok(())
+}
+
+async fn discovery_task(app_handle: tauri::AppHandle, interface: Option<BindInterface>) {
+    let state = app_handle.state::<AppState>();
+    let mut shutdown = app_handle.state::<Shutdown>().signal.subscribe();
+    let mut port = app_handle.state::<Ports>().discovery.subscribe();
+    let mut interfaces = app_handle.state::<Interfaces>().0.subscribe();
+    // One byte over the limit, so an oversized datagram shows instead of being cut to fit
+    let mut recv_buf = vec![0u8; MAX_PRESENCE_LEN + 1];
+    let mut recv_buf_v6 = vec![0u8; MAX_PRESENCE_LEN + 1];
+    let mut filter = PresenceFilter::default();
+
+    // Runs once per port setting
+    loop {
+        let discovery_port = *port.borrow_and_update();
+        interfaces.borrow_and_update();
+        let socket = match bind_discovery_socket(discovery_port, interface.as_ref()) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!(port = discovery_port, error = %e, "Failed to bind discovery socket");
+                *app_handle.state::<ActiveDiscovery>().0.lock().unwrap() = None;
+                tokio::select! {
+                    _ = shutdown.recv() => return,
+                    _ = port.changed() => continue,
+                }
+            }
+        };
+        let socket_v6 = match bind_v6_discovery_socket(discovery_port, interface.as_ref().map(|interface| interface.index)) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                warn!(error = %e, "IPv6 discovery unavailable");
+                None
+            }
+        };
+        let sockets = Arc::new(DiscoverySockets { v4: socket, v6: socket_v6, port: discovery_port });
+        *app_handle.state::<ActiveDiscovery>().0.lock().unwrap() = Some(sockets.clone());
+        info!(port = discovery_port, "Discovery listening");
+
+        // Re-armed on every tick from the current settings, so changes apply right away
+        let mut next_tick = tokio::time::Instant::now();
+        loop {
+            tokio::select! {
+                _ = shutdown.recv() => return,
+                _ = port.changed() => break,
+                _ = interfaces.changed() => break,
+                _ = tokio::time::sleep_until(next_tick) => {
+                    let (broadcast_interval_ms, peer_timeout) = {
+                        let state = state.0.lock().unwrap();
+                        let factor = match state.settings.presence_status {
+                            PresenceStatus::Away => AWAY_BROADCAST_INTERVAL_FACTOR,
+                            _ => 1,
+                        };
+                        (state.settings.broadcast_interval_ms.max(MIN_BROADCAST_INTERVAL_MS) * factor, Duration::from_secs(state.settings.peer_timeout_secs))
+                    };
+                    next_tick = tokio::time::Instant::now() + Duration::from_millis(broadcast_interval_ms);
+
+                    // Peer cleanup
+                    let timed_out = state.0.lock().unwrap().expire_peers(peer_timeout);
+                    if !timed_out.is_empty() {
+                        for peer in &timed_out {
+                            info!(peer_id = %peer.id, username = %peer.username, "Peer timed out");
+                            emit_peer_left(&app_handle, peer);
                         }
-                    } else {
-                        // Specific interface mode: broadcast to the given address
-                        let target_addr = format!("{}:{}", broadcast_address, DISCOVERY_PORT);
-                        if let Err(e) = socket.send_to(&bytes, &target_addr).await {
-                            eprintln!("Не удалось отправить broadcast на {}: {}", target_addr, e);
+                        emit_peers_updated(&app_handle);
+                    }
+
+                    announce(&state, &sockets).await;
+                    filter.report();
+                }
+                Ok((len, remote_addr)) = sockets.v4.recv_from(&mut recv_buf) => {
+                    if filter.receive(&app_handle, &state, &recv_buf, len, remote_addr) {
+                        answer_presence(&state, &sockets.v4, remote_addr).await;
+                    }
+                }
+                Ok((len, remote_addr)) = recv_optional(sockets.v6.as_ref(), &mut recv_buf_v6) => {
+                    if filter.receive(&app_handle, &state, &recv_buf_v6, len, remote_addr) {
+                        if let Some(socket_v6) = &sockets.v6 {
+                            answer_presence(&state, socket_v6, remote_addr).await;
                         }
                     }
                 }
             }
-            Ok((len, remote_addr)) = socket.recv_from(&mut recv_buf) => {
-                let mut local_ips = HashSet::new();
-                if let Ok(ifaces) = NetworkInterface::show() {
-                    for iface in ifaces {
-                        for addr in &iface.addr {
-                            local_ips.insert(addr.ip());
+        }
+    }
+}
+
+/// Advertises this device as a `_kitsunet._tcp` service and merges resolved services into
+/// the same peer set as broadcast discovery.
+async fn mdns_task(app_handle: AppHandle) {
+    let state = app_handle.state::<AppState>();
+    let mdns = match ServiceDaemon::new() {
+        Ok(mdns) => mdns,
+        Err(e) => {
+            error!(error = %e, "Failed to start mDNS daemon");
+            return;
+        }
+    };
+    let receiver = match mdns.browse(MDNS_SERVICE_TYPE) {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            error!(error = %e, "Failed to browse mDNS services");
+            return;
+        }
+    };
+
+    let instance_name = state.0.lock().unwrap().device_id.clone();
+    let fullname = format!("{}.{}", instance_name, MDNS_SERVICE_TYPE);
+    // Username and transfer port currently registered
+    let mut advertised: Option<(String, u16, PresenceStatus)> = None;
+    let mut resolved: HashMap<String, Peer> = HashMap::new();
+    let mut refresh_interval = interval(Duration::from_secs(1));
+    let mut shutdown = app_handle.state::<Shutdown>().signal.subscribe();
+
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                // Say goodbye so peers drop us right away instead of waiting for the TTL
+                if advertised.is_some() {
+                    let _ = mdns.unregister(&fullname);
+                }
+                let _ = mdns.shutdown();
+                break;
+            }
+            _ = refresh_interval.tick() => {
+                // Keep the advertisement in sync with the settings
+                let wanted = {
+                    let state = state.0.lock().unwrap();
+                    state.settings.broadcasting_enabled
+                        .then(|| (state.settings.username.clone(), state.transfer_port(), state.settings.presence_status))
+                };
+                if wanted != advertised {
+                    match &wanted {
+                        Some((username, transfer_port, status)) => {
+                            let status = serde_json::to_value(status).unwrap();
+                            let device_type = serde_json::to_value(DeviceType::local()).unwrap();
+                            let capabilities = Capability::ALL.iter()
+                                .map(|capability| serde_json::to_value(capability).unwrap().as_str().unwrap_or_default().to_string())
+                                .collect::<Vec<_>>()
+                                .join(",");
+                            let protocol_version = PROTOCOL_VERSION.to_string();
+                            let properties = [
+                                ("id", instance_name.as_str()),
+                                ("username", username.as_str()),
+                                ("os", std::env::consts::OS),
+                                ("device_type", device_type.as_str().unwrap_or_default()),
+                                ("capabilities", capabilities.as_str()),
+                                ("status", status.as_str().unwrap_or_default()),
+                                ("protocol_version", protocol_version.as_str()),
+                            ];
+                            let service = ServiceInfo::new(
+                                MDNS_SERVICE_TYPE,
+                                &instance_name,
+                                &format!("{}.local.", instance_name),
+                                "",
+                                *transfer_port,
+                                &properties[..],
+                            ).map(ServiceInfo::enable_addr_auto);
+                            if let Err(e) = service.and_then(|service| mdns.register(service)) {
+                                error!(error = %e, "Failed to register mDNS service");
+                            }
+                        }
+                        None => {
+                            if let Err(e) = mdns.unregister(&fullname) {
+                                error!(error = %e, "Failed to unregister mDNS service");
+                            }
                         }
                     }
-                }
-                if local_ips.contains(&remote_addr.ip()) {
-                    continue;
+                    advertised = wanted;
                 }
 
-                if let Ok(message) = serde_json::from_slice::<Message>(&recv_buf[..len]) {
-                    let Message::Presence(username) = message;
+                // mDNS doesn't beacon every interval, so keep resolved peers fresh for the
+                // timeout cleanup until the service is explicitly removed
+                let mut state = state.0.lock().unwrap();
+                for peer in resolved.values() {
+                    if !state.is_blocked(Some(&peer.id), &peer.address) {
+                        state.upsert_peer(Peer { last_seen: Some(Instant::now()), ..peer.clone() });
+                    }
+                }
+            }
+            Ok(event) = receiver.recv_async() => match event {
+                ServiceEvent::ServiceResolved(info) => {
+                    let local_ips = local_ips();
+                    if info.get_addresses().iter().any(|ip| local_ips.contains(ip)) {
+                        continue;
+                    }
+                    let address = info.get_addresses_v4().into_iter().next().map(|ip| IpAddr::V4(*ip))
+                        .or_else(|| info.get_addresses().iter().next().copied());
+                    let Some(address) = address.filter(|address| state.0.lock().unwrap().is_allowed_ip(*address)) else {
+                        continue;
+                    };
                     let new_peer = Peer {
-                        username,
-                        address: remote_addr.ip().to_string(),
+                        id: info.get_property_val_str("id").map(str::to_string).unwrap_or_else(|| address.to_string()),
+                        username: info.get_property_val_str("username").unwrap_or(info.get_hostname()).to_string(),
+                        address: address.to_string(),
+                        os: info.get_property_val_str("os").map(str::to_string),
+                        device_type: info.get_property_val_str("device_type")
+                            .and_then(|device_type| serde_json::from_value(device_type.into()).ok()),
+                        status: info.get_property_val_str("status")
+                            .and_then(|status| serde_json::from_value(status.into()).ok()),
+                        alias: None,
+                        last_seen_ms_ago: None,
                         last_seen: Some(Instant::now()),
+                        missed_beacons: 0,
+                        manual: false,
+                        transfer_port: info.get_port(),
+                        capabilities: Some(info.get_property_val_str("capabilities")
+                            .map(|capabilities| capabilities.split(',')
+                                .map(|capability| serde_json::from_value(capability.into()).unwrap_or(Capability::Unknown))
+                                .collect())
+                            .unwrap_or_default()),
+                        protocol_version: info.get_property_val_str("protocol_version")
+                            .and_then(|version| version.parse().ok()),
+                        verified: None,
                     };
+                    resolved.insert(info.get_fullname().to_string(), new_peer.clone());
 
-                    let mut state = state.0.lock().unwrap();
-                    if match state.peers.replace(new_peer.clone()) {
-                        None => true, // It's a new peer
-                        Some(old) => old.username != new_peer.username, // It's an existing peer, check if username changed
-                    } {
-                        app_handle.emit("peers_updated", ()).unwrap();
+                    info!(peer_id = %new_peer.id, address = %new_peer.address, username = %new_peer.username, "Peer resolved over mDNS");
+                    let (is_new, changed) = {
+                        let mut state = state.0.lock().unwrap();
+                        if state.is_blocked(Some(&new_peer.id), &new_peer.address) {
+                            (false, false)
+                        } else {
+                            (!state.peers.contains(&new_peer), state.upsert_peer(new_peer.clone()))
+                        }
+                    };
+                    if is_new {
+                        emit_peer_joined(&app_handle, &new_peer);
+                    }
+                    if changed {
+                        emit_peers_updated(&app_handle);
                     }
                 }
-            }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    if let Some(peer) = resolved.remove(&fullname) {
+                        if state.0.lock().unwrap().peers.remove(&peer) {
+                            emit_peer_left(&app_handle, &peer);
+                            emit_peers_updated(&app_handle);
+                        }
+                    }
+                }
+                _ => {}
+            },
         }
     }
 }
@@ -483,6 +7201,7 @@ async fn discovery_task(app_handle: tauri::AppHandle) {
 fn main() {
     let state = AppState::default();
     let offers: FileOffers = Arc::new(Mutex::new(HashMap::new()));
+    let transfers: ActiveTransfers = Arc::new(Mutex::new(HashMap::new()));
 
     #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd" ))]
     std::env::set_var("WEBKIT_DISABLE_COMPOSITING_MODE", "1");
@@ -492,24 +7211,691 @@ fn main() {
         .plugin(tauri_plugin_dialog::init())
         .manage(state)
         .manage(offers)
+        .manage(RangeSlots::default())
+        .manage(transfers)
+        .manage(Arc::new(TransferQueue::default()))
+        .manage(Arc::new(TransferStats::default()))
+        .manage(Shutdown::new())
+        .manage(ActiveDiscovery::default())
+        .manage(ResumableParts::default())
+        .manage(RecentBatches::default())
+        .manage(PeersUpdated::default())
+        .manage(OfferLimiter::default())
         .invoke_handler(tauri::generate_handler![
             get_users,
+            announce_now,
+            get_active_broadcast_targets,
+            set_peer_alias,
+            add_manual_peer,
+            clear_stale_peers,
+            clear_all_peers,
             send_files,
+            send_files_to_all,
+            preflight_send,
+            cancel_transfer,
+            cancel_pending_offer,
+            pause_transfer,
+            resume_transfer,
+            get_queue,
+            cancel_queued,
+            get_transfer_stats,
+            send_text,
+            estimate_transfer,
             get_own_address,
             get_settings,
             update_settings,
+            block_peer,
+            unblock_peer,
+            set_peer_auto_accept,
+            get_pairing_pin,
+            set_pairing_pin,
+            clear_paired_peers,
+            get_transfer_history,
+            get_resumable_transfers,
+            get_log_path,
             accept_file_offer,
             reject_file_offer,
             get_network_interfaces,
-            show_in_folder
+            get_listen_status,
+            get_own_fingerprint,
+            get_peer_fingerprint,
+            forget_peer_fingerprint,
+            show_in_folder,
+            open_file,
+            diagnose_peer,
+            send_pipe,
+            get_recent_batches,
+            resend_batch,
+            export_diagnostics,
+            send_files_encrypted,
+            send_url,
+            get_active_transfers,
+            cancel_all_transfers
         ])
         .setup(|app| {
+            let log_dir = app.path().app_log_dir()?;
+            std::fs::create_dir_all(&log_dir)?;
+            app.manage(LogGuard(Mutex::new(Some(init_logging(&log_dir)))));
             let handle = app.handle().clone();
+            let settings = load_settings(&handle);
+            let tls = tls::TlsState::load(&app.path().app_config_dir()?.join("tls"))
+                .map_err(|e| e as Box<dyn Error>)?;
+            let acceptor = tls.acceptor();
+            app.manage(tls);
+            app.manage(BatchJournal::load(app.path().app_config_dir()?.join(BATCH_PROGRESS_FILE)));
+            let discovery_mode = settings.discovery_mode;
+            app.manage(Ports::new(&settings));
+            app.manage(Interfaces(tokio::sync::watch::channel(get_network_interfaces()).0));
+            let device_id = load_device_id(&handle);
+            let aliases = load_aliases(&handle);
+            let paired_peers = load_paired_peers(&handle);
+            {
+                let app_state = app.state::<AppState>();
+                let mut state = app_state.0.lock().unwrap();
+                state.settings = settings;
+                state.device_id = device_id;
+                state.aliases = aliases;
+                state.pairing = Pairing { paired_peers, pin: generate_pin(), ..Pairing::default() };
+            }
             let offers = app.state::<FileOffers>().inner().clone();
-            tauri::async_runtime::spawn(discovery_task(handle.clone()));
-            tauri::async_runtime::spawn(file_receiver_task(handle.clone(), offers));
+            let interface = resolve_bind_interface(&handle);
+            if discovery_mode.uses_broadcast() {
+                tauri::async_runtime::spawn(discovery_task(handle.clone(), interface.clone()));
+            }
+            if discovery_mode.uses_mdns() {
+                tauri::async_runtime::spawn(mdns_task(handle.clone()));
+            }
+            tauri::async_runtime::spawn(file_receiver_task(handle.clone(), offers, acceptor, interface));
+            tauri::async_runtime::spawn(transfer_queue_task(handle.clone()));
+            tauri::async_runtime::spawn(stats_task(handle.clone()));
+            tauri::async_runtime::spawn(interface_watch_task(handle.clone()));
+            tauri::async_runtime::spawn(peers_updated_task(handle.clone()));
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("Ошибка запуска приложения");
-}
\ No newline at end of file
+        .build(tauri::generate_context!())
+        .expect("Failed to start the application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                tauri::async_runtime::block_on(app.state::<Shutdown>().run());
+                app.state::<LogGuard>().0.lock().unwrap().take();
+            }
+        });
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::io::duplex;
+
+    /// Collects what the streaming loops report instead of emitting it.
+    #[derive(Default)]
+    struct RecordedEvents {
+        progress: Mutex<Vec<FileTransferProgress>>,
+        bytes: AtomicU64,
+    }
+
+    impl TransferEvents for RecordedEvents {
+        fn progress(&self, progress: FileTransferProgress) {
+            self.progress.lock().unwrap().push(progress);
+        }
+
+        fn bytes_transferred(&self, bytes: u64) {
+            self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// A file in the temp dir, removed again on drop.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("kitsunet-test-{}", Uuid::new_v4()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+
+        async fn open(&self) -> tokio::fs::File {
+            tokio::fs::OpenOptions::new().read(true).write(true).open(&self.0).await.unwrap()
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            std::fs::read(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn preflight_accepts_relative_symlinks() {
+        let dir = std::env::temp_dir().join(format!("kitsunet-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+        std::os::unix::fs::symlink("a.txt", dir.join("link")).unwrap();
+
+        let file = preflight_file(dir.to_string_lossy().into_owned()).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(file.error.is_none(), "{:?}", file.error);
+        assert_eq!(file.file_count, 2);
+        assert_eq!(file.size, 5);
+    }
+
+    #[tokio::test]
+    async fn journaled_files_are_found_again() {
+        let saved = TempFile::new(b"hello");
+        let journal_file = TempFile::new(b"");
+        let journal = BatchJournal::load(journal_file.0.clone());
+        let file = FileMetadata { sha256: Some("abc".to_string()), ..metadata("a.txt", 5) };
+        let files = vec![file.clone(), FileMetadata { sha256: Some("def".to_string()), ..metadata("b.txt", 7) }];
+        assert!(BatchJournal::key("sender", &[metadata("a.txt", 5)]).is_none());
+        let key = BatchJournal::key("sender", &files).unwrap();
+        assert_ne!(BatchJournal::key("other", &files), Some(key.clone()));
+
+        journal.file_saved(&key, 0, &file, &saved.0).await;
+        assert_eq!(journal.saved_path(&key, 0, &file).await, Some(saved.0.clone()));
+        assert_eq!(journal.saved_path(&key, 1, &files[1]).await, None);
+        // Survives a restart
+        let reloaded = BatchJournal::load(journal_file.0.clone());
+        assert_eq!(reloaded.saved_path(&key, 0, &file).await, Some(saved.0.clone()));
+        reloaded.finish(&key).await;
+        assert_eq!(reloaded.saved_path(&key, 0, &file).await, None);
+    }
+
+    /// A few chunks worth of data that doesn't compress to nothing.
+    fn sample_data() -> Vec<u8> {
+        (0..3 * 1024 * 1024 + 123).map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect()
+    }
+
+    fn metadata(path: &str, size: u64) -> FileMetadata {
+        FileMetadata {
+            path: path.to_string(),
+            size,
+            sha256: None,
+            content_id: None,
+            compressed: false,
+            ranges: Vec::new(),
+            mode: None,
+            symlink_target: None,
+            streamed: false,
+            encryption: None,
+        }
+    }
+
+    /// Sends `source` into `target`, which already holds its first `offset` bytes, and
+    /// returns whether the receiver verified it.
+    async fn transfer(source: &TempFile, target: &TempFile, file_meta: &FileMetadata, offset: u64) -> (bool, RecordedEvents) {
+        let (mut sender, mut receiver) = duplex(64 * 1024);
+        let control = TransferControl::default();
+        let sent_events = RecordedEvents::default();
+        let received_events = RecordedEvents::default();
+        let mut file = target.open().await;
+        let (sent, received) = tokio::join!(
+            send_inline_file(&mut sender, &source.0, file_meta, offset, 1024 * 1024, &control, &sent_events),
+            receive_inline_file(&mut receiver, &mut file, file_meta, offset, Duration::from_secs(30), 1024 * 1024, None, &received_events),
+        );
+        sent.unwrap();
+        assert_eq!(sent_events.bytes.load(Ordering::Relaxed), file_meta.size - offset);
+        (received.unwrap(), received_events)
+    }
+
+    #[tokio::test]
+    async fn preamble_round_trips() {
+        let (mut sender, mut receiver) = duplex(1024);
+        let hello = Hello {
+            sender_id: "device".to_string(),
+            pin: Some("123456".to_string()),
+            auth: None,
+            username: Some("alice".to_string()),
+        };
+        write_preamble(&mut sender, &hello).await.unwrap();
+
+        read_preamble(&mut receiver).await.unwrap();
+        let received: Hello = read_json(&mut receiver).await.unwrap();
+        assert_eq!(received.sender_id, "device");
+        assert_eq!(received.pin.as_deref(), Some("123456"));
+        assert_eq!(received.username.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn preamble_accepts_supported_versions() {
+        for version in [MIN_PROTOCOL_VERSION, PROTOCOL_VERSION] {
+            let (mut sender, mut receiver) = duplex(1024);
+            sender.write_all(PROTOCOL_MAGIC).await.unwrap();
+            sender.write_u8(version).await.unwrap();
+            assert_eq!(read_preamble(&mut receiver).await.unwrap(), version);
+        }
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let (mut sender, mut receiver) = duplex(1024);
+            sender.write_all(PROTOCOL_MAGIC).await.unwrap();
+            sender.write_u8(version).await.unwrap();
+            assert!(read_preamble(&mut receiver).await.is_err());
+        }
+
+        let (mut sender, mut receiver) = duplex(1024);
+        sender.write_all(b"HTTP/").await.unwrap();
+        assert!(read_preamble(&mut receiver).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn older_peers_connect_and_get_the_fallback() {
+        // The oldest sender we take, with a `Hello` from before `username`
+        let (mut sender, mut receiver) = duplex(1024);
+        sender.write_all(PROTOCOL_MAGIC).await.unwrap();
+        sender.write_u8(MIN_PROTOCOL_VERSION).await.unwrap();
+        write_json(&mut sender, &serde_json::json!({ "sender_id": "old" })).await.unwrap();
+        assert_eq!(read_preamble(&mut receiver).await.unwrap(), MIN_PROTOCOL_VERSION);
+        let hello: Hello = read_json(&mut receiver).await.unwrap();
+        assert_eq!(hello.sender_id, "old");
+        assert!(hello.username.is_none());
+
+        // As a recipient it only gets what it announced, one that announced nothing everything
+        let mut state = SharedState::default();
+        state.upsert_peer(Peer {
+            id: "old".to_string(),
+            username: "old".to_string(),
+            address: "10.0.0.2".to_string(),
+            os: None,
+            device_type: None,
+            alias: None,
+            last_seen: Some(Instant::now()),
+            missed_beacons: 0,
+            last_seen_ms_ago: None,
+            manual: false,
+            transfer_port: DEFAULT_TRANSFER_PORT,
+            status: None,
+            capabilities: Some(vec![Capability::Compression, Capability::Resume]),
+            protocol_version: Some(MIN_PROTOCOL_VERSION),
+            verified: None,
+        });
+        assert!(state.peer_supports("10.0.0.2", Capability::Compression));
+        assert!(!state.peer_supports("10.0.0.2", Capability::Pack));
+        assert_eq!(state.peer_protocol_version("10.0.0.2"), MIN_PROTOCOL_VERSION);
+        assert!(state.peer_supports("10.0.0.3", Capability::Pack));
+        assert_eq!(state.peer_protocol_version("10.0.0.3"), PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn calibration_is_answered_before_the_burst() {
+        let (mut sender, mut receiver) = duplex(1024);
+        let receiving = tokio::spawn(async move {
+            receive_calibration(&mut receiver, 4096, Duration::from_secs(5)).await.unwrap();
+        });
+        // Nothing of the burst is sent before the first answer
+        assert_eq!(sender.read_u8().await.unwrap(), 1);
+        sender.write_all(&[0; 4096]).await.unwrap();
+        assert_eq!(sender.read_u8().await.unwrap(), 1);
+        receiving.await.unwrap();
+
+        let (mut sender, mut receiver) = duplex(1024);
+        assert!(receive_calibration(&mut receiver, MAX_CALIBRATION_LEN + 1, Duration::from_secs(5)).await.is_err());
+        assert_eq!(sender.read_u8().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn copied_sender_ids_dont_skip_pairing() {
+        let mut state = SharedState::default();
+        state.upsert_peer(Peer {
+            id: "paired".to_string(),
+            username: "paired".to_string(),
+            address: "10.0.0.2".to_string(),
+            os: None,
+            device_type: None,
+            alias: None,
+            last_seen: Some(Instant::now()),
+            missed_beacons: 0,
+            last_seen_ms_ago: None,
+            manual: false,
+            transfer_port: DEFAULT_TRANSFER_PORT,
+            status: None,
+            capabilities: None,
+            protocol_version: None,
+            verified: None,
+        });
+        state.pairing.paired_peers.insert("paired".to_string());
+        assert!(state.is_paired("paired", "10.0.0.2"));
+        assert!(state.is_known_peer("paired", "10.0.0.2"));
+        // Someone else on the network sending the id it heard in the peer's presence
+        assert!(!state.is_paired("paired", "10.0.0.9"));
+        assert!(!state.is_known_peer("paired", "10.0.0.9"));
+    }
+
+    #[tokio::test]
+    async fn accepted_offer_round_trips() {
+        let (mut sender, mut receiver) = duplex(64 * 1024);
+        let files = vec![metadata("a.txt", 10), metadata("docs/b.bin", 20)];
+        let recipient = async {
+            let request: TransferRequest = read_json(&mut receiver).await.unwrap();
+            let TransferRequest::Files { files, batch_id, packed, sender_label } = request else {
+                panic!("Expected a batch, got {:?}", request);
+            };
+            assert_eq!(files.iter().map(|file| file.path.as_str()).collect::<Vec<_>>(), ["a.txt", "docs/b.bin"]);
+            assert_eq!(batch_id.as_deref(), Some("batch"));
+            assert!(packed);
+            assert_eq!(sender_label.as_deref(), Some("Build Server"));
+            answer_offer(&mut receiver, &[0, 1], &[4, 0], &[1]).await.unwrap();
+        };
+        let (answer, ()) = tokio::join!(
+            offer_batch(&mut sender, files, Some("batch".to_string()), true, Some("Build Server".to_string()), Duration::from_secs(5)),
+            recipient,
+        );
+        assert_eq!(answer.unwrap(), OfferAnswer { accepted: vec![0, 1], offsets: vec![4, 0], already_there: vec![1] });
+    }
+
+    #[test]
+    fn shared_secret_must_match() {
+        let challenge = auth_challenge();
+        let auth = shared_secret_response("team passphrase", &challenge);
+        assert!(verify_shared_secret("team passphrase", &challenge, Some(&auth)));
+        assert!(!verify_shared_secret("other passphrase", &challenge, Some(&auth)));
+        assert!(!verify_shared_secret("team passphrase", &auth_challenge(), Some(&auth)));
+        assert!(!verify_shared_secret("team passphrase", &challenge, Some("zz")));
+        assert!(!verify_shared_secret("team passphrase", &challenge, None));
+    }
+
+    #[test]
+    fn presence_signatures_cover_the_username() {
+        let mut state = SharedState { device_id: "device".to_string(), ..Default::default() };
+        state.settings.username = "alice".to_string();
+        assert_eq!(Presence::local(&state).signature("secret"), PresenceSignature::Missing);
+
+        state.settings.shared_secret = Some("secret".to_string());
+        let bytes = serde_json::to_vec(&Message::Presence(Presence::local(&state))).unwrap();
+        let Message::Presence(presence) = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(presence.signature("secret"), PresenceSignature::Valid);
+        assert_eq!(presence.signature("other"), PresenceSignature::Invalid);
+        let spoofed = Presence { username: "mallory".to_string(), ..presence.clone() };
+        assert_eq!(spoofed.signature("secret"), PresenceSignature::Invalid);
+        let replayed = Presence { timestamp: presence.timestamp.map(|timestamp| timestamp - 3600), ..presence };
+        assert_eq!(replayed.signature("secret"), PresenceSignature::Invalid);
+    }
+
+    #[tokio::test]
+    async fn declined_offers_are_reported() {
+        for (verdict, pairing) in [(0, false), (PAIRING_REJECTED, true)] {
+            let (mut sender, mut receiver) = duplex(64 * 1024);
+            let recipient = async {
+                let _: TransferRequest = read_json(&mut receiver).await.unwrap();
+                write_verdict(&mut receiver, verdict).await.unwrap();
+            };
+            let (answer, ()) = tokio::join!(
+                offer_batch(&mut sender, vec![metadata("a.txt", 10)], None, false, None, Duration::from_secs(5)),
+                recipient,
+            );
+            match answer {
+                Err(TransferError::PairingRequired) => assert!(pairing),
+                Err(TransferError::Rejected) => assert!(!pairing),
+                other => panic!("Unexpected answer {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn offer_times_out_without_answer() {
+        let (mut sender, _receiver) = duplex(64 * 1024);
+        let answer = offer_batch(&mut sender, vec![metadata("a.txt", 10)], None, false, None, Duration::from_millis(50)).await;
+        assert!(matches!(answer, Err(TransferError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn offer_rejects_resume_list_of_wrong_length() {
+        let (mut sender, mut receiver) = duplex(64 * 1024);
+        let recipient = async {
+            let _: TransferRequest = read_json(&mut receiver).await.unwrap();
+            answer_offer(&mut receiver, &[0, 1], &[0], &[]).await.unwrap();
+        };
+        let files = vec![metadata("a.txt", 10), metadata("b.txt", 10)];
+        let (answer, ()) = tokio::join!(offer_batch(&mut sender, files, None, false, None, Duration::from_secs(5)), recipient);
+        assert!(matches!(answer, Err(TransferError::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn file_round_trips() {
+        let data = sample_data();
+        let source = TempFile::new(&data);
+        let target = TempFile::new(&[]);
+        let (verified, events) = transfer(&source, &target, &metadata("sample.bin", data.len() as u64), 0).await;
+
+        assert!(verified);
+        assert_eq!(target.contents(), data);
+        assert_eq!(events.bytes.load(Ordering::Relaxed), data.len() as u64);
+        let progress = events.progress.lock().unwrap();
+        let last = progress.last().unwrap();
+        assert_eq!(last.file_name.as_deref(), Some("sample.bin"));
+        assert_eq!(last.bytes_transferred, data.len() as u64);
+        assert_eq!(last.total_bytes, data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn empty_file_reports_full_progress() {
+        let source = TempFile::new(&[]);
+        let target = TempFile::new(&[]);
+        let (verified, events) = transfer(&source, &target, &metadata("empty.txt", 0), 0).await;
+
+        assert!(verified);
+        let progress = events.progress.lock().unwrap();
+        assert_eq!(progress.last().map(|last| last.progress), Some(100.0));
+    }
+
+    #[tokio::test]
+    async fn stream_of_unknown_length_round_trips() {
+        let data = sample_data();
+        let target = TempFile::new(&[]);
+        let file_meta = streamed_metadata("piped.bin", None);
+        let (mut sender, mut receiver) = duplex(64 * 1024);
+        let mut file = target.open().await;
+        let mut source = &data[..];
+        let control = TransferControl::default();
+        let sent_events = RecordedEvents::default();
+        let received_events = RecordedEvents::default();
+        let (sent, received) = tokio::join!(
+            send_streamed_file(&mut sender, &mut source, &file_meta, None, &control, &sent_events),
+            receive_streamed_file(&mut receiver, &mut file, &file_meta, Duration::from_secs(30), &received_events),
+        );
+
+        assert_eq!(sent.unwrap(), data.len() as u64);
+        assert!(received.unwrap());
+        assert_eq!(target.contents(), data);
+        // No end to measure against until it's over
+        assert!(received_events.progress.lock().unwrap().iter().all(|progress| progress.progress == 0.0));
+    }
+
+    #[tokio::test]
+    async fn encrypted_file_only_opens_with_its_key() {
+        let data = sample_data();
+        let source = TempFile::new(&data);
+        let salt = encode_hex(&random_bytes(ENCRYPTION_SALT_LEN));
+        let key = derive_encryption_key("hunter2", &salt).await.unwrap();
+        let file_meta = FileMetadata {
+            streamed: true,
+            encryption: Some(FileEncryption { salt, nonce: encode_hex(&random_bytes(ENCRYPTION_NONCE_LEN)) }),
+            ..metadata("secret.bin", data.len() as u64)
+        };
+        let control = TransferControl::default();
+        let events = RecordedEvents::default();
+
+        for (receiver_key, opens) in [(key, true), (chacha20poly1305::Key::default(), false)] {
+            let target = TempFile::new(&[]);
+            let mut file = target.open().await;
+            let (mut sender, receiver) = duplex(256 * 1024);
+            let receiving = async {
+                // Hanging up once it gives up, like the connection would be
+                let mut receiver = receiver;
+                receive_encrypted_file(&mut receiver, &mut file, &file_meta, &receiver_key, Duration::from_secs(30), &events).await
+            };
+            let (sent, received) = tokio::join!(
+                send_encrypted_file(&mut sender, &source.0, &file_meta, &key, &control, &events),
+                receiving,
+            );
+            if opens {
+                sent.unwrap();
+                assert!(received.unwrap());
+                assert_eq!(target.contents(), data);
+            } else {
+                assert!(received.is_err());
+                assert!(target.contents().is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_file_round_trips() {
+        let data = sample_data();
+        let source = TempFile::new(&data);
+        let target = TempFile::new(&[]);
+        let file_meta = FileMetadata { compressed: true, ..metadata("sample.bin", data.len() as u64) };
+        let (verified, events) = transfer(&source, &target, &file_meta, 0).await;
+
+        assert!(verified);
+        assert_eq!(target.contents(), data);
+        assert_eq!(events.bytes.load(Ordering::Relaxed), data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn resumed_file_round_trips() {
+        let data = sample_data();
+        let offset = 1024 * 1024 + 7;
+        let source = TempFile::new(&data);
+        let target = TempFile::new(&data[..offset]);
+        let (verified, events) = transfer(&source, &target, &metadata("sample.bin", data.len() as u64), offset as u64).await;
+
+        assert!(verified);
+        assert_eq!(target.contents(), data);
+        assert_eq!(events.bytes.load(Ordering::Relaxed), (data.len() - offset) as u64);
+    }
+
+    #[tokio::test]
+    async fn corrupted_prefix_fails_the_checksum() {
+        let data = sample_data();
+        let offset = 1000;
+        let source = TempFile::new(&data);
+        let target = TempFile::new(&vec![0; offset]);
+        let (verified, _) = transfer(&source, &target, &metadata("sample.bin", data.len() as u64), offset as u64).await;
+        assert!(!verified);
+    }
+
+    #[tokio::test]
+    async fn quiet_sender_times_out() {
+        let target = TempFile::new(&[]);
+        let (_sender, mut receiver) = duplex(64 * 1024);
+        let mut file = target.open().await;
+        let result = receive_inline_file(&mut receiver, &mut file, &metadata("sample.bin", 10), 0, Duration::from_millis(50), 1024 * 1024, None, &RecordedEvents::default()).await;
+        let error = result.unwrap_err();
+        assert_eq!(error.downcast_ref::<std::io::Error>().map(std::io::Error::kind), Some(std::io::ErrorKind::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn packed_stream_round_trips() {
+        let data = sample_data();
+        let (mut sender, mut receiver) = duplex(64 * 1024);
+        let (plain_tx, mut plain_rx) = duplex(64 * 1024);
+        let mut unpacked = Vec::new();
+        let (packed, unpacking, read) = tokio::join!(
+            pack_into(&data[..], &mut sender),
+            unpack_from(&mut receiver, plain_tx, Duration::from_secs(30)),
+            plain_rx.read_to_end(&mut unpacked),
+        );
+        packed.unwrap();
+        unpacking.unwrap();
+        read.unwrap();
+        assert_eq!(unpacked, data);
+    }
+
+    #[test]
+    fn accept_rules_must_cover_every_file() {
+        let patterns = vec!["*.csv".to_string(), "reports/*.PDF".to_string()];
+        let files = [metadata("Data.CSV", 1), metadata("reports/q1.pdf", 1), metadata("out/more.csv", 1)];
+        assert_eq!(matching_accept_rules(&patterns, &files), Some(patterns.clone()));
+        assert_eq!(matching_accept_rules(&patterns, &[metadata("data.csv", 1), metadata("notes.txt", 1)]), None);
+        assert_eq!(matching_accept_rules(&[], &files), None);
+    }
+
+    #[test]
+    fn peer_folder_names_are_safe() {
+        assert_eq!(peer_folder_name("Alice's Mac").as_deref(), Some("Alice's Mac"));
+        assert_eq!(peer_folder_name("../etc/passwd").as_deref(), Some(".._etc_passwd"));
+        assert_eq!(peer_folder_name("fe80::1%3").as_deref(), Some("fe80__1%3"));
+        assert_eq!(peer_folder_name("name. ").as_deref(), Some("name"));
+        assert_eq!(peer_folder_name("com1").as_deref(), Some("com1_"));
+        assert_eq!(peer_folder_name(".."), None);
+        assert_eq!(peer_folder_name("  "), None);
+    }
+
+    #[test]
+    fn offer_flood_is_reported_once_and_wears_off() {
+        let limiter = OfferLimiter::default();
+        let flooding: IpAddr = "192.168.1.20".parse().unwrap();
+        let other: IpAddr = "192.168.1.21".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..MAX_OFFERS_PER_WINDOW {
+            assert_eq!(limiter.admit(flooding, start), OfferAdmission::Admitted);
+        }
+        assert_eq!(limiter.admit(flooding, start), OfferAdmission::FloodStarted);
+        assert_eq!(limiter.admit(flooding, start), OfferAdmission::Throttled);
+        assert!(limiter.is_throttled(flooding, start));
+        assert_eq!(limiter.admit(other, start), OfferAdmission::Admitted);
+
+        let later = start + OFFER_FLOOD_COOLDOWN;
+        assert!(!limiter.is_throttled(flooding, later));
+        assert_eq!(limiter.admit(flooding, later), OfferAdmission::Admitted);
+    }
+
+    #[test]
+    fn broadcast_addresses_are_validated() {
+        for valid in ["255.255.255.255", "192.168.1.255", "10.0.0.255", "239.255.0.1", "ff02::1%3", "ff02::1"] {
+            assert!(validate_broadcast_address(valid).is_ok(), "{}", valid);
+        }
+        for invalid in ["192.168.1.10", "fe80::1", "ff02::1%eth0", "192.168.1.255%2", "All", ""] {
+            assert!(validate_broadcast_address(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn fetched_files_get_a_single_safe_name() {
+        assert!(validate_fetch_url("file:///etc/passwd").is_err());
+        assert!(validate_fetch_url("not a url").is_err());
+        let url = validate_fetch_url("https://example.com/files/My%20Report.pdf?dl=1").unwrap();
+        assert_eq!(fetch_file_name(&url, None), "My Report.pdf");
+        assert_eq!(fetch_file_name(&url, Some("../../evil.sh")), "evil.sh");
+        assert_eq!(fetch_file_name(&url, Some("  ")), "My Report.pdf");
+        assert_eq!(fetch_file_name(&validate_fetch_url("http://example.com/").unwrap(), None), "example.com");
+        assert_eq!(fetch_file_name(&url, Some("..")), FETCH_FALLBACK_NAME);
+    }
+
+    #[test]
+    fn seed_peers_take_our_port_unless_they_name_one() {
+        assert_eq!(parse_seed_peer("10.1.2.3", 51234), Some("10.1.2.3:51234".parse().unwrap()));
+        assert_eq!(parse_seed_peer("10.1.2.3:4000", 51234), Some("10.1.2.3:4000".parse().unwrap()));
+        assert_eq!(parse_seed_peer("fd00::1", 51234), Some("[fd00::1]:51234".parse().unwrap()));
+        assert_eq!(parse_seed_peer("seed.local", 51234), None);
+    }
+
+    #[test]
+    fn portable_names_keep_their_extension() {
+        assert_eq!(portable_path("docs/report.pdf"), "docs/report.pdf");
+        assert_eq!(portable_path("../x"), "../x");
+        let long = format!("{}.tar.gz", "a".repeat(300));
+        let shortened = portable_name(&long);
+        assert_eq!(shortened.len(), MAX_FILE_NAME_LEN);
+        assert!(shortened.ends_with("a.gz"));
+        if cfg!(windows) {
+            assert_eq!(portable_name("what? now: "), "what_ now_");
+            assert_eq!(portable_name("aux.txt"), "aux.txt_");
+        } else {
+            assert_eq!(portable_name("what? now: "), "what? now: ");
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_send_stops() {
+        let source = TempFile::new(&sample_data());
+        let (mut sender, _receiver) = duplex(64 * 1024);
+        let control = TransferControl::default();
+        control.cancel.cancel();
+        let result = send_inline_file(&mut sender, &source.0, &metadata("sample.bin", 0), 0, 1024 * 1024, &control, &RecordedEvents::default()).await;
+        assert!(matches!(result, Err(TransferError::Cancelled)));
+    }
+}
@@ -0,0 +1,178 @@
+//! TLS for the file transfer connection.
+//!
+//! Every installation generates a self-signed certificate on first launch and keeps it in
+//! the config dir. There is no CA to vouch for peers, so the client pins the certificate
+//! fingerprint the first time it talks to an address (trust on first use) and refuses the
+//! connection if that address later presents a different certificate.
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+
+const CERT_FILE: &str = "cert.der";
+const KEY_FILE: &str = "key.der";
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+/// Name put in our certificate and used as SNI. Peers are identified by their pinned
+/// fingerprint, not by this name.
+const SERVER_NAME: &str = "kitsunet";
+
+/// Hex SHA-256 of a DER certificate, the form shown to users for comparison.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    format!("{:x}", Sha256::digest(cert.as_ref()))
+}
+
+pub struct TlsState {
+    acceptor: TlsAcceptor,
+    provider: Arc<CryptoProvider>,
+    own_fingerprint: String,
+    /// Peer address -> pinned certificate fingerprint
+    known_peers: Arc<Mutex<HashMap<String, String>>>,
+    known_peers_path: PathBuf,
+}
+
+impl TlsState {
+    /// Loads our certificate from `dir`, generating it on first launch, along with the
+    /// fingerprints pinned so far.
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        std::fs::create_dir_all(dir)?;
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+        let (cert, key) = match (std::fs::read(&cert_path), std::fs::read(&key_path)) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            _ => {
+                let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+                let cert = certified.cert.der().to_vec();
+                let key = certified.key_pair.serialize_der();
+                crate::write_atomic(&key_path, &key)?;
+                crate::write_atomic(&cert_path, &cert)?;
+                (cert, key)
+            }
+        };
+        let cert = CertificateDer::from(cert);
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)?;
+
+        let known_peers_path = dir.join(KNOWN_PEERS_FILE);
+        let known_peers = std::fs::read(&known_peers_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            provider,
+            own_fingerprint: fingerprint(&cert),
+            known_peers: Arc::new(Mutex::new(known_peers)),
+            known_peers_path,
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.clone()
+    }
+
+    pub fn own_fingerprint(&self) -> &str {
+        &self.own_fingerprint
+    }
+
+    pub fn peer_fingerprint(&self, address: &str) -> Option<String> {
+        self.known_peers.lock().unwrap().get(address).cloned()
+    }
+
+    /// Drops the pinned certificate for `address`, e.g. after the peer reinstalled.
+    pub fn forget_peer(&self, address: &str) -> std::io::Result<()> {
+        let mut known_peers = self.known_peers.lock().unwrap();
+        known_peers.remove(address);
+        crate::write_json_atomic(&self.known_peers_path, &*known_peers)
+    }
+
+    /// Runs the client side of the handshake against `address`, pinning its certificate
+    /// if this is the first time we see it.
+    pub async fn connect(&self, address: &str, tcp: TcpStream) -> std::io::Result<client::TlsStream<TcpStream>> {
+        let verifier = PinnedCertVerifier {
+            provider: self.provider.clone(),
+            pinned: self.peer_fingerprint(address),
+        };
+        let config = ClientConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(std::io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(SERVER_NAME).map_err(std::io::Error::other)?;
+        let stream = TlsConnector::from(Arc::new(config)).connect(server_name, tcp).await?;
+
+        // Only pin once the handshake has proven the peer holds the certificate's key
+        let peer_fingerprint = stream.get_ref().1.peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(fingerprint);
+        if let Some(peer_fingerprint) = peer_fingerprint {
+            let mut known_peers = self.known_peers.lock().unwrap();
+            if !known_peers.contains_key(address) {
+                known_peers.insert(address.to_string(), peer_fingerprint);
+                crate::write_json_atomic(&self.known_peers_path, &*known_peers)?;
+            }
+        }
+        Ok(stream)
+    }
+}
+
+/// Accepts any certificate for a peer we haven't pinned yet and exactly the pinned one
+/// otherwise. Signatures are still checked, so the peer must own the certificate's key.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    provider: Arc<CryptoProvider>,
+    pinned: Option<String>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match &self.pinned {
+            Some(pinned) if *pinned != fingerprint(end_entity) => Err(rustls::Error::General(
+                "Peer certificate changed since the last transfer".to_string(),
+            )),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
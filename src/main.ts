@@ -7,11 +7,21 @@ const radar = document.querySelector('.radar') as HTMLElement;
 const selfDot = document.querySelector('.radar-dot.self') as HTMLElement;
 const usernameInput = document.getElementById('username-input') as HTMLInputElement;
 const broadcastToggle = document.getElementById('broadcast-toggle') as HTMLInputElement;
+const compressToggle = document.getElementById('compress-toggle') as HTMLInputElement;
+const quarantineToggle = document.getElementById('quarantine-toggle') as HTMLInputElement;
+const organizeByPeerToggle = document.getElementById('organize-by-peer-toggle') as HTMLInputElement;
+const packToggle = document.getElementById('pack-toggle') as HTMLInputElement;
+const flattenToggle = document.getElementById('flatten-toggle') as HTMLInputElement;
+const senderLabelInput = document.getElementById('sender-label-input') as HTMLInputElement;
+const requirePairingToggle = document.getElementById('require-pairing-toggle') as HTMLInputElement;
+const sharedSecretInput = document.getElementById('shared-secret-input') as HTMLInputElement;
+const pairingPin = document.getElementById('pairing-pin') as HTMLElement;
 
 // Modals
 const fileOfferModal = document.getElementById('file-offer-modal') as HTMLElement;
 const settingsModal = document.getElementById('settings-modal') as HTMLElement;
 const fileOfferTitle = document.getElementById('file-offer-title') as HTMLElement;
+const preparingStatus = document.getElementById('preparing-status') as HTMLElement;
 const fileOfferCloseButton = document.getElementById('file-offer-close-button') as HTMLElement;
 const incomingFileList = document.getElementById('incoming-file-list') as HTMLElement;
 
@@ -19,6 +29,7 @@ const incomingFileList = document.getElementById('incoming-file-list') as HTMLEl
 const acceptOfferBtn = document.getElementById('accept-offer-btn') as HTMLButtonElement;
 const declineOfferBtn = document.getElementById('decline-offer-btn') as HTMLButtonElement;
 const addFileBtn = document.getElementById('add-file-btn') as HTMLButtonElement;
+const sendLinkBtn = document.getElementById('send-link-btn') as HTMLButtonElement;
 const dynamicSendBtn = document.getElementById('dynamic-send-btn') as HTMLButtonElement;
 const settingsBtn = document.getElementById('settings-btn') as HTMLButtonElement;
 
@@ -27,12 +38,32 @@ const fileList = document.getElementById('file-list') as HTMLElement;
 
 // Settings
 const networkInterfaceSelect = document.getElementById('network-interface-select') as HTMLSelectElement;
+const bindInterfaceSelect = document.getElementById('bind-interface-select') as HTMLSelectElement;
+const discoveryModeSelect = document.getElementById('discovery-mode-select') as HTMLSelectElement;
+const presenceStatusSelect = document.getElementById('presence-status-select') as HTMLSelectElement;
+const manualPeerInput = document.getElementById('manual-peer-input') as HTMLInputElement;
+const manualPeerBtn = document.getElementById('manual-peer-btn') as HTMLButtonElement;
+const downloadDirBtn = document.getElementById('download-dir-btn') as HTMLButtonElement;
+const autoRejectInput = document.getElementById('auto-reject-input') as HTMLInputElement;
+const discoveryPortInput = document.getElementById('discovery-port-input') as HTMLInputElement;
+const transferPortInput = document.getElementById('transfer-port-input') as HTMLInputElement;
+const allowedCidrsInput = document.getElementById('allowed-cidrs-input') as HTMLInputElement;
+const broadcastAddressesInput = document.getElementById('broadcast-addresses-input') as HTMLInputElement;
+const seedPeersInput = document.getElementById('seed-peers-input') as HTMLInputElement;
+const autoAcceptPatternsInput = document.getElementById('auto-accept-patterns-input') as HTMLInputElement;
 
 // --- State ---
 let filePathsToSend: string[] = [];
-let selectedPeerAddress: string | null = null;
+let selectedPeerAddresses: string[] = [];
+// Sender side: how many recipients have finished each file
+let senderCompletions = new Map<string, number>();
+let senderProgress = new Map<string, Map<string, number>>();
+let activeRecipientCount = 0;
 let currentOfferId: string | null = null;
+// Whether the current offer has files sent with send_files_encrypted
+let currentOfferEncrypted = false;
 let isTransferring = false;
+let currentSettings: Record<string, unknown> = {};
 
 // --- Functions ---
 
@@ -70,7 +101,7 @@ function escapeCSSSelector(selector: string): string {
 
 async function updatePeerList() {
     if (isTransferring) return;
-    const peers = await invoke<{ address: string, username: string }[]>('get_users');
+    const peers = await invoke<{ id: string, address: string, username: string, alias: string | null, os: string | null, device_type: string | null, status: string | null, verified: boolean | null }[]>('get_users');
     const ownAddress: string = await invoke('get_own_address');
 
     document.querySelectorAll('.radar-dot.peer').forEach(dot => dot.remove());
@@ -81,9 +112,16 @@ async function updatePeerList() {
     otherPeers.forEach((peer, index) => {
         const dot = document.createElement('div');
         dot.className = 'radar-dot peer';
+        dot.dataset.peerId = peer.id;
         dot.dataset.peerAddress = peer.address;
         dot.dataset.peerUsername = peer.username;
-        dot.textContent = peer.username;
+        dot.textContent = peer.alias ?? peer.username;
+        // Only false with a shared secret set, for peers that didn't sign with it
+        const unverified = peer.verified === false ? 'unverified' : null;
+        dot.title = [unverified, peer.alias ? peer.username : null, peer.status, peer.device_type, peer.os, peer.address].filter(Boolean).join(' · ');
+        if (peer.device_type) dot.dataset.deviceType = peer.device_type;
+        if (peer.status) dot.dataset.status = peer.status;
+        if (unverified) dot.dataset.unverified = '';
         dot.style.setProperty('--angle', `${index * angleStep}deg`);
         radar.appendChild(dot);
     });
@@ -98,6 +136,32 @@ async function loadNetworkInterfaces() {
         option.textContent = `${iface.name} (${iface.ip})`;
         networkInterfaceSelect.appendChild(option);
     });
+
+    // Receiving is bound by interface name, an empty value means all of them
+    bindInterfaceSelect.innerHTML = '<option value="">All interfaces</option>';
+    new Set(interfaces.filter(iface => iface.name !== 'All' && !iface.ip.includes(':')).map(iface => iface.name)).forEach(name => {
+        const option = document.createElement('option');
+        option.value = name;
+        option.textContent = name;
+        bindInterfaceSelect.appendChild(option);
+    });
+}
+
+// Selects the bind interface setting, returning false if that interface isn't there.
+// It's kept selectable so saving other settings doesn't reset it.
+function selectBindInterface(name: string | null): boolean {
+    bindInterfaceSelect.value = name ?? '';
+    if (name === null || bindInterfaceSelect.value === name) return true;
+    const option = document.createElement('option');
+    option.value = name;
+    option.textContent = `${name} (unavailable)`;
+    bindInterfaceSelect.appendChild(option);
+    bindInterfaceSelect.value = name;
+    return false;
+}
+
+function notifyBindInterfaceUnavailable(name: string) {
+    alert(`Network interface ${name} is not available, receiving on all interfaces instead.`);
 }
 
 function togglePulse(isBroadcasting: boolean) {
@@ -110,23 +174,66 @@ function togglePulse(isBroadcasting: boolean) {
 
 async function loadSettings() {
     await loadNetworkInterfaces();
-    const settings: { username: string, broadcasting_enabled: boolean, broadcast_address: string } = await invoke('get_settings');
+    const settings: { username: string, broadcasting_enabled: boolean, broadcast_address: string, broadcast_addresses: string[], seed_peers: string[], download_dir: string | null, discovery_mode: string, require_pairing: boolean, shared_secret: string | null, compress_transfers: boolean, quarantine_received: boolean, organize_by_peer: boolean, auto_reject_over_bytes: number | null, bind_interface: string | null, discovery_port: number, transfer_port: number, allowed_cidrs: string[], presence_status: string, auto_accept_patterns: string[] } = await invoke('get_settings');
+    currentSettings = settings;
     usernameInput.value = settings.username;
     broadcastToggle.checked = settings.broadcasting_enabled;
+    requirePairingToggle.checked = settings.require_pairing;
+    sharedSecretInput.value = settings.shared_secret ?? '';
+    compressToggle.checked = settings.compress_transfers;
+    quarantineToggle.checked = settings.quarantine_received;
+    organizeByPeerToggle.checked = settings.organize_by_peer;
+    autoRejectInput.value = settings.auto_reject_over_bytes === null ? '' : String(settings.auto_reject_over_bytes / 1e9);
+    discoveryPortInput.value = String(settings.discovery_port);
+    transferPortInput.value = String(settings.transfer_port);
+    allowedCidrsInput.value = settings.allowed_cidrs.join(', ');
+    broadcastAddressesInput.value = settings.broadcast_addresses.join(', ');
+    seedPeersInput.value = settings.seed_peers.join(', ');
+    autoAcceptPatternsInput.value = settings.auto_accept_patterns.join(', ');
+    pairingPin.textContent = `(${await invoke('get_pairing_pin')})`;
     selfDot.textContent = settings.username;
     networkInterfaceSelect.value = settings.broadcast_address;
+    // The backend may have fallen back before this page was listening for it
+    if (!selectBindInterface(settings.bind_interface)) {
+        notifyBindInterfaceUnavailable(settings.bind_interface!);
+    }
+    discoveryModeSelect.value = settings.discovery_mode;
+    presenceStatusSelect.value = settings.presence_status;
     togglePulse(settings.broadcasting_enabled);
+    downloadDirBtn.textContent = settings.download_dir ?? 'System downloads folder';
 }
 
 async function saveSettings() {
+    // Keep settings the UI doesn't edit (they'd be reset to defaults otherwise)
     const newSettings = {
+        ...currentSettings,
         username: usernameInput.value,
         broadcasting_enabled: broadcastToggle.checked,
+        require_pairing: requirePairingToggle.checked,
+        shared_secret: sharedSecretInput.value === '' ? null : sharedSecretInput.value,
+        compress_transfers: compressToggle.checked,
+        quarantine_received: quarantineToggle.checked,
+        organize_by_peer: organizeByPeerToggle.checked,
+        auto_reject_over_bytes: autoRejectInput.value === '' ? null : Math.round(Number(autoRejectInput.value) * 1e9),
         broadcast_address: networkInterfaceSelect.value,
+        broadcast_addresses: broadcastAddressesInput.value.split(',').map(address => address.trim()).filter(address => address !== ''),
+        seed_peers: seedPeersInput.value.split(',').map(address => address.trim()).filter(address => address !== ''),
+        bind_interface: bindInterfaceSelect.value === '' ? null : bindInterfaceSelect.value,
+        discovery_mode: discoveryModeSelect.value,
+        presence_status: presenceStatusSelect.value,
+        discovery_port: Number(discoveryPortInput.value),
+        transfer_port: Number(transferPortInput.value),
+        allowed_cidrs: allowedCidrsInput.value.split(',').map(cidr => cidr.trim()).filter(cidr => cidr !== ''),
+        auto_accept_patterns: autoAcceptPatternsInput.value.split(',').map(pattern => pattern.trim()).filter(pattern => pattern !== ''),
     };
     await invoke('update_settings', { settings: newSettings });
+    currentSettings = newSettings;
     selfDot.textContent = newSettings.username;
     togglePulse(newSettings.broadcasting_enabled);
+    if (newSettings.broadcasting_enabled) {
+        // Don't make peers wait for the next tick to see the change
+        invoke('announce_now').catch(error => console.warn('Announce failed:', error));
+    }
 }
 
 function renderFileList() {
@@ -211,27 +318,35 @@ function hideModal(modal: HTMLElement) {
     modal.classList.remove('visible');
 }
 
-function showFileOffer(offer: { payload: { id: string, from: string, files: { name: string, size: number }[], total_size: number } }) {
-    const { id, from, files, total_size } = offer.payload;
+function showFileOffer(offer: { payload: { id: string, from: string, files: { path: string, size: number, encryption?: object }[], total_size: number, unknown_sender: boolean, url?: string } }) {
+    const { id, from, files, total_size, unknown_sender, url } = offer.payload;
     currentOfferId = id;
-    fileOfferTitle.textContent = `Incoming transfer from ${from}`;
+    currentOfferEncrypted = files.some(file => file.encryption);
+    fileOfferTitle.textContent = unknown_sender
+        ? `⚠ Incoming transfer from unknown device ${from}`
+        : `Incoming transfer from ${from}`;
+    // Downloaded by us, the size is only known once it starts
+    if (url) fileOfferTitle.textContent += `, to download from ${url}`;
     fileOfferCloseButton.classList.remove("visible")
 
     acceptOfferBtn.style.display = 'block';
     declineOfferBtn.style.display = 'block';
 
     incomingFileList.innerHTML = '';
-    files.forEach(file => {
+    files.forEach((file, index) => {
         const li = document.createElement('li');
-        li.dataset.fileName = file.name;
+        li.dataset.fileName = file.path;
+        li.dataset.fileIndex = String(index);
         li.innerHTML = `
           <div class="file-info" style="flex-grow: 1;">
-              <span class="file-name">${file.name} (${formatBytes(file.size)})</span>
+              <input type="checkbox" class="accept-file-checkbox" checked title="Receive this file">
+              <span class="file-name">${file.path} (${formatBytes(file.size)})</span>
           </div>
           <div class="receiving-details">
               <progress max="100" value="0" style="display: none;"></progress>
               <span class="status-icon"></span>
               <button class="show-in-folder-btn" style="display: none;">🔎</button>
+              <button class="open-file-btn" style="display: none;" title="Open">📄</button>
           </div>
         `;
         incomingFileList.appendChild(li);
@@ -243,6 +358,15 @@ function showFileOffer(offer: { payload: { id: string, from: string, files: { na
     showModal(fileOfferModal);
 }
 
+function checkReceiverDone() {
+    const allReceiverFilesDone = [...document.querySelectorAll('#incoming-file-list .status-icon')]
+        .every(icon => icon.classList.contains('complete') || icon.classList.contains('failed'));
+    if (allReceiverFilesDone && currentOfferId) {
+        setTransferring(false);
+        fileOfferCloseButton.classList.add("visible")
+    }
+}
+
 // --- Event Listeners ---
 
 settingsBtn.addEventListener('click', () => showModal(settingsModal));
@@ -282,30 +406,84 @@ addFileBtn.addEventListener('click', async () => {
     }
 });
 
+// The recipients fetch the file themselves, nothing goes through this machine
+sendLinkBtn.addEventListener('click', async () => {
+    if (isTransferring) return;
+    if (selectedPeerAddresses.length === 0) {
+        alert('Please select a recipient on the radar.');
+        return;
+    }
+    const url = prompt('Link for the recipients to download:');
+    if (!url) return;
+    const failures: RecipientError[] = [];
+    await Promise.all(selectedPeerAddresses.map(async recipient => {
+        try {
+            await invoke('send_url', { recipient, url: url.trim(), name: null });
+        } catch (error) {
+            failures.push({ recipient, error: error as TransferError });
+        }
+    }));
+    if (failures.length > 0) {
+        alert(`Failed to share the link with some recipients:\n${failures.map(f => `${f.recipient}: ${describeTransferError(f.error)}`).join('\n')}`);
+        await askForPairingPins(failures);
+    }
+});
+
+radar.addEventListener('dblclick', async (e) => {
+    const target = (e.target as HTMLElement).closest('.radar-dot.peer') as HTMLElement;
+    if (!target) return;
+    const alias = prompt(`Local name for ${target.dataset.peerUsername}:`, target.textContent ?? '');
+    if (alias === null) return;
+    try {
+        await invoke('set_peer_alias', { peerId: target.dataset.peerId, alias });
+    } catch (error) {
+        alert(error);
+    }
+});
+
+radar.addEventListener('contextmenu', async (e) => {
+    const target = (e.target as HTMLElement).closest('.radar-dot.peer') as HTMLElement;
+    if (!target) return;
+    e.preventDefault();
+    if (!confirm(`Block ${target.textContent}? You won't see it or receive its offers anymore.`)) return;
+    try {
+        await invoke('block_peer', { peer: target.dataset.peerId });
+        currentSettings = await invoke('get_settings');
+    } catch (error) {
+        alert(error);
+    }
+});
+
 radar.addEventListener('click', (e) => {
     if (isTransferring) return;
     const target = (e.target as HTMLElement).closest('.radar-dot.peer') as HTMLElement;
 
+    // Ctrl/Cmd-click adds or removes a peer, a plain click selects just that one
+    const multiSelect = e.ctrlKey || e.metaKey;
+
     if (target && target.classList.contains('selected')) {
         target.classList.remove('selected');
-        selectedPeerAddress = null;
-        dynamicSendBtn.style.display = 'none';
+        selectedPeerAddresses = selectedPeerAddresses.filter(address => address !== target.dataset.peerAddress);
     } else {
-        document.querySelectorAll('.radar-dot.peer.selected').forEach(dot => dot.classList.remove('selected'));
+        if (!multiSelect) {
+            document.querySelectorAll('.radar-dot.peer.selected').forEach(dot => dot.classList.remove('selected'));
+            selectedPeerAddresses = [];
+        }
 
         if (target) {
             target.classList.add('selected');
-            selectedPeerAddress = target.dataset.peerAddress!;
-
-            const rect = target.getBoundingClientRect();
-            dynamicSendBtn.style.display = 'block';
-            dynamicSendBtn.style.top = `${rect.top}px`;
-            dynamicSendBtn.style.left = `${rect.left + (rect.width / 2) - (dynamicSendBtn.offsetWidth / 2)}px`;
-        } else {
-            selectedPeerAddress = null;
-            dynamicSendBtn.style.display = 'none';
+            selectedPeerAddresses.push(target.dataset.peerAddress!);
         }
     }
+
+    if (target && target.classList.contains('selected')) {
+        const rect = target.getBoundingClientRect();
+        dynamicSendBtn.style.display = 'block';
+        dynamicSendBtn.style.top = `${rect.top}px`;
+        dynamicSendBtn.style.left = `${rect.left + (rect.width / 2) - (dynamicSendBtn.offsetWidth / 2)}px`;
+    } else if (selectedPeerAddresses.length === 0) {
+        dynamicSendBtn.style.display = 'none';
+    }
 });
 
 dynamicSendBtn.addEventListener('click', async () => {
@@ -314,21 +492,26 @@ dynamicSendBtn.addEventListener('click', async () => {
         alert('Please select files to send.');
         return;
     }
-    if (!selectedPeerAddress) {
+    if (selectedPeerAddresses.length === 0) {
         alert('Please select a recipient on the radar.');
         return;
     }
 
-    const recipientAddress = selectedPeerAddress;
-    const recipientDot = document.querySelector(`.radar-dot.peer[data-peer-address="${recipientAddress}"]`);
+    const recipients = selectedPeerAddresses;
+    const recipientDots = recipients
+        .map(address => document.querySelector(`.radar-dot.peer[data-peer-address="${address}"]`))
+        .filter(dot => dot !== null);
 
     setTransferring(true);
 
-    if (recipientDot) {
-        recipientDot.classList.remove('selected');
-        recipientDot.classList.add('transferring');
-    }
-    selectedPeerAddress = null;
+    recipientDots.forEach(dot => {
+        dot.classList.remove('selected');
+        dot.classList.add('transferring');
+    });
+    selectedPeerAddresses = [];
+    senderCompletions = new Map();
+    senderProgress = new Map();
+    activeRecipientCount = recipients.length;
     dynamicSendBtn.style.display = 'none';
 
     document.querySelectorAll('#file-list .transfer-details').forEach(details => {
@@ -337,35 +520,87 @@ dynamicSendBtn.addEventListener('click', async () => {
 
     try {
         await invoke('send_files', {
-            recipient: recipientAddress,
+            recipients,
             filePaths: filePathsToSend,
+            options: {
+                pack: packToggle.checked,
+                flatten: flattenToggle.checked,
+                sender_label: senderLabelInput.value.trim() || null,
+            },
         });
     } catch (error) {
         console.error(`Failed to send files:`, error);
-        alert(`Failed to send files.`);
-        // Reset UI on failure
-        if (recipientDot) {
-            recipientDot.classList.remove('transferring');
-        }
-        filePathsToSend = [];
-        renderFileList();
-        setTransferring(false);
+        const failures = error as RecipientError[];
+        alert(`Failed to send files to some recipients:\n${failures.map(f => `${f.recipient}: ${describeTransferError(f.error)}`).join('\n')}`);
+        await askForPairingPins(failures);
     }
+
+    // Every recipient has either finished or failed by now
+    recipientDots.forEach(dot => dot.classList.remove('transferring'));
+    filePathsToSend = [];
+    setTransferring(false);
+    setTimeout(() => {
+        renderFileList();
+    }, 2000);
 });
 
+type TransferError = { kind: string, message?: string };
+type RecipientError = { recipient: string, error: TransferError };
+
+function describeTransferError(error: TransferError): string {
+    switch (error.kind) {
+        case 'PeerUnreachable': return 'device is unreachable';
+        case 'Rejected': return 'declined';
+        case 'PairingRequired': return 'pairing PIN required';
+        case 'AuthRequired': return 'shared secret missing or wrong';
+        case 'StorageUnavailable': return 'their download folder is unavailable';
+        case 'Timeout': return 'no answer';
+        case 'Cancelled': return 'cancelled';
+        case 'CannotSendToSelf': return 'that is this device';
+        case 'NotFound': return `file not found (${error.message})`;
+        case 'InvalidUrl': return `not an http or https link (${error.message})`;
+        case 'EmptyPassword': return 'the password is empty';
+        default: return error.message ?? error.kind;
+    }
+}
+
+// Ask for a PIN for every recipient that wanted one
+async function askForPairingPins(failures: RecipientError[]) {
+    for (const { recipient, error } of failures) {
+        if (error.kind !== 'PairingRequired') continue;
+        const pin = prompt(`Enter the pairing PIN shown on ${recipient}, then send again:`);
+        if (pin) await invoke('set_pairing_pin', { address: recipient, pin });
+    }
+}
+
 acceptOfferBtn.addEventListener('click', async () => {
     if (currentOfferId) {
+        // Encrypted files without their password are declined
+        const password = currentOfferEncrypted
+            ? prompt('Some files are encrypted. Enter the password the sender gave you:')
+            : null;
         setTransferring(true);
         // Hide accept/decline buttons and show progress bars
         acceptOfferBtn.style.display = 'none';
         declineOfferBtn.style.display = 'none';
-        document.querySelectorAll('#incoming-file-list li').forEach(li => {
-            const progressBar = li.querySelector('progress');
-            if (progressBar) progressBar.style.display = 'block';
+        // Unticked files are declined right away, the rest get progress bars
+        const acceptedIndices: number[] = [];
+        document.querySelectorAll<HTMLElement>('#incoming-file-list li[data-file-index]').forEach(li => {
+            const checkbox = li.querySelector('.accept-file-checkbox') as HTMLInputElement;
+            checkbox.disabled = true;
+            if (checkbox.checked) {
+                acceptedIndices.push(Number(li.dataset.fileIndex));
+                const progressBar = li.querySelector('progress');
+                if (progressBar) progressBar.style.display = 'block';
+            } else {
+                const statusIcon = li.querySelector('.status-icon') as HTMLElement;
+                statusIcon.classList.add('failed');
+                statusIcon.title = 'Declined';
+            }
         });
 
         try {
-            await invoke('accept_file_offer', { offerId: currentOfferId });
+            await invoke('accept_file_offer', { offerId: currentOfferId, acceptedIndices, password });
         } catch (error) {
             console.error('Failed to accept offer:', error);
             alert('Failed to start file reception.');
@@ -385,21 +620,104 @@ declineOfferBtn.addEventListener('click', async () => {
 
 listen('peers_updated', updatePeerList);
 listen('file-offer', showFileOffer);
+// Offers from trusted devices are already accepted, just show them arriving
+function showAcceptedOffer(offer: Parameters<typeof showFileOffer>[0], title: string) {
+    showFileOffer(offer);
+    fileOfferTitle.textContent = title;
+    setTransferring(true);
+    acceptOfferBtn.style.display = 'none';
+    declineOfferBtn.style.display = 'none';
+    document.querySelectorAll<HTMLElement>('#incoming-file-list li[data-file-index]').forEach(li => {
+        (li.querySelector('.accept-file-checkbox') as HTMLInputElement).disabled = true;
+        const progressBar = li.querySelector('progress');
+        if (progressBar) progressBar.style.display = 'block';
+    });
+}
+listen('file-offer-auto-accepted', (event) => {
+    const offer = event as Parameters<typeof showFileOffer>[0];
+    showAcceptedOffer(offer, `Receiving from trusted device ${offer.payload.from}`);
+});
+listen('auto-accepted-by-rule', (event) => {
+    const offer = event as Parameters<typeof showFileOffer>[0] & { payload: { rules: string[] } };
+    showAcceptedOffer(offer, `Receiving ${offer.payload.rules.join(', ')} from ${offer.payload.from}`);
+});
+listen('text-received', (event) => {
+    const { from, text } = event.payload as { from: string, text: string };
+    alert(`Message from ${from}:\n\n${text}`);
+});
+listen('bind-interface-unavailable', (event) => notifyBindInterfaceUnavailable(event.payload as string));
+
+// Recipients still waiting for a free sending slot are dimmed instead of pulsing
+listen('queue-updated', (event) => {
+    const entries = event.payload as { id: string, recipient: string, status: string }[];
+    const waiting = new Set(entries.filter(entry => entry.status === 'pending').map(entry => entry.recipient));
+    document.querySelectorAll<HTMLElement>('.radar-dot.peer').forEach(dot => {
+        dot.classList.toggle('queued', waiting.has(dot.dataset.peerAddress!));
+    });
+});
+
+listen('file-offer-auto-rejected', (event) => {
+    const { from, total_size, reason } = event.payload as { from: string, total_size: number, reason: { kind: string, limit?: number, available_bytes?: number, file?: string } };
+    const why = reason.kind === 'too_large'
+        ? `it is over your ${formatBytes(reason.limit!)} limit`
+        : reason.kind === 'disallowed_type'
+            ? `${reason.file} is not a file type you accept`
+            : reason.kind === 'busy'
+                ? 'your status is busy'
+                : `only ${formatBytes(reason.available_bytes!)} of disk space is free`;
+    alert(`Declined ${formatBytes(total_size)} from ${from}: ${why}.`);
+});
+listen('storage-unavailable', (event) => {
+    const { from, reason } = event.payload as { from: string, reason: string };
+    alert(`Declined files from ${from}: ${reason}. Choose a download folder in the settings.`);
+});
+listen('offer-flood-detected', (event) => {
+    const { from, address, cooldown_secs } = event.payload as { from: string, address: string, cooldown_secs: number };
+    alert(`${from} (${address}) sent too many offers at once and is ignored for ${Math.round(cooldown_secs / 60)} minutes.`);
+});
+const onOfferGone = (event: { payload: unknown }) => {
+    const { id } = event.payload as { id: string };
+    if (id === currentOfferId) {
+        currentOfferId = null;
+        hideModal(fileOfferModal);
+    }
+};
+// Docking, VPNs and the like change what there is to pick from
+listen('interfaces_changed', async () => {
+    await loadNetworkInterfaces();
+    networkInterfaceSelect.value = currentSettings.broadcast_address as string;
+    selectBindInterface(currentSettings.bind_interface as string | null);
+});
+// Big batches take a moment to set up before the first byte arrives
+listen('preparing', (event) => {
+    const { offer_id, stage, done, total } = event.payload as { offer_id: string, stage: string, done: number, total: number };
+    if (offer_id !== currentOfferId) return;
+    const what = stage === 'creating_folders' ? 'Creating folders' : 'Checking files';
+    preparingStatus.textContent = `${what}… ${done} of ${total}`;
+    preparingStatus.style.display = done < total ? 'block' : 'none';
+});
+listen('offer-expired', onOfferGone);
+listen('offer-withdrawn', onOfferGone);
 listen('transfer-progress', (event) => {
-    const { file_path, file_name, progress } = event.payload as { file_path: string, file_name: string, progress: number };
+    const { recipient, offer_id, file_path, file_name, progress } = event.payload as { recipient: string, offer_id: string, file_path: string, file_name: string, progress: number };
 
-    // For sender
+    // For sender, the bar shows the average over all recipients
     if (file_path) {
+        const perRecipient = senderProgress.get(file_path) ?? new Map<string, number>();
+        perRecipient.set(recipient, progress);
+        senderProgress.set(file_path, perRecipient);
+
         const escapedPath = escapeCSSSelector(file_path);
         const fileLi = document.querySelector(`#file-list li[data-file-path="${escapedPath}"]`);
         if (fileLi) {
             const progressBar = fileLi.querySelector('progress');
-            if (progressBar) progressBar.value = progress;
+            const total = [...perRecipient.values()].reduce((sum, value) => sum + value, 0);
+            if (progressBar) progressBar.value = total / Math.max(activeRecipientCount, 1);
         }
     }
 
-    // For receiver
-    if (file_name) {
+    // For receiver, only the offer on screen; another one from the same peer may share file names
+    if (file_name && offer_id === currentOfferId) {
         const fileLi = document.querySelector(`#incoming-file-list li[data-file-name="${file_name}"]`);
         if (fileLi) {
             const progressBar = fileLi.querySelector('progress');
@@ -407,64 +725,175 @@ listen('transfer-progress', (event) => {
         }
     }
 });
-listen('transfer-complete', (event) => {
-    const { file_path, file_name, saved_path } = event.payload as { file_path: string, file_name: string, saved_path: string };
+// A file the recipient already had counts as done, on both sides
+const onFileDone = (event: { payload: unknown }) => {
+    const { offer_id, file_path, file_name, saved_path, original_name, duration_ms, total_bytes, average_bytes_per_sec } = event.payload as {
+        offer_id: string, file_path: string, file_name: string, saved_path: string, original_name?: string,
+        duration_ms?: number, total_bytes?: number, average_bytes_per_sec?: number,
+    };
+    // e.g. "240 MB in 18.0s (13 MB/s)", shown as the tooltip of the done icon
+    const summary = duration_ms === undefined ? ''
+        : `${formatBytes(total_bytes!)} in ${(duration_ms / 1000).toFixed(1)}s (${formatBytes(average_bytes_per_sec!)}/s)`;
 
-    // For sender
-    if (file_path) {
+    // For sender, a file is done once every recipient has it
+    if (file_path) senderCompletions.set(file_path, (senderCompletions.get(file_path) ?? 0) + 1);
+    if (file_path && senderCompletions.get(file_path)! >= activeRecipientCount) {
         const escapedPath = escapeCSSSelector(file_path);
         const fileLi = document.querySelector(`#file-list li[data-file-path="${escapedPath}"]`);
         if (fileLi) {
-            const statusIcon = fileLi.querySelector('.status-icon');
-            if (statusIcon) statusIcon.classList.add('complete');
+            const statusIcon = fileLi.querySelector('.status-icon') as HTMLElement;
+            if (statusIcon) {
+                statusIcon.classList.add('complete');
+                if (summary) statusIcon.title = summary;
+            }
             const progressBar = fileLi.querySelector('progress');
             if (progressBar) progressBar.style.display = 'none';
         }
     }
 
     // For receiver
-    if (file_name) {
+    if (file_name && offer_id === currentOfferId) {
         const fileLi = document.querySelector(`#incoming-file-list li[data-file-name="${file_name}"]`);
         if (fileLi) {
-            const statusIcon = fileLi.querySelector('.status-icon');
-            if (statusIcon) statusIcon.classList.add('complete');
+            const statusIcon = fileLi.querySelector('.status-icon') as HTMLElement;
+            if (statusIcon) {
+                statusIcon.classList.add('complete');
+                // The sender's name wasn't valid here, say what it was saved as instead
+                const renamed = original_name ? `Saved as ${saved_path.split(/[\\/]/).pop()}` : '';
+                statusIcon.title = [summary, renamed].filter(part => part !== '').join('\n');
+            }
             const progressBar = fileLi.querySelector('progress');
             if (progressBar) progressBar.style.display = 'none';
             const showBtn = fileLi.querySelector('.show-in-folder-btn') as HTMLButtonElement;
             if (showBtn) {
                 showBtn.style.display = 'block';
                 showBtn.addEventListener('click', () => {
-                    invoke('show_in_folder', { path: saved_path });
+                    invoke('show_in_folder', { path: saved_path }).catch(error => alert(error));
+                });
+            }
+            const openBtn = fileLi.querySelector('.open-file-btn') as HTMLButtonElement;
+            if (openBtn) {
+                openBtn.style.display = 'block';
+                openBtn.addEventListener('click', () => {
+                    invoke('open_file', { path: saved_path }).catch(error => alert(error));
                 });
             }
         }
     }
 
-    const totalSenderFiles = filePathsToSend.length;
-    const completedSenderFiles = document.querySelectorAll('#file-list .status-icon.complete').length;
-    const recipientDot = document.querySelector('.radar-dot.peer.transferring');
+    checkReceiverDone();
+};
+listen('transfer-complete', onFileDone);
+listen('transfer-skipped', onFileDone);
+// For sender, the recipient didn't want this file, which still settles it for that recipient
+listen('transfer-declined', (event) => {
+    const { file_path } = event.payload as { file_path: string };
+    senderCompletions.set(file_path, (senderCompletions.get(file_path) ?? 0) + 1);
+    if (senderCompletions.get(file_path)! >= activeRecipientCount) {
+        const fileLi = document.querySelector(`#file-list li[data-file-path="${escapeCSSSelector(file_path)}"]`);
+        const statusIcon = fileLi?.querySelector('.status-icon') as HTMLElement | null;
+        if (statusIcon) {
+            statusIcon.classList.add('failed');
+            statusIcon.title = 'Declined by recipient';
+        }
+        const progressBar = fileLi?.querySelector('progress');
+        if (progressBar) progressBar.style.display = 'none';
+    }
+});
+listen('transfer-failed', (event) => {
+    const { recipient, offer_id, file_name, reason, kind } = event.payload as { recipient: string, offer_id: string, file_name: string, reason: string, kind?: string };
 
-    if (totalSenderFiles > 0 && completedSenderFiles === totalSenderFiles) {
-        filePathsToSend = [];
-        setTransferring(false);
-        if (recipientDot) {
-            recipientDot.classList.remove('transferring');
+    // For sender, a recipient that failed no longer counts towards completion
+    if (recipient) {
+        activeRecipientCount = Math.max(activeRecipientCount - 1, 0);
+        document.querySelector(`.radar-dot.peer[data-peer-address="${recipient}"]`)?.classList.remove('transferring');
+    }
+
+    // For receiver
+    if (file_name && offer_id === currentOfferId) {
+        const fileLi = document.querySelector(`#incoming-file-list li[data-file-name="${file_name}"]`);
+        if (fileLi) {
+            const statusIcon = fileLi.querySelector('.status-icon') as HTMLElement;
+            if (statusIcon) {
+                statusIcon.classList.add('failed');
+                statusIcon.title = reason;
+            }
+            const progressBar = fileLi.querySelector('progress');
+            if (progressBar) progressBar.style.display = 'none';
         }
-        setTimeout(() => {
-            renderFileList();
-        }, 2000);
     }
 
-    const allReceiverFilesDone = [...document.querySelectorAll('#incoming-file-list .status-icon')].every(icon => icon.classList.contains('complete'));
-    if (allReceiverFilesDone && currentOfferId) {
-        setTransferring(false);
-        fileOfferCloseButton.classList.add("visible")
+    // A full drive or a cancel ends the whole batch, nothing after this file will arrive
+    if (!recipient && offer_id === currentOfferId && (kind === 'DiskFull' || kind === 'Cancelled')) {
+        document.querySelectorAll<HTMLElement>('#incoming-file-list .status-icon:not(.complete)').forEach(statusIcon => {
+            statusIcon.classList.add('failed');
+            statusIcon.title = reason;
+        });
+        if (kind === 'DiskFull') alert(`The download drive is full, the transfer was stopped.\n${reason}`);
+    }
+
+    checkReceiverDone();
+});
+
+// Sums up a received batch in the offer title, so a partly failed one isn't missed
+listen('batch-received', (event) => {
+    const { offer_id, from, received, skipped, failed, result } = event.payload as {
+        offer_id: string, from: string, received: number, skipped: number, failed: number, result: string,
+    };
+    if (offer_id !== currentOfferId) return;
+    const done = received + skipped;
+    fileOfferTitle.textContent = result === 'complete'
+        ? `Received ${done} file${done === 1 ? '' : 's'} from ${from}`
+        : `Received ${done} of ${done + failed} files from ${from}, ${failed} failed`;
+});
+
+downloadDirBtn.addEventListener('click', async () => {
+    const selected = await open({ directory: true });
+    if (typeof selected !== 'string') return;
+    try {
+        await invoke('update_settings', { settings: { ...currentSettings, download_dir: selected } });
+        currentSettings.download_dir = selected;
+        downloadDirBtn.textContent = selected;
+    } catch (error) {
+        alert(error);
+    }
+});
+
+manualPeerBtn.addEventListener('click', async () => {
+    const address = manualPeerInput.value.trim();
+    if (!address) return;
+    manualPeerBtn.disabled = true;
+    try {
+        await invoke('add_manual_peer', { address, username: null });
+        manualPeerInput.value = '';
+    } catch (error) {
+        alert(error);
+    } finally {
+        manualPeerBtn.disabled = false;
     }
 });
 
 usernameInput.addEventListener('input', saveSettings);
 broadcastToggle.addEventListener('change', saveSettings);
+requirePairingToggle.addEventListener('change', saveSettings);
+sharedSecretInput.addEventListener('change', saveSettings);
+compressToggle.addEventListener('change', saveSettings);
+quarantineToggle.addEventListener('change', saveSettings);
+organizeByPeerToggle.addEventListener('change', saveSettings);
+autoRejectInput.addEventListener('change', saveSettings);
+discoveryPortInput.addEventListener('change', saveSettings);
+transferPortInput.addEventListener('change', saveSettings);
+allowedCidrsInput.addEventListener('change', () => saveSettings().catch(error => alert(error)));
+broadcastAddressesInput.addEventListener('change', () => saveSettings().catch(error => alert(error)));
+seedPeersInput.addEventListener('change', () => saveSettings().catch(error => alert(error)));
+autoAcceptPatternsInput.addEventListener('change', () => saveSettings().catch(error => alert(error)));
+listen('pairing-pin-changed', (event) => {
+    pairingPin.textContent = `(${event.payload as string})`;
+});
 networkInterfaceSelect.addEventListener('change', saveSettings);
+bindInterfaceSelect.addEventListener('change', saveSettings);
+discoveryModeSelect.addEventListener('change', saveSettings);
+presenceStatusSelect.addEventListener('change', saveSettings);
 
 // --- Initial Load ---
 updatePeerList();